@@ -0,0 +1,247 @@
+//! Rendered frames saved as raw ANSI escape text, which `cat` or `less -R` can show
+//!
+//! Each frame is drawn after moving the cursor home (`ESC [ H`) so that `cat` redraws in place,
+//! like the dumps made by other programs. Frames can also be separated by lines starting with a
+//! form feed, followed by the timestamp of the next frame when the timing headers are enabled.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::cells::CellGrid;
+use crate::image::TextImage;
+
+pub const ANSI_EXTENSION: &str = "ans";
+
+/// Starts the lines separating the frames
+const FRAME_DELIMITER: char = '\x0C';
+const CURSOR_HOME: &str = "\x1b[H";
+/// Frame rate of dumps played without a frame limit and without timing headers
+const DEFAULT_FPS: u32 = 15;
+
+/// What is written before each frame of an ANSI dump
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum AnsiHeaders {
+    /// The cursor is moved home before each frame
+    #[default]
+    None,
+    /// A form feed line before each frame
+    Delimiter,
+    /// A form feed line with the timestamp of the frame
+    Timing,
+}
+
+/// Whether the file at the path is an ANSI dump, going by its extension
+pub fn is_ansi_dump(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|e| e.eq_ignore_ascii_case(ANSI_EXTENSION))
+}
+
+/// Writes the frames as ANSI text, one after the other
+pub struct AnsiWriter {
+    file: BufWriter<File>,
+    headers: AnsiHeaders,
+}
+
+impl AnsiWriter {
+    pub fn create(path: &Path, headers: AnsiHeaders) -> io::Result<AnsiWriter> {
+        let file = BufWriter::new(File::create(path)?);
+
+        Ok(AnsiWriter {
+            file,
+            headers,
+        })
+    }
+}
+
+impl AnsiWriter {
+    /// Adds the frame shown at the timestamp in the video
+    pub fn push(&mut self, timestamp: Duration, grid: &CellGrid) -> io::Result<()> {
+        match self.headers {
+            AnsiHeaders::None => write!(self.file, "{CURSOR_HOME}")?,
+            AnsiHeaders::Delimiter => writeln!(self.file, "{FRAME_DELIMITER}")?,
+            AnsiHeaders::Timing => writeln!(self.file, "{FRAME_DELIMITER} {:.3}s", timestamp.as_secs_f64())?,
+        }
+
+        writeln!(self.file, "{}", grid.to_ansi_text("\n"))
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A frame of an ANSI dump
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnsiFrame {
+    text: String,
+    timestamp: Option<Duration>, // Given by the timing header of the frame
+}
+
+impl AnsiFrame {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn timestamp(&self) -> Option<Duration> {
+        self.timestamp
+    }
+}
+
+/// The frames of an ANSI dump, made by this program or not
+pub struct AnsiDump {
+    frames: Vec<AnsiFrame>,
+}
+
+impl AnsiDump {
+    /// Reads the dump at the path, the bytes that are not UTF-8 are replaced
+    pub fn open(path: &str) -> io::Result<AnsiDump> {
+        let bytes = fs::read(path)?;
+        Ok(AnsiDump::parse(&String::from_utf8_lossy(&bytes)))
+    }
+
+    /// Splits the text at the frame delimiters, or where the cursor is moved home if there are
+    /// none, a text without either is a single frame
+    pub fn parse(text: &str) -> AnsiDump {
+        let has_delimiters = text.lines().any(|l| l.starts_with(FRAME_DELIMITER));
+
+        let mut frames = Vec::new();
+        if has_delimiters {
+            let mut current = AnsiFrame { text: String::new(), timestamp: None };
+            for line in text.lines() {
+                if let Some(header) = line.strip_prefix(FRAME_DELIMITER) {
+                    let timestamp = header.trim().strip_suffix('s')
+                        .and_then(|s| s.parse::<f64>().ok())
+                        .filter(|s| s.is_finite() && *s >= 0.0)
+                        .map(Duration::from_secs_f64);
+                    let next = AnsiFrame { text: String::new(), timestamp };
+                    frames.push(std::mem::replace(&mut current, next));
+                } else {
+                    current.text.push_str(line);
+                    current.text.push('\n');
+                }
+            }
+            frames.push(current);
+
+            // The text before the first delimiter is usually empty
+            if is_blank(&frames[0].text) {
+                frames.remove(0);
+            }
+        } else {
+            frames = text.split(CURSOR_HOME)
+                .filter(|f| !is_blank(f))
+                .map(|f| AnsiFrame { text: f.to_string(), timestamp: None })
+                .collect();
+        }
+
+        AnsiDump {
+            frames,
+        }
+    }
+}
+
+impl AnsiDump {
+    pub fn frames(&self) -> &[AnsiFrame] {
+        &self.frames
+    }
+
+    /// Frame rate to play the dump at, the given frame limit unless it is `u32::MAX`,
+    /// otherwise the one of the timing headers
+    pub fn fps(&self, frame_limit: Option<u32>) -> u32 {
+        if let Some(limit) = frame_limit.filter(|l| *l != u32::MAX) {
+            return limit.max(1);
+        }
+
+        let timestamps = self.frames.iter().filter_map(|f| f.timestamp).collect::<Vec<Duration>>();
+        match (timestamps.first(), timestamps.last()) {
+            (Some(first), Some(last)) if last > first => {
+                let fps = (timestamps.len() - 1) as f64 / (*last - *first).as_secs_f64();
+                (fps.round() as u32).max(1)
+            },
+            _ => DEFAULT_FPS,
+        }
+    }
+
+    /// The frames as images that can be played
+    pub fn into_images(self) -> Vec<TextImage> {
+        self.frames.into_iter()
+            .map(|f| TextImage::build_from_text(f.text))
+            .collect()
+    }
+}
+
+/// Whether the text shows nothing but spaces, like a screen clear
+fn is_blank(text: &str) -> bool {
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skips the escape sequence up to its final byte
+            if chars.next() == Some('[') {
+                chars.by_ref().find(|c| ('\x40'..='\x7E').contains(c));
+            }
+        } else if !c.is_whitespace() {
+            return false;
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::filled_grid;
+
+    #[test]
+    fn frames_are_read_back() {
+        let path = std::env::temp_dir().join("cli-player-test.ans");
+        let grid = |c| filled_grid(c, 2, 2);
+
+        let mut writer = AnsiWriter::create(&path, AnsiHeaders::Timing).unwrap();
+        writer.push(Duration::from_millis(1000), &grid('a')).unwrap();
+        writer.push(Duration::from_millis(1250), &grid('b')).unwrap();
+        writer.push(Duration::from_millis(1500), &grid('c')).unwrap();
+        writer.finish().unwrap();
+
+        let dump = AnsiDump::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(3, dump.frames().len());
+        assert_eq!("aa\naa\n", dump.frames()[0].text());
+        assert_eq!(Some(Duration::from_millis(1250)), dump.frames()[1].timestamp());
+        assert_eq!(4, dump.fps(None));
+        assert_eq!(4, dump.fps(Some(u32::MAX)));
+        assert_eq!(10, dump.fps(Some(10)));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn frames_without_headers_are_read_back() {
+        let path = std::env::temp_dir().join("cli-player-test-no-headers.ans");
+        let grid = |c| filled_grid(c, 2, 2);
+
+        let mut writer = AnsiWriter::create(&path, AnsiHeaders::default()).unwrap();
+        for (i, c) in ['a', 'b', 'c'].into_iter().enumerate() {
+            writer.push(Duration::from_millis(i as u64 * 100), &grid(c)).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let dump = AnsiDump::open(path.to_str().unwrap()).unwrap();
+        let texts = dump.frames().iter().map(|f| f.text()).collect::<Vec<&str>>();
+        assert_eq!(vec!["aa\naa\n", "bb\nbb\n", "cc\ncc\n"], texts);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dumps_are_split_where_the_cursor_moves_home() {
+        let dump = AnsiDump::parse("\x1b[2J\x1b[H\x1b[31mab\x1b[0m\n\x1b[Hcd\n");
+        let texts = dump.frames().iter().map(|f| f.text()).collect::<Vec<&str>>();
+        assert_eq!(vec!["\x1b[31mab\x1b[0m\n", "cd\n"], texts);
+        assert_eq!(DEFAULT_FPS, dump.fps(None));
+
+        // Without delimiters nor cursor moves the whole text is one frame
+        assert_eq!(1, AnsiDump::parse("ab\ncd\n").frames().len());
+    }
+}
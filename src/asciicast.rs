@@ -0,0 +1,146 @@
+//! Records the rendered frames as an asciicast v2 file, which can be played by asciinema players
+//!
+//! The file is a JSON header line followed by one line per output event, see
+//! <https://docs.asciinema.org/manual/asciicast/v2/>
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::cells::CellGrid;
+
+pub const ASCIICAST_EXTENSION: &str = "cast";
+
+/// Hides the cursor and clears the screen before the first frame
+const START_SEQUENCE: &str = "\x1b[?25l\x1b[2J";
+/// Shows the cursor again after the last frame
+const END_SEQUENCE: &str = "\x1b[0m\x1b[?25h\r\n";
+
+/// Writes the frames as output events timed by the frame rate they are played at
+pub struct AsciicastWriter {
+    file: BufWriter<File>,
+    title: String,
+    fps: f64,
+    glyph_width: usize,
+    nb_frames: u32,
+}
+
+impl AsciicastWriter {
+    /// The header is only written with the first frame since it needs the size of the frames
+    pub fn create(path: &Path, title: &str, fps: f64, glyph_width: usize) -> io::Result<AsciicastWriter> {
+        let file = BufWriter::new(File::create(path)?);
+
+        Ok(AsciicastWriter {
+            file,
+            title: title.to_string(),
+            fps,
+            glyph_width,
+            nb_frames: 0,
+        })
+    }
+}
+
+impl AsciicastWriter {
+    /// Adds the frame after the others, it is shown one frame duration after the previous one
+    pub fn push(&mut self, grid: &CellGrid) -> io::Result<()> {
+        let mut output = String::new();
+        if self.nb_frames == 0 {
+            self.write_header(grid)?;
+            output.push_str(START_SEQUENCE);
+        }
+
+        // Each frame is drawn over the previous one
+        output.push_str("\x1b[H");
+        output.push_str(&grid.to_ansi_text("\r\n"));
+
+        let time = self.nb_frames as f64 / self.fps;
+        self.write_event(time, &output)?;
+        self.nb_frames += 1;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        // The last frame stays for a frame duration too
+        let time = self.nb_frames as f64 / self.fps;
+        if self.nb_frames > 0 {
+            self.write_event(time, END_SEQUENCE)?;
+        }
+
+        self.file.flush()
+    }
+
+    fn write_header(&mut self, grid: &CellGrid) -> io::Result<()> {
+        writeln!(
+            self.file,
+            "{{\"version\": 2, \"width\": {}, \"height\": {}, \"title\": {}, \"env\": {{\"TERM\": \"xterm-256color\"}}}}",
+            grid.width() * self.glyph_width,
+            grid.height(),
+            json_string(&self.title),
+        )
+    }
+
+    fn write_event(&mut self, time: f64, output: &str) -> io::Result<()> {
+        writeln!(self.file, "[{:.6}, \"o\", {}]", time, json_string(output))
+    }
+}
+
+/// Quotes and escapes the string for JSON
+fn json_string(string: &str) -> String {
+    let mut json = String::with_capacity(string.len() + 2);
+    json.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::test_fixtures::filled_grid;
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(r#""a \"b\" \\ c""#, json_string(r#"a "b" \ c"#));
+        assert_eq!(r#""\u001b[H\r\n""#, json_string("\x1b[H\r\n"));
+        assert_eq!(r#""＠ é""#, json_string("＠ é"));
+    }
+
+    #[test]
+    fn frames_are_timed_by_the_frame_rate() {
+        let path = std::env::temp_dir().join("cli-player-test.cast");
+        let grid = |c| filled_grid(c, 3, 2);
+
+        let mut writer = AsciicastWriter::create(&path, "test \"video\"", 4.0, 2).unwrap();
+        writer.push(&grid('a')).unwrap();
+        writer.push(&grid('b')).unwrap();
+        writer.push(&grid('c')).unwrap();
+        writer.finish().unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(
+            r#"{"version": 2, "width": 6, "height": 2, "title": "test \"video\"", "env": {"TERM": "xterm-256color"}}"#,
+            lines[0],
+        );
+        assert_eq!(r#"[0.000000, "o", "\u001b[?25l\u001b[2J\u001b[Haaa\r\naaa"]"#, lines[1]);
+        assert_eq!(r#"[0.250000, "o", "\u001b[Hbbb\r\nbbb"]"#, lines[2]);
+        assert_eq!(r#"[0.500000, "o", "\u001b[Hccc\r\nccc"]"#, lines[3]);
+        assert!(lines[4].starts_with("[0.750000, "));
+        assert_eq!(5, lines.len());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
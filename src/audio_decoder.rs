@@ -0,0 +1,482 @@
+//! Decodes the audio track of a video file in the program with symphonia, so that the audio
+//! does not need to be extracted to another file first
+//!
+//! The decoder is a rodio source, the audio is decoded while it is being played.
+
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::{Error as SymphoniaError, SeekErrorKind};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, StandardVisualKey};
+use symphonia::core::probe::{Hint, ProbeResult};
+use symphonia::core::units::{Time, TimeBase};
+
+#[derive(Debug)]
+pub enum AudioDecodeError {
+    IoError(io::Error),
+    SymphoniaError(SymphoniaError),
+    NoAudioTrack,
+    TrackDoesNotExist(usize, usize), // The track asked for and the number of audio tracks
+}
+
+impl Display for AudioDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioDecodeError::IoError(e) => write!(f, "{}", e),
+            AudioDecodeError::SymphoniaError(e) => write!(f, "Could not decode the audio: {}", e),
+            AudioDecodeError::NoAudioTrack => write!(f, "The video has no audio track that can be decoded"),
+            AudioDecodeError::TrackDoesNotExist(track, count) => {
+                write!(f, "There is no audio track {track}, the video has {count} (listed by --list-audio-tracks)")
+            },
+        }
+    }
+}
+
+impl Error for AudioDecodeError {}
+
+/// Where the audio is read from, a media file or a file already in memory
+#[derive(Clone, Debug)]
+pub enum AudioInput {
+    Path(String),
+    Bytes(Arc<[u8]>),
+}
+
+/// An audio track of a media file, as listed to pick one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AudioTrack {
+    index: usize, // Among the audio tracks of the file, from 0
+    language: Option<String>,
+    codec: String,
+    channels: Option<u16>,
+}
+
+impl AudioTrack {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+
+    /// Short name of the codec, like `aac`
+    pub fn codec(&self) -> &str {
+        &self.codec
+    }
+
+    pub fn channels(&self) -> Option<u16> {
+        self.channels
+    }
+}
+
+impl Display for AudioTrack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}, {}", self.index, self.language.as_deref().unwrap_or("unknown language"), self.codec)?;
+        match self.channels {
+            Some(1) => write!(f, ", mono"),
+            Some(c) => write!(f, ", {c} channels"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Decodes an audio track of the input, the samples are interleaved
+pub struct AudioDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    channels: u16,
+    sample_rate: u32,
+    buffer: Vec<f32>, // The samples of the last decoded packet, it is empty once everything was decoded
+    buffer_position: usize,
+    skip_until: Option<f64>, // After a seek, the samples before this position in seconds are dropped
+}
+
+impl AudioDecoder {
+    /// Decodes the first audio track
+    pub fn open(input: &AudioInput) -> Result<AudioDecoder, AudioDecodeError> {
+        AudioDecoder::open_track(input, 0)
+    }
+
+    /// Decodes the audio track at the index among the audio tracks of the input
+    pub fn open_track(input: &AudioInput, index: usize) -> Result<AudioDecoder, AudioDecodeError> {
+        let format = probe(input)?.format;
+
+        let tracks: Vec<&Track> = audio_tracks_of(format.as_ref()).collect();
+        let track = match tracks.get(index) {
+            Some(t) => *t,
+            None if tracks.is_empty() => return Err(AudioDecodeError::NoAudioTrack),
+            None => return Err(AudioDecodeError::TrackDoesNotExist(index, tracks.len())),
+        };
+        let decoder = match symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default()) {
+            Ok(d) => d,
+            Err(e) => return Err(AudioDecodeError::SymphoniaError(e)),
+        };
+
+        let mut audio_decoder = AudioDecoder {
+            track_id: track.id,
+            time_base: track.codec_params.time_base,
+            channels: track.codec_params.channels.map_or(2, |c| c.count() as u16),
+            sample_rate: track.codec_params.sample_rate.unwrap_or(44100),
+            format,
+            decoder,
+            buffer: Vec::new(),
+            buffer_position: 0,
+            skip_until: None,
+        };
+        audio_decoder.decode_packet();
+
+        Ok(audio_decoder)
+    }
+}
+
+impl AudioDecoder {
+    /// Makes the decoder continue from the position in the audio,
+    /// a position past the end ends the audio
+    pub fn seek(&mut self, position: Duration) -> Result<(), AudioDecodeError> {
+        let time = Time::new(position.as_secs(), position.subsec_nanos() as f64 / 1e9);
+        let seeked = self.format.seek(SeekMode::Accurate, SeekTo::Time { time, track_id: Some(self.track_id) });
+
+        self.buffer.clear();
+        self.buffer_position = 0;
+        match seeked {
+            Ok(_) => (),
+            Err(SymphoniaError::SeekError(SeekErrorKind::OutOfRange)) => return Ok(()),
+            Err(e) => return Err(AudioDecodeError::SymphoniaError(e)),
+        }
+
+        // The packet that was seeked to may start a bit before the position
+        self.decoder.reset();
+        self.skip_until = Some(position.as_secs_f64());
+        self.decode_packet();
+
+        Ok(())
+    }
+
+    /// Decodes the audio up to `duration` in a 16 bits WAV file
+    pub fn into_wav(self, duration: Option<Duration>) -> Vec<u8> {
+        let (channels, sample_rate) = (self.channels, self.sample_rate);
+        let samples: Vec<f32> = match duration {
+            Some(d) => {
+                let length = (d.as_secs_f64() * sample_rate as f64) as usize * channels as usize;
+                self.take(length).collect()
+            },
+            None => self.collect(),
+        };
+
+        wav_bytes(&samples, channels, sample_rate)
+    }
+
+    /// Fills the buffer with the samples of the next packet of the track,
+    /// the buffer is left empty at the end of the track
+    fn decode_packet(&mut self) {
+        self.buffer.clear();
+        self.buffer_position = 0;
+
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(p) => p,
+                // The end of the file is also an error
+                Err(_) => return,
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(d) => d,
+                // Damaged packets are skipped
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(_) => return,
+            };
+            let spec = *decoded.spec();
+            let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            samples.copy_interleaved_ref(decoded);
+
+            self.channels = spec.channels.count() as u16;
+            self.sample_rate = spec.rate;
+            self.buffer.extend_from_slice(samples.samples());
+
+            if let Some(position) = self.skip_until {
+                let skipped_frames = ((position - self.seconds(packet.ts())) * self.sample_rate as f64).round().max(0.0);
+                self.buffer_position = (skipped_frames as usize * self.channels as usize).min(self.buffer.len());
+            }
+            if self.buffer_position < self.buffer.len() {
+                self.skip_until = None;
+                return;
+            }
+            self.buffer.clear();
+            self.buffer_position = 0;
+        }
+    }
+
+    /// Position in seconds of a timestamp of the track
+    fn seconds(&self, timestamp: u64) -> f64 {
+        match self.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(timestamp);
+                time.seconds as f64 + time.frac
+            },
+            None => timestamp as f64 / self.sample_rate as f64,
+        }
+    }
+}
+
+impl Iterator for AudioDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = *self.buffer.get(self.buffer_position)?;
+        self.buffer_position += 1;
+
+        // Decoding ahead lets rodio know when the next packet starts
+        if self.buffer_position == self.buffer.len() {
+            self.decode_packet();
+        }
+
+        Some(sample)
+    }
+}
+
+impl Source for AudioDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.buffer.len() - self.buffer_position)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// The audio tracks of the input, in the order of the file
+pub fn audio_tracks(input: &AudioInput) -> Result<Vec<AudioTrack>, AudioDecodeError> {
+    let format = probe(input)?.format;
+    let codecs = symphonia::default::get_codecs();
+
+    let tracks = audio_tracks_of(format.as_ref())
+        .enumerate()
+        .map(|(index, track)| AudioTrack {
+            index,
+            language: track.language.clone(),
+            codec: match codecs.get_codec(track.codec_params.codec) {
+                Some(c) => c.short_name.to_string(),
+                None => "unsupported codec".to_string(),
+            },
+            channels: track.codec_params.channels.map(|c| c.count() as u16),
+        })
+        .collect();
+
+    Ok(tracks)
+}
+
+/// The tracks with audio, the video tracks have no sample rate
+fn audio_tracks_of(format: &dyn FormatReader) -> impl Iterator<Item = &Track> {
+    format.tracks().iter().filter(|t| t.codec_params.sample_rate.is_some())
+}
+
+/// Finds the format of the input
+fn probe(input: &AudioInput) -> Result<ProbeResult, AudioDecodeError> {
+    let mut hint = Hint::new();
+    let source: Box<dyn MediaSource> = match input {
+        AudioInput::Path(path) => {
+            if let Some(extension) = Path::new(path).extension().and_then(|e| e.to_str()) {
+                hint.with_extension(extension);
+            }
+            match File::open(path) {
+                Ok(f) => Box::new(f),
+                Err(e) => return Err(AudioDecodeError::IoError(e)),
+            }
+        },
+        AudioInput::Bytes(bytes) => Box::new(Cursor::new(Arc::clone(bytes))),
+    };
+    let stream = MediaSourceStream::new(source, Default::default());
+
+    match symphonia::default::get_probe().format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default()) {
+        Ok(p) => Ok(p),
+        Err(e) => Err(AudioDecodeError::SymphoniaError(e)),
+    }
+}
+
+/// The picture embedded in the input, like the cover of an album, as an image file.
+/// The front cover is picked over the other pictures.
+pub fn cover_art(input: &AudioInput) -> Option<Vec<u8>> {
+    let mut probed = probe(input).ok()?;
+
+    // The tags before the audio, like ID3, are read while probing, the others are in the format
+    let mut visuals = Vec::new();
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        visuals.extend_from_slice(revision.visuals());
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        visuals.extend_from_slice(revision.visuals());
+    }
+
+    let visual = visuals.iter()
+        .find(|v| v.usage == Some(StandardVisualKey::FrontCover))
+        .or(visuals.first())?;
+    Some(visual.data.to_vec())
+}
+
+/// The gain in dB given by the ReplayGain tags of the input to even out the loudness of its track
+pub fn replay_gain(input: &AudioInput) -> Option<f64> {
+    let mut probed = probe(input).ok()?;
+
+    let mut tags = Vec::new();
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        tags.extend_from_slice(revision.tags());
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.extend_from_slice(revision.tags());
+    }
+
+    let tag = tags.iter().find(|t| t.std_key == Some(StandardTagKey::ReplayGainTrackGain))?;
+    parse_decibels(&tag.value.to_string())
+}
+
+/// A gain written like "-6.48 dB"
+fn parse_decibels(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let number = match text.len().checked_sub(2) {
+        Some(i) if text.is_char_boundary(i) && text[i..].eq_ignore_ascii_case("db") => &text[..i],
+        _ => text,
+    };
+
+    number.trim().parse().ok()
+}
+
+/// Decodes the part of an audio track of the input between `start` and `end` to a WAV file
+pub fn decode_to_wav(input: &AudioInput, track: usize, start: Duration, end: Option<Duration>) -> Result<Vec<u8>, AudioDecodeError> {
+    let mut decoder = AudioDecoder::open_track(input, track)?;
+    decoder.seek(start)?;
+
+    Ok(decoder.into_wav(end.map(|e| e.saturating_sub(start))))
+}
+
+/// A 16 bits PCM WAV file of the interleaved samples
+pub fn wav_bytes(samples: &[f32], channels: u16, sample_rate: u32) -> Vec<u8> {
+    let data = wav_data(samples);
+
+    let mut bytes = wav_header(channels, sample_rate, data.len() as u32);
+    bytes.extend_from_slice(&data);
+    bytes
+}
+
+/// Size of the header of the WAV files, the samples come after it
+pub const WAV_HEADER_LENGTH: usize = 44;
+
+/// The header of a 16 bits PCM WAV file followed by `data_length` bytes of samples
+pub fn wav_header(channels: u16, sample_rate: u32, data_length: u32) -> Vec<u8> {
+    const BYTES_PER_SAMPLE: u16 = 2;
+
+    let mut bytes = Vec::with_capacity(WAV_HEADER_LENGTH);
+
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(WAV_HEADER_LENGTH as u32 - 8 + data_length).to_le_bytes());
+    bytes.extend_from_slice(b"WAVE");
+
+    bytes.extend_from_slice(b"fmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes()); // Integer PCM
+    bytes.extend_from_slice(&channels.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * (channels * BYTES_PER_SAMPLE) as u32).to_le_bytes());
+    bytes.extend_from_slice(&(channels * BYTES_PER_SAMPLE).to_le_bytes());
+    bytes.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_length.to_le_bytes());
+
+    bytes
+}
+
+/// The samples as the 16 bits integers of the data of a WAV file
+pub fn wav_data(samples: &[f32]) -> Vec<u8> {
+    samples.iter()
+        .flat_map(|s| ((s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_is_decoded_from_memory() {
+        // One second of a rising ramp in mono
+        let samples: Vec<f32> = (0..8000).map(|i| i as f32 / 8000.0).collect();
+        let wav: Arc<[u8]> = Arc::from(wav_bytes(&samples, 1, 8000));
+
+        let mut decoder = AudioDecoder::open(&AudioInput::Bytes(Arc::clone(&wav))).unwrap();
+        assert_eq!((1, 8000), (decoder.channels(), decoder.sample_rate()));
+        assert_eq!(8000, decoder.by_ref().count());
+
+        let mut decoder = AudioDecoder::open(&AudioInput::Bytes(wav)).unwrap();
+        decoder.seek(Duration::from_millis(500)).unwrap();
+        let first = decoder.next().unwrap();
+        assert!((first - 0.5).abs() < 0.01, "first sample was {first}");
+        assert_eq!(3999, decoder.count());
+    }
+
+    #[test]
+    fn audio_track_of_videos_is_decoded() {
+        let wav = decode_to_wav(&AudioInput::Path("./test-assets/video.mp4".to_string()), 0, Duration::from_secs(1), Some(Duration::from_secs(2))).unwrap();
+
+        let mut decoder = AudioDecoder::open(&AudioInput::Bytes(Arc::from(wav))).unwrap();
+        let samples_per_second = decoder.sample_rate() as usize * decoder.channels() as usize;
+        assert_eq!(samples_per_second, decoder.by_ref().count());
+    }
+
+    #[test]
+    fn audio_tracks_are_listed() {
+        let input = AudioInput::Path("./test-assets/video.mp4".to_string());
+
+        // The video track is not listed
+        let tracks = audio_tracks(&input).unwrap();
+        assert_eq!(1, tracks.len());
+        assert_eq!((0, "aac"), (tracks[0].index(), tracks[0].codec()));
+
+        assert!(AudioDecoder::open_track(&input, 0).is_ok());
+        assert!(matches!(AudioDecoder::open_track(&input, 1), Err(AudioDecodeError::TrackDoesNotExist(1, 1))));
+    }
+
+    #[test]
+    fn files_without_pictures_have_no_cover_art() {
+        let wav: Arc<[u8]> = Arc::from(wav_bytes(&[0.0; 100], 1, 8000));
+        assert_eq!(None, cover_art(&AudioInput::Bytes(wav)));
+    }
+
+    #[test]
+    fn replay_gain_is_read_in_decibels() {
+        assert_eq!(Some(-6.48), parse_decibels("-6.48 dB"));
+        assert_eq!(Some(2.0), parse_decibels("+2.00 db"));
+        assert_eq!(None, parse_decibels("loud"));
+
+        let wav: Arc<[u8]> = Arc::from(wav_bytes(&[0.0; 100], 1, 8000));
+        assert_eq!(None, replay_gain(&AudioInput::Bytes(wav)));
+    }
+
+    #[test]
+    fn files_without_audio_are_rejected() {
+        let result = AudioDecoder::open(&AudioInput::Bytes(Arc::from(&b"not audio"[..])));
+        assert!(result.is_err());
+    }
+}
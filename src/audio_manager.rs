@@ -1,88 +1,506 @@
-use std::{io::BufReader, fs::File};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::VideoError;
+use crate::audio_decoder::{self, AudioDecoder, AudioInput};
+use crate::audio_output::{AudioOutput, OfflineSink};
+use crate::loudness::{self, GainControl, GainSource, LoudnessMeter};
+use crate::time_stretch::{SpeedControl, TimeStretch};
 use crate::video::RodioError;
 
-use rodio::{OutputStream, OutputStreamHandle, Sink, Decoder};
+use rodio::{OutputStream, OutputStreamHandle, Sink, Sample, Source};
+
+/// Samples given at once to the loudness meter
+const LOUDNESS_CHUNK_LENGTH: usize = 8192;
+/// Audio measured before playing to know the loudness from the start, the rest is measured meanwhile
+const LOUDNESS_ESTIMATE_DURATION: Duration = Duration::from_secs(10);
+/// Audio measured in the background between two updates of the loudness
+const LOUDNESS_UPDATE_DURATION: Duration = Duration::from_secs(30);
+/// Longest wait for the audio to fade out when the manager is dropped
+const MAX_FADE_OUT_WAIT: Duration = Duration::from_millis(500);
 
-// NOTE: output_stream and _output_stream_handle need to be kept in memory for the audio
-// to play
 pub struct AudioManager {
-    _output_stream: OutputStream,
-    _output_stream_handle: OutputStreamHandle,
-    sink: Sink,
+    output: Output,
+    track: usize, // Index of the played track among the audio tracks of the inputs
+    playing: Option<PlayedSource>,
+    paused: bool,
+    gain: Arc<GainControl>,
+    stop_current: Arc<AtomicBool>, // Fades out and ends the source being played
+    normalize: bool, // Whether the loudness of the tracks is evened out
+    speed: Arc<SpeedControl>,
+}
+
+/// Where the sources are played
+enum Output {
+    // NOTE: output_stream and _output_stream_handle need to be kept in memory for the audio
+    // to play
+    Device {
+        _output_stream: OutputStream,
+        _output_stream_handle: OutputStreamHandle,
+        sink: Sink,
+    },
+    /// Without a sound card, the samples are taken at the same pace
+    Offline(OfflineSink),
+}
+
+/// Keeps track of how much of the source given to the sink was played
+struct PlayedSource {
+    start: Duration, // Position in the input where the source starts
+    samples_per_second: u64,
+    played_samples: Arc<AtomicU64>,
 }
 
 impl AudioManager {
-    pub fn build() -> Result<AudioManager, VideoError> {
+    /// Plays on the output, the audio is played without sound when there is no sound card
+    pub fn build(output: &AudioOutput) -> Result<AudioManager, VideoError> {
+        let output = match output {
+            AudioOutput::Device => match AudioManager::open_device() {
+                Ok(o) => o,
+                Err(e) => {
+                    eprintln!("No sound card could be used, playing without sound: {e}");
+                    Output::Offline(AudioManager::open_offline(None)?)
+                },
+            },
+            AudioOutput::Null => Output::Offline(AudioManager::open_offline(None)?),
+            AudioOutput::Wav(path) => Output::Offline(AudioManager::open_offline(Some(path))?),
+        };
+
+        Ok(AudioManager {
+            output,
+            track: 0,
+            playing: None,
+            paused: false,
+            gain: Arc::new(GainControl::default()),
+            stop_current: Arc::new(AtomicBool::new(false)),
+            normalize: false,
+            speed: Arc::new(SpeedControl::default()),
+        })
+    }
+
+    fn open_device() -> Result<Output, RodioError> {
         let (_output_stream, output_stream_handle) = match OutputStream::try_default() {
             Ok((output, s)) => (output, s),
-            Err(e) => return Err(VideoError::RodioError(RodioError::StreamError(e)))
+            Err(e) => return Err(RodioError::StreamError(e))
         };
 
         let sink = match Sink::try_new(&output_stream_handle) {
             Ok(s) => s,
-            Err(e) => return Err(VideoError::RodioError(RodioError::PlayError(e))),
+            Err(e) => return Err(RodioError::PlayError(e)),
         };
-        
-        Ok(AudioManager {
+
+        Ok(Output::Device {
             _output_stream,
             _output_stream_handle: output_stream_handle,
             sink,
         })
     }
+
+    fn open_offline(wav_path: Option<&Path>) -> Result<OfflineSink, VideoError> {
+        match OfflineSink::new(wav_path) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(VideoError::IoError(e)),
+        }
+    }
 }
 
 impl AudioManager {
-    fn audio_buffer_from_path(path: &str) -> Result<Decoder<BufReader<File>>, VideoError> {
-        let file = match File::open(path) {
-            Ok(f) => f,
-            Err(e) => return Err(VideoError::IoError(e)),
+    fn open_decoder(&self, input: &AudioInput) -> Result<AudioDecoder, VideoError> {
+        match AudioDecoder::open_track(input, self.track) {
+            Ok(d) => Ok(d),
+            Err(e) => Err(VideoError::AudioDecodeError(e)),
+        }
+    }
+
+    pub fn play(&mut self, audio_source: AudioDecoder) {
+        self.fade_out();
+        let source = self.adjusted(audio_source);
+        self.output.append(source);
+    }
+
+    /// The source played at the speed and gain of the manager, it becomes the current source
+    fn adjusted<S>(&mut self, source: S) -> GainSource<TimeStretch<S>>
+    where S: Source<Item = f32> {
+        self.stop_current = Arc::new(AtomicBool::new(false));
+        GainSource::new(
+            TimeStretch::new(source, Arc::clone(&self.speed)),
+            Arc::clone(&self.gain),
+            Arc::clone(&self.stop_current),
+        )
+    }
+
+    pub fn play_from_path(&mut self, path: &str) -> Result<(), VideoError> {
+        let audio_source = self.open_decoder(&AudioInput::Path(path.to_string()))?;
+        self.play(audio_source);
+
+        Ok(())
+    }
+
+    /// Plays the audio from the position in the input, replacing what was playing.
+    /// The audio is decoded while it is played, `position` gives what is played from then on
+    /// (at the speed of the audio the position moves faster or slower).
+    pub fn play_at(&mut self, input: &AudioInput, position: Duration) -> Result<(), VideoError> {
+        let mut audio_source = self.open_decoder(input)?;
+        if let Err(e) = audio_source.seek(position) {
+            return Err(VideoError::AudioDecodeError(e));
+        }
+        let samples_per_second = audio_source.sample_rate() as u64 * audio_source.channels() as u64;
+
+        let played_samples = Arc::new(AtomicU64::new(0));
+        let source = CountedSource {
+            source: audio_source,
+            played_samples: Arc::clone(&played_samples),
         };
 
-        let buf_reader = BufReader::new(file);
+        // The new source fades in from silence once the previous one faded out
+        self.fade_out();
+        let source = self.adjusted(source);
+        self.output.append(source);
+        self.playing = Some(PlayedSource {
+            start: position,
+            samples_per_second,
+            played_samples,
+        });
+
+        Ok(())
+    }
 
-        // "Error while playing the video: Unrecognized format" while trying to open .mp4 file
-        let audio_source =  match Decoder::new(buf_reader) { 
+    /// Fades out the source being played, it ends once it is silent and the next one is played.
+    /// It returns right away, the fade goes on while the audio is played.
+    fn fade_out(&self) {
+        self.stop_current.store(true, Ordering::Relaxed);
+    }
+
+    /// Evens out the loudness of the input with the one of other videos. The ReplayGain tags
+    /// are used when the input has them. Otherwise the start of the track is measured before
+    /// it is played and the rest in the background, the gain slowly follows what was measured.
+    pub fn normalize_loudness(&mut self, input: &AudioInput) {
+        self.normalize = true;
+        let analysis = self.gain.start_analysis();
+
+        // The tags are about the first audio track
+        if self.track == 0 {
+            if let Some(decibels) = audio_decoder::replay_gain(input) {
+                self.gain.set_normalization(loudness::decibels_to_gain(decibels));
+                return;
+            }
+        }
+
+        let mut decoder = match self.open_decoder(input) {
             Ok(d) => d,
-            Err(e) => return Err(VideoError::RodioError(RodioError::DecoderError(e)))
+            Err(_) => return,
         };
+        let samples_per_second = decoder.sample_rate() as f64 * decoder.channels() as f64;
+        let mut meter = LoudnessMeter::new(decoder.channels(), decoder.sample_rate());
 
-        Ok(audio_source)
+        let estimate_length = (LOUDNESS_ESTIMATE_DURATION.as_secs_f64() * samples_per_second) as usize;
+        let mut remaining = measure(&mut decoder, &mut meter, estimate_length);
+        self.gain.set_normalization(meter.loudness().map_or(1.0, loudness::normalization_gain));
+
+        let gain = Arc::clone(&self.gain);
+        let update_length = (LOUDNESS_UPDATE_DURATION.as_secs_f64() * samples_per_second) as usize;
+        thread::spawn(move || {
+            while remaining {
+                remaining = measure(&mut decoder, &mut meter, update_length);
+                // Another track is played
+                if !gain.is_current_analysis(analysis) {
+                    return;
+                }
+                gain.set_normalization(meter.loudness().map_or(1.0, loudness::normalization_gain));
+            }
+        });
     }
 
-    pub fn play(&self, audio_source: Decoder<BufReader<File>>) {
-        self.sink.append(audio_source)
+    /// Index of the audio track that is played
+    pub fn track(&self) -> usize {
+        self.track
     }
 
-    pub fn play_from_path(&self, path: &str) -> Result<(), VideoError> {
-        let audio_source = AudioManager::audio_buffer_from_path(path)?;
-        self.play(audio_source);
+    /// Picks the audio track that is played next, it is only opened once it is played
+    pub fn set_track(&mut self, track: usize) {
+        self.track = track;
+    }
 
-        Ok(())
+    /// Plays another audio track of the input from where the audio is, the track is kept
+    /// for what is played next. The track that was playing goes on if the new one can't be played.
+    pub fn switch_track(&mut self, input: &AudioInput, track: usize) -> Result<(), VideoError> {
+        let previous_track = self.track;
+        self.track = track;
+
+        // The track is checked before its loudness is measured, nothing may be playing
+        let mut result = self.open_decoder(input).map(|_| ());
+        if result.is_ok() {
+            if self.normalize && track != previous_track {
+                self.normalize_loudness(input);
+            }
+            if let Some(position) = self.position() {
+                result = self.play_at(input, position);
+            }
+        }
+
+        if result.is_err() {
+            self.track = previous_track;
+        }
+        result
+    }
+
+    /// Position in the input of the audio being played, `None` when nothing is played
+    /// or everything was played. It is a bit ahead of what is heard since the audio
+    /// is taken by the sound card before being played.
+    pub fn position(&self) -> Option<Duration> {
+        let playing = self.playing.as_ref()?;
+        if self.output.empty() || playing.samples_per_second == 0 {
+            return None;
+        }
+
+        let played_samples = playing.played_samples.load(Ordering::Relaxed);
+        Some(playing.start + Duration::from_secs_f64(played_samples as f64 / playing.samples_per_second as f64))
+    }
+
+    /// Fades out and stops the audio
+    pub fn stop(&mut self) {
+        self.fade_out();
+        self.playing = None;
     }
 
-    pub fn stop(&self) {
-        self.sink.stop()
+    /// Fades out and pauses the audio, the position stays the same until it is resumed
+    pub fn pause(&mut self) {
+        self.gain.set_paused(true);
+        self.paused = true;
     }
 
+    /// Plays the paused audio again, it fades in
+    pub fn resume(&mut self) {
+        self.gain.set_paused(false);
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Plays the audio faster or slower with the same pitch, the position follows the input
+    pub fn set_speed(&self, speed: f64) {
+        self.speed.set_speed(speed)
+    }
+
+    /// Sets the volume, the audio goes to it smoothly
     pub fn set_volume(&self, volume: f32) {
-        self.sink.set_volume(volume)
+        self.gain.set_volume(volume)
+    }
+}
+
+impl Drop for AudioManager {
+    fn drop(&mut self) {
+        // The audio is cut when the output is closed, it is given some time to fade out first
+        self.fade_out();
+        let start = Instant::now();
+        while !self.output.empty() && start.elapsed() < MAX_FADE_OUT_WAIT {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Measures the next `length` samples of the decoder, returns false once it has no more of them
+fn measure(decoder: &mut AudioDecoder, meter: &mut LoudnessMeter, length: usize) -> bool {
+    let mut samples = Vec::with_capacity(LOUDNESS_CHUNK_LENGTH);
+    let mut measured = 0;
+
+    while measured < length {
+        samples.clear();
+        samples.extend(decoder.by_ref().take(LOUDNESS_CHUNK_LENGTH));
+        if samples.is_empty() {
+            return false;
+        }
+        meter.push(&samples);
+        measured += samples.len();
+    }
+
+    true
+}
+
+impl Output {
+    /// Plays the source once the ones before it ended
+    fn append<S>(&mut self, source: S)
+    where S: Source<Item = f32> + Send + 'static {
+        match self {
+            Output::Device { sink, .. } => sink.append(source),
+            Output::Offline(sink) => sink.append(source),
+        }
+    }
+
+    fn empty(&self) -> bool {
+        match self {
+            Output::Device { sink, .. } => sink.empty(),
+            Output::Offline(sink) => sink.empty(),
+        }
+    }
+}
+
+/// Counts the samples taken from the source
+struct CountedSource<S> {
+    source: S,
+    played_samples: Arc<AtomicU64>,
+}
+
+impl<S: Source> Iterator for CountedSource<S> where S::Item: Sample {
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.source.next()?;
+        self.played_samples.fetch_add(1, Ordering::Relaxed);
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for CountedSource<S> where S::Item: Sample {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audio_decoder::AudioDecodeError;
 
     #[test]
     fn building_audio_manager_in_default() {
-        AudioManager::build().unwrap();
+        // Falls back to playing without sound on machines without a sound card
+        AudioManager::build(&AudioOutput::Device).unwrap();
     }
 
     #[test]
     fn playing_file_from_path() {
-        let audio = AudioManager::build().unwrap();
+        let mut audio = AudioManager::build(&AudioOutput::Null).unwrap();
         audio.play_from_path("./test-assets/audio.wav").unwrap();
     }
+
+    #[test]
+    fn playing_file_from_position() {
+        let mut audio = AudioManager::build(&AudioOutput::Null).unwrap();
+        let input = AudioInput::Path("./test-assets/audio.wav".to_string());
+        audio.play_at(&input, Duration::from_secs(1)).unwrap();
+        audio.play_at(&input, Duration::from_secs(3)).unwrap();
+    }
+
+    #[test]
+    fn playing_the_audio_of_a_video() {
+        let mut audio = AudioManager::build(&AudioOutput::Null).unwrap();
+        audio.play_at(&AudioInput::Path("./test-assets/video.mp4".to_string()), Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn position_follows_the_played_audio() {
+        let mut audio = AudioManager::build(&AudioOutput::Null).unwrap();
+        assert_eq!(None, audio.position());
+
+        audio.play_at(&AudioInput::Path("./test-assets/audio.wav".to_string()), Duration::from_secs(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        let position = audio.position().unwrap();
+        assert!(position > Duration::from_secs(1) && position < Duration::from_secs(3), "position was {position:?}");
+
+        audio.stop();
+        assert_eq!(None, audio.position());
+    }
+
+    #[test]
+    fn played_audio_is_written_to_wav() {
+        let path = std::env::temp_dir().join("cli-player-test-audio-manager.wav");
+
+        let mut audio = AudioManager::build(&AudioOutput::Wav(path.clone())).unwrap();
+        audio.play_at(&AudioInput::Path("./test-assets/audio.wav".to_string()), Duration::from_secs(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        drop(audio);
+
+        let decoded = AudioDecoder::open(&AudioInput::Path(path.to_str().unwrap().to_string())).unwrap();
+        assert!(decoded.count() > 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn switching_tracks_keeps_the_position() {
+        let mut audio = AudioManager::build(&AudioOutput::Null).unwrap();
+        let input = AudioInput::Path("./test-assets/video.mp4".to_string());
+
+        audio.play_at(&input, Duration::from_secs(2)).unwrap();
+        audio.switch_track(&input, 0).unwrap();
+        let position = audio.position().unwrap();
+        assert!(position >= Duration::from_secs(2) && position < Duration::from_secs(3), "position was {position:?}");
+
+        // The video has one audio track
+        assert!(audio.switch_track(&input, 1).is_err());
+        assert_eq!(0, audio.track());
+        assert!(audio.position().is_some());
+    }
+
+    #[test]
+    fn videos_without_audio_fail_only_when_played() {
+        let mut audio = AudioManager::build(&AudioOutput::Null).unwrap();
+        let input = AudioInput::Path("./test-assets/video-no-audio.mp4".to_string());
+
+        audio.set_track(0);
+        assert!(matches!(audio.play_at(&input, Duration::ZERO), Err(VideoError::AudioDecodeError(AudioDecodeError::NoAudioTrack))));
+        assert_eq!(None, audio.position());
+    }
+
+    #[test]
+    fn pausing_keeps_the_position() {
+        let mut audio = AudioManager::build(&AudioOutput::Null).unwrap();
+        audio.normalize_loudness(&AudioInput::Path("./test-assets/audio.wav".to_string()));
+        audio.play_at(&AudioInput::Path("./test-assets/audio.wav".to_string()), Duration::from_secs(1)).unwrap();
+
+        // The audio is read while it fades out
+        audio.pause();
+        std::thread::sleep(Duration::from_millis(300));
+        let position = audio.position().unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(position, audio.position().unwrap());
+
+        audio.resume();
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(audio.position().unwrap() > position);
+    }
+
+    #[test]
+    fn playing_from_another_position_does_not_wait_for_the_fade() {
+        let mut audio = AudioManager::build(&AudioOutput::Null).unwrap();
+        let input = AudioInput::Path("./test-assets/audio.wav".to_string());
+        audio.play_at(&input, Duration::from_secs(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+
+        let start = Instant::now();
+        audio.play_at(&input, Duration::from_secs(3)).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50), "took {:?}", start.elapsed());
+
+        // The new position is played once the previous one faded out
+        std::thread::sleep(Duration::from_millis(500));
+        let position = audio.position().unwrap();
+        assert!(position > Duration::from_secs(3) && position < Duration::from_millis(3500), "position was {position:?}");
+    }
+
+    #[test]
+    fn position_follows_the_speed() {
+        let mut audio = AudioManager::build(&AudioOutput::Null).unwrap();
+        audio.set_speed(2.0);
+        audio.play_at(&AudioInput::Path("./test-assets/audio.wav".to_string()), Duration::ZERO).unwrap();
+
+        std::thread::sleep(Duration::from_millis(500));
+        let position = audio.position().unwrap();
+        assert!(position > Duration::from_millis(900) && position < Duration::from_millis(1300), "position was {position:?}");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,315 @@
+//! Plays the audio without a sound card, for headless machines and tests
+//!
+//! The samples are taken from the source at the pace they would be played at, so the position
+//! of the audio moves like with a sound card. They are dropped or written to a WAV file.
+
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use rodio::Source;
+
+use crate::audio_decoder::{self, WAV_HEADER_LENGTH};
+
+/// Time between each batch of samples taken from the source
+const PLAYBACK_TICK: Duration = Duration::from_millis(10);
+
+/// Where the audio can be played, from the command line
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AudioOutputKind {
+    /// The default sound card, or nowhere if there is none
+    #[default]
+    Device,
+    /// Nowhere, the audio is only used as the clock
+    Null,
+}
+
+/// Where the audio is played
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AudioOutput {
+    Device,
+    Null,
+    /// A WAV file with the audio that was played, in the format of the first audio played
+    Wav(PathBuf),
+}
+
+type QueuedSource = Box<dyn Source<Item = f32> + Send>;
+
+/// Plays the sources one after the other in real time, without a sound card
+pub struct OfflineSink {
+    wav: Option<WavWriter>, // Moved to the thread while the sources are played
+    player: Option<(Arc<AtomicBool>, JoinHandle<Option<WavWriter>>)>, // Stops the thread playing the sources
+    queue: Arc<Mutex<Queue>>,
+}
+
+/// The sources waiting to be played
+struct Queue {
+    sources: VecDeque<QueuedSource>,
+    finished: bool, // Everything was played, the thread playing the sources stopped
+}
+
+impl OfflineSink {
+    /// The audio is written to the WAV file at `wav_path` if there is one
+    pub fn new(wav_path: Option<&Path>) -> io::Result<OfflineSink> {
+        let wav = match wav_path {
+            Some(p) => Some(WavWriter::create(p)?),
+            None => None,
+        };
+
+        Ok(OfflineSink {
+            wav,
+            player: None,
+            queue: Arc::new(Mutex::new(Queue {
+                sources: VecDeque::new(),
+                finished: true,
+            })),
+        })
+    }
+}
+
+impl OfflineSink {
+    /// Plays the source once the ones before it ended
+    pub fn append<S>(&mut self, source: S)
+    where S: Source<Item = f32> + Send + 'static {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            if !queue.finished {
+                queue.sources.push_back(Box::new(source));
+                return;
+            }
+        }
+
+        // The thread that played the sources before stopped
+        self.stop();
+        {
+            let mut queue = self.queue.lock().unwrap();
+            queue.sources.push_back(Box::new(source));
+            queue.finished = false;
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let playback = Playback {
+            stop: Arc::clone(&stop),
+            queue: Arc::clone(&self.queue),
+            wav: self.wav.take(),
+        };
+        let handle = thread::spawn(move || playback.run());
+        self.player = Some((stop, handle));
+    }
+
+    /// Stops playing, the sources waiting to be played are dropped
+    pub fn stop(&mut self) {
+        if let Some((stop, handle)) = self.player.take() {
+            stop.store(true, Ordering::Relaxed);
+            // The thread gives the file back to write the next sources
+            self.wav = handle.join().unwrap_or(None);
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        queue.sources.clear();
+        queue.finished = true;
+    }
+
+    /// Whether everything was played
+    pub fn empty(&self) -> bool {
+        self.queue.lock().unwrap().finished
+    }
+}
+
+impl Drop for OfflineSink {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Takes the samples of the queued sources as they would be played
+struct Playback {
+    stop: Arc<AtomicBool>,
+    queue: Arc<Mutex<Queue>>,
+    wav: Option<WavWriter>,
+}
+
+impl Playback {
+    /// Plays until the queue is empty or the playback is stopped, gives back the WAV file
+    fn run(mut self) -> Option<WavWriter> {
+        loop {
+            let source = {
+                let mut queue = self.queue.lock().unwrap();
+                match queue.sources.pop_front() {
+                    Some(s) => s,
+                    None => {
+                        queue.finished = true;
+                        break;
+                    },
+                }
+            };
+
+            if !self.play(source) {
+                break;
+            }
+        }
+
+        self.wav
+    }
+
+    /// Plays the source, returns false if the playback was stopped before it ended
+    fn play(&mut self, mut source: QueuedSource) -> bool {
+        let (channels, sample_rate) = (source.channels().max(1), source.sample_rate());
+        let start = Instant::now();
+        let mut played_frames = 0;
+        let mut samples = Vec::new();
+
+        while !self.stop.load(Ordering::Relaxed) {
+            let due_frames = (start.elapsed().as_secs_f64() * sample_rate as f64) as u64;
+
+            samples.clear();
+            samples.extend(source.by_ref().take((due_frames - played_frames) as usize * channels as usize));
+            let ended = samples.len() < (due_frames - played_frames) as usize * channels as usize;
+            played_frames = due_frames;
+
+            if let Some(wav) = &mut self.wav {
+                if let Err(e) = wav.write(&samples, channels, sample_rate) {
+                    eprintln!("Stopped writing the audio: {e}");
+                    self.wav = None;
+                }
+            }
+
+            if ended {
+                return true;
+            }
+            thread::sleep(PLAYBACK_TICK);
+        }
+
+        false
+    }
+}
+
+/// Writes a 16 bits WAV file as the samples come, the file is valid after each write
+pub struct WavWriter {
+    file: File,
+    format: Option<(u16, u32)>, // The channels and sample rate, given by the first samples
+    data_length: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path) -> io::Result<WavWriter> {
+        let mut file = File::create(path)?;
+        file.write_all(&[0; WAV_HEADER_LENGTH])?;
+
+        Ok(WavWriter {
+            file,
+            format: None,
+            data_length: 0,
+        })
+    }
+}
+
+impl WavWriter {
+    /// Adds the interleaved samples, the format is the one of the first samples written
+    pub fn write(&mut self, samples: &[f32], channels: u16, sample_rate: u32) -> io::Result<()> {
+        let (channels, sample_rate) = *self.format.get_or_insert((channels, sample_rate));
+        let data = audio_decoder::wav_data(samples);
+
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&data)?;
+        self.data_length += data.len() as u32;
+
+        // The sizes are kept up to date in case the program is stopped
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&audio_decoder::wav_header(channels, sample_rate, self.data_length))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A constant sample in mono
+    struct ConstantSource {
+        remaining: usize,
+    }
+
+    impl Iterator for ConstantSource {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            self.remaining = self.remaining.checked_sub(1)?;
+            Some(0.5)
+        }
+    }
+
+    impl Source for ConstantSource {
+        fn current_frame_len(&self) -> Option<usize> {
+            Some(self.remaining)
+        }
+
+        fn channels(&self) -> u16 {
+            1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            8000
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    #[test]
+    fn sources_are_played_in_real_time() {
+        let mut sink = OfflineSink::new(None).unwrap();
+        assert!(sink.empty());
+
+        let start = Instant::now();
+        sink.append(ConstantSource { remaining: 1600 });
+        assert!(!sink.empty());
+        while !sink.empty() {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(400), "played in {elapsed:?}");
+
+        // The sources appended meanwhile are played after
+        let start = Instant::now();
+        sink.append(ConstantSource { remaining: 800 });
+        sink.append(ConstantSource { remaining: 800 });
+        while !sink.empty() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(400), "played in {elapsed:?}");
+    }
+
+    #[test]
+    fn played_audio_is_written_to_wav() {
+        let path = std::env::temp_dir().join("cli-player-test-output.wav");
+
+        let mut sink = OfflineSink::new(Some(&path)).unwrap();
+        sink.append(ConstantSource { remaining: 400 });
+        while !sink.empty() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        // Stopping does not write anything more
+        sink.append(ConstantSource { remaining: 8000 });
+        sink.stop();
+        drop(sink);
+
+        let bytes = fs::read(&path).unwrap();
+        let data_length = u32::from_le_bytes(bytes[40..44].try_into().unwrap()) as usize;
+        assert_eq!(audio_decoder::wav_header(1, 8000, data_length as u32), bytes[..WAV_HEADER_LENGTH]);
+        assert_eq!(WAV_HEADER_LENGTH + data_length, bytes.len());
+        assert!((800..1000).contains(&data_length), "data length was {data_length}");
+        assert_eq!(audio_decoder::wav_data(&[0.5]), bytes[WAV_HEADER_LENGTH..WAV_HEADER_LENGTH + 2]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+//! Rendered images as grids of colored glyphs, before they are turned into text
+
+use colored::{Color, Colorize};
+use rayon::prelude::{ParallelIterator, ParallelSlice};
+
+use crate::config::Config;
+use crate::image::ImageAsString;
+
+/// A glyph with the color it is printed in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cell {
+    pub character: char,
+    pub color: Option<Color>,
+}
+
+impl Cell {
+    pub fn new(character: char, color: Option<Color>) -> Cell {
+        Cell {
+            character,
+            color,
+        }
+    }
+}
+
+impl Cell {
+    /// The glyph of the pallet, or of the mosaic, rendering the color of a pixel
+    pub fn for_pixel(red: u8, green: u8, blue: u8, config: &Config) -> Cell {
+        if let Some(mosaic) = config.mosaic() {
+            let glyph = mosaic.glyph_for_color(red, green, blue);
+            return Cell::new(glyph.character(), glyph.color());
+        }
+
+        let luminosity = ((red as u32 + green as u32 + blue as u32) / 3) as u8;
+        let character = config.pallet().character_for_luminosity(luminosity).unwrap_or('�');
+
+        if !config.color() {
+            Cell::new(character, None)
+        } else {
+            Cell::new(character, Some(Color::TrueColor { r: red, g: green, b: blue }))
+        }
+    }
+}
+
+/// Stores the rows of glyphs of a rendered image
+#[derive(Clone, Debug, PartialEq)]
+pub struct CellGrid {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+}
+
+impl CellGrid {
+    /// Creates a grid from the cells, row by row
+    pub fn new(width: usize, height: usize, cells: Vec<Cell>) -> CellGrid {
+        assert_eq!(width * height, cells.len(), "The grid should have exactly width * height cells");
+
+        CellGrid {
+            width,
+            height,
+            cells,
+        }
+    }
+
+    /// Creates a grid by putting the rows one after the other
+    pub fn from_rows(rows: Vec<Vec<Cell>>) -> CellGrid {
+        let width = rows.first().map_or(0, |r| r.len());
+        let height = rows.len();
+        let cells = rows.into_iter().flatten().collect();
+
+        CellGrid::new(width, height, cells)
+    }
+}
+
+impl CellGrid {
+    /// Number of glyphs per row
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    pub fn cells_mut(&mut self) -> &mut [Cell] {
+        &mut self.cells
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &[Cell]> {
+        // chunks panics on a size of 0
+        self.cells.chunks(self.width.max(1))
+    }
+
+    /// The text to print in the terminal, with the color escape codes
+    pub fn to_text(&self) -> String {
+        // Adding the escape codes is slow, so the rows are done in parallel
+        let text_rows = self.cells.par_chunks(self.width.max(1)).map(|row| {
+            let mut text_row = String::new();
+            for cell in row {
+                match cell.color {
+                    // Without this .to_string() the output is not colored
+                    Some(c) => text_row.push_str(&cell.character.to_string().color(c).to_string()),
+                    None => text_row.push(cell.character),
+                }
+            }
+            text_row.push('\n');
+
+            text_row
+        }).collect::<Vec<String>>();
+
+        text_rows.concat()
+    }
+
+    /// The text with the color escape codes, even when the output is not a terminal.
+    /// The color is only set when it changes and the rows are separated by `line_break`.
+    pub fn to_ansi_text(&self, line_break: &str) -> String {
+        let mut text = String::new();
+        for (i, row) in self.rows().enumerate() {
+            if i > 0 {
+                text.push_str(line_break);
+            }
+
+            let mut current_color = None;
+            for cell in row {
+                if cell.color != current_color {
+                    match cell.color {
+                        Some(c) => text.push_str(&format!("\x1b[{}m", foreground_code(c))),
+                        None => text.push_str("\x1b[0m"),
+                    }
+                    current_color = cell.color;
+                }
+                text.push(cell.character);
+            }
+
+            // The colors do not leak to the next rows
+            if current_color.is_some() {
+                text.push_str("\x1b[0m");
+            }
+        }
+
+        text
+    }
+}
+
+/// The SGR parameters setting the color of the text
+fn foreground_code(color: Color) -> String {
+    match color {
+        // colored can lower true colors depending on the terminal, the text has to be the same everywhere
+        Color::TrueColor { r, g, b } => format!("38;2;{r};{g};{b}"),
+        c => c.to_fg_str().to_string(),
+    }
+}
+
+impl ImageAsString for CellGrid {
+    fn as_string(&self, _config: &Config) -> String {
+        self.to_text()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ansi_text_only_sets_changed_colors() {
+        let red = Some(Color::Red);
+        let grid = CellGrid::from_rows(vec![
+            vec![Cell::new('a', red), Cell::new('b', red), Cell::new('c', None)],
+            vec![Cell::new('d', None), Cell::new('e', Some(Color::TrueColor { r: 1, g: 2, b: 3 })), Cell::new('f', None)],
+        ]);
+
+        assert_eq!(
+            "\x1b[31mab\x1b[0mc\r\nd\x1b[38;2;1;2;3me\x1b[0mf",
+            grid.to_ansi_text("\r\n"),
+        );
+    }
+}
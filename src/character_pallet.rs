@@ -4,19 +4,36 @@ use std::fmt::Display;
 use std::fs;
 use std::io;
 
+use unicode_width::UnicodeWidthChar;
+
 #[derive(Debug)]
 pub enum CharacterPalletParsingError {
     IoError(io::Error),
-    FormattingError,
+    FormattingError(String),
+    UndisplayableCharacterError(String, char),
+    MixedWidthsError(String),
 }
 
 impl Display for CharacterPalletParsingError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             CharacterPalletParsingError::IoError(e) => write!(f, "{}", e),
-            CharacterPalletParsingError::FormattingError => write!(
+            CharacterPalletParsingError::FormattingError(name) => write!(
+                f,
+                "The pallet '{}' is not formatted properly, it needs a line of characters and optionally a line of weights",
+                name,
+            ),
+            CharacterPalletParsingError::UndisplayableCharacterError(name, c) => write!(
                 f,
-                "There was an error in the formatting of the file being parsed",
+                "The pallet '{}' contains {:?} which does not take any space when displayed",
+                name,
+                c,
+            ),
+            CharacterPalletParsingError::MixedWidthsError(name) => write!(
+                f,
+                "The pallet '{}' mixes characters of different widths (like 'a' and '🟥'), \
+                all the characters need to take the same number of columns",
+                name,
             ),
         }
     }
@@ -24,62 +41,257 @@ impl Display for CharacterPalletParsingError {
 
 impl Error for CharacterPalletParsingError {}
 
+impl CharacterPalletParsingError {
+    /// Name of the pallet that could not be parsed, `None` when the whole file could not be read
+    pub fn pallet_name(&self) -> Option<&str> {
+        match self {
+            CharacterPalletParsingError::IoError(_) => None,
+            CharacterPalletParsingError::FormattingError(name)
+            | CharacterPalletParsingError::UndisplayableCharacterError(name, _)
+            | CharacterPalletParsingError::MixedWidthsError(name) => Some(name),
+        }
+    }
+}
+
 /// Stores a pallet of characters of differing luminosity values
 #[derive(Clone)]
 pub struct CharacterPallet {
     pub name: String,
     characters: Vec<char>, // Emptiest to densest
+    weights: Vec<u32>, // Width of the luminosity slice of each character
 }
 
 impl CharacterPallet {
-    fn new(name: String, characters: Vec<char>) -> CharacterPallet {
-        CharacterPallet { name, characters }
+    /// Creates a pallet where every character covers the same range of luminosity
+    pub fn new(name: String, characters: Vec<char>) -> CharacterPallet {
+        let weights = vec![1; characters.len()];
+        CharacterPallet { name, characters, weights }
+    }
+
+    fn new_weighted(name: String, characters: Vec<char>, weights: Vec<u32>) -> CharacterPallet {
+        CharacterPallet { name, characters, weights }
     }
 }
 
 impl CharacterPallet {
     /// Gives a character that conresponds best to the luminosity within the pallet
     /// 
-    /// This assumes that the luminosity of the characters linear
+    /// Each character covers a slice of luminosity proportional to its weight
     pub fn character_for_luminosity(&self, luminosity: u8) -> Option<char> {
-        let nb_divisions = (self.characters.len() as u8).checked_sub(1)?;
+        let last_index = self.characters.len().checked_sub(1)?;
+
+        let total_weight: u32 = self.weights.iter().sum();
+        let position = luminosity as f32 / u8::MAX as f32 * total_weight as f32;
+
+        let mut slice_end = 0;
+        for (i, weight) in self.weights.iter().enumerate() {
+            slice_end += weight;
+            if position < slice_end as f32 {
+                return Some(self.characters[i]);
+            }
+        }
+
+        Some(self.characters[last_index])
+    }
+
+    /// Characters of the pallet from emptiest to densest
+    pub fn characters(&self) -> &[char] {
+        &self.characters
+    }
+
+    /// Weights of the characters, in the same order as `characters()`
+    pub fn weights(&self) -> &[u32] {
+        &self.weights
+    }
+
+    /// Inserts a character at the index with a weight of 1
+    pub fn insert(&mut self, index: usize, character: char) {
+        self.characters.insert(index, character);
+        self.weights.insert(index, 1);
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        self.characters.remove(index);
+        self.weights.remove(index);
+    }
+
+    /// Swaps two characters along with their weights
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.characters.swap(a, b);
+        self.weights.swap(a, b);
+    }
+
+    /// Sets the weight of a character, a weight of 0 is brought back up to 1
+    pub fn set_weight(&mut self, index: usize, weight: u32) {
+        self.weights[index] = weight.max(1);
+    }
+
+    /// Number of terminal columns taken by each character of the pallet
+    pub fn glyph_width(&self) -> usize {
+        self.characters.first()
+            .and_then(|c| display_width(*c))
+            .unwrap_or(1)
+    }
+
+    /// Checks if the character can be displayed alongside the others of the pallet
+    pub fn accepts(&self, character: char) -> bool {
+        match display_width(character) {
+            Some(w) => self.characters.is_empty() || w == self.glyph_width(),
+            None => false,
+        }
+    }
+
+    fn has_uniform_weights(&self) -> bool {
+        self.weights.iter().all(|w| *w == 1)
+    }
+}
+
+/// Number of terminal columns taken by the character, `None` if it does not take any space
+pub fn display_width(character: char) -> Option<usize> {
+    match character.width() {
+        Some(0) | None => None,
+        w => w,
+    }
+}
 
-        let luminosity_slice_width = u8::MAX as f32 / nb_divisions as f32;
-        // Rust automitically rounds up
-        let luminosity_slice_index = luminosity as f32 / luminosity_slice_width;
+/// Makes sure all the characters take the same, non-zero, number of columns.
+/// Otherwise the rows of the image would not be aligned.
+fn check_widths(name: &str, characters: &[char]) -> Result<(), CharacterPalletParsingError> {
+    let mut widths = Vec::new();
+    for c in characters {
+        match display_width(*c) {
+            Some(w) => widths.push(w),
+            None => return Err(CharacterPalletParsingError::UndisplayableCharacterError(name.to_string(), *c)),
+        }
+    }
 
-        Some(self.characters[luminosity_slice_index as usize])
+    if widths.windows(2).any(|w| w[0] != w[1]) {
+        return Err(CharacterPalletParsingError::MixedWidthsError(name.to_string()));
     }
+
+    Ok(())
 }
 
 /// Parses `CharacterPallet` from a file, please refer to the formatting in `character-pallets.txt`
-/// to understand how to properly format a file for parseing
+/// to understand how to properly format a file for parseing.
+/// The pallets that are not valid are left out, the errors saying why are given along with the valid ones.
 pub fn parse_pallets_from_file(
     path: &str,
-) -> Result<HashMap<String, CharacterPallet>, CharacterPalletParsingError> {
+) -> Result<(HashMap<String, CharacterPallet>, Vec<CharacterPalletParsingError>), CharacterPalletParsingError> {
     let input = match fs::read_to_string(path) {
         Ok(i) => i,
         Err(e) => return Err(CharacterPalletParsingError::IoError(e)),
     };
 
+    Ok(parse_pallets(&input))
+}
+
+fn parse_pallets(input: &str) -> (HashMap<String, CharacterPallet>, Vec<CharacterPalletParsingError>) {
+    let lines = input.lines().collect::<Vec<&str>>();
     let mut character_pallets = HashMap::new();
-    for (i, line) in input.lines().enumerate() {
-        if line.ends_with(':') {
-            // This SHOULD never cause a crash
-            let name = line[0..(line.len() - 1)].to_string();
-            let mut characters = match input.lines().nth(i + 1) {
-                Some(l) => l.chars().collect::<Vec<char>>(),
-                None => return Err(CharacterPalletParsingError::FormattingError),
-            };
-            // Reverts the character to be emptiest to densest
-            characters.reverse();
-
-            let new_character_pallet = CharacterPallet::new(name.clone(), characters);
-            character_pallets.insert(name, new_character_pallet);
+    let mut invalid_pallets = Vec::new();
+
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(name) = line.strip_suffix(':') {
+            // One pallet edited badly does not keep the others from being used
+            match parse_pallet(name, &lines[i + 1..]) {
+                Ok(p) => {
+                    character_pallets.insert(name.to_string(), p);
+                },
+                Err(e) => invalid_pallets.push(e),
+            }
         }
     }
 
-    Ok(character_pallets)
+    (character_pallets, invalid_pallets)
+}
+
+/// Parses the pallet from the lines following its name
+fn parse_pallet(name: &str, lines: &[&str]) -> Result<CharacterPallet, CharacterPalletParsingError> {
+    let mut characters = match lines.first() {
+        Some(l) => l.chars().collect::<Vec<char>>(),
+        None => return Err(CharacterPalletParsingError::FormattingError(name.to_string())),
+    };
+    // Reverts the character to be emptiest to densest
+    characters.reverse();
+    check_widths(name, &characters)?;
+
+    let weights_line = lines.get(1)
+        .filter(|l| !l.ends_with(':'))
+        .and_then(|l| l.strip_prefix(WEIGHTS_PREFIX));
+    let weights = match weights_line {
+        Some(l) => parse_weights(name, l, characters.len())?,
+        None => vec![1; characters.len()],
+    };
+
+    Ok(CharacterPallet::new_weighted(name.to_string(), characters, weights))
+}
+
+/// Prefix of the optional line following the characters which gives their weights
+const WEIGHTS_PREFIX: &str = "weights:";
+
+fn parse_weights(name: &str, line: &str, nb_characters: usize) -> Result<Vec<u32>, CharacterPalletParsingError> {
+    let mut weights = Vec::new();
+    for weight in line.split_whitespace() {
+        match weight.parse::<u32>() {
+            Ok(w) => weights.push(w.max(1)),
+            Err(_) => return Err(CharacterPalletParsingError::FormattingError(name.to_string())),
+        }
+    }
+
+    if weights.len() != nb_characters {
+        return Err(CharacterPalletParsingError::FormattingError(name.to_string()));
+    }
+    // Weights are written in the same order as the characters, densest to emptiest
+    weights.reverse();
+
+    Ok(weights)
+}
+
+/// Saves the pallet in the file, replacing the pallet of the same name if there is one.
+/// The other pallets of the file are left untouched.
+pub fn save_pallet_to_file(path: &str, pallet: &CharacterPallet) -> Result<(), CharacterPalletParsingError> {
+    let input = match fs::read_to_string(path) {
+        Ok(i) => i,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(CharacterPalletParsingError::IoError(e)),
+    };
+
+    let header = format!("{}:", pallet.name);
+    let mut pallet_lines = vec![pallet.characters.iter().rev().collect::<String>()];
+    if !pallet.has_uniform_weights() {
+        let weights = pallet.weights.iter().rev()
+            .map(|w| w.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+        pallet_lines.push(format!("{WEIGHTS_PREFIX} {weights}"));
+    }
+
+    let mut lines = input.lines().map(|l| l.to_string()).collect::<Vec<String>>();
+    match lines.iter().position(|l| *l == header) {
+        Some(i) => {
+            // Removes the old characters and weights
+            let mut old_end = (i + 2).min(lines.len());
+            if lines.get(old_end).is_some_and(|l| l.starts_with(WEIGHTS_PREFIX) && !l.ends_with(':')) {
+                old_end += 1;
+            }
+            lines.splice((i + 1)..old_end, pallet_lines);
+        },
+        None => {
+            if lines.last().is_some_and(|l| !l.is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(header);
+            lines.extend(pallet_lines);
+        },
+    }
+
+    let mut output = lines.join("\n");
+    output.push('\n');
+    match fs::write(path, output) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(CharacterPalletParsingError::IoError(e)),
+    }
 }
 
 #[cfg(test)]
@@ -94,10 +306,11 @@ mod tests {
 
     #[test]
     fn parseing_works() {
-        let pallets =
+        let (pallets, invalid_pallets) =
             parse_pallets_from_file("./test-assets/character-pallets.txt").unwrap();
 
-        assert_ne!(0, pallets.len())
+        assert_ne!(0, pallets.len());
+        assert!(invalid_pallets.is_empty());
     }
 
     #[test]
@@ -114,4 +327,62 @@ mod tests {
             pallet.character_for_luminosity(l).unwrap();
         }
     }
+
+    #[test]
+    fn wide_pallets_are_parsed() {
+        let (pallets, _) = parse_pallets("squares:\n🟥🟧🟨\n");
+
+        assert_eq!(2, pallets.get("squares").unwrap().glyph_width());
+        assert_eq!(1, new_ascii_pallet().glyph_width());
+    }
+
+    #[test]
+    fn mixed_widths_are_rejected() {
+        let (pallets, invalid_pallets) = parse_pallets("mixed:\n🟥#.\n");
+        assert!(pallets.is_empty());
+        assert!(matches!(invalid_pallets[..], [CharacterPalletParsingError::MixedWidthsError(_)]));
+
+        let pallet = new_ascii_pallet();
+        assert!(pallet.accepts('x'));
+        assert!(!pallet.accepts('🟥'));
+        assert!(!pallet.accepts('\u{200B}'));
+    }
+
+    #[test]
+    fn invalid_pallets_are_left_out() {
+        let (pallets, invalid_pallets) = parse_pallets("blank:\n\u{200B}\nweighted:\n#.\nweights: 1\nascii:\n#.\n");
+
+        assert_eq!(vec!["ascii"], pallets.keys().collect::<Vec<&String>>());
+        assert_eq!(vec![Some("blank"), Some("weighted")], invalid_pallets.iter().map(|e| e.pallet_name()).collect::<Vec<Option<&str>>>());
+    }
+
+    #[test]
+    fn weights_widen_luminosity_slices() {
+        let mut pallet = CharacterPallet::new("pallet".to_string(), vec![' ', '#']);
+        assert_eq!(Some(' '), pallet.character_for_luminosity(100));
+
+        pallet.set_weight(1, 3);
+        assert_eq!(Some('#'), pallet.character_for_luminosity(100));
+        assert_eq!(Some(' '), pallet.character_for_luminosity(60));
+    }
+
+    #[test]
+    fn saving_then_parseing_keeps_pallet() {
+        let path = "./test-assets/saved-pallets.txt";
+        let _ = fs::remove_file(path);
+
+        let mut pallet = new_ascii_pallet();
+        pallet.set_weight(0, 4);
+        save_pallet_to_file(path, &pallet).unwrap();
+        // Saving twice should replace and not duplicate the pallet
+        save_pallet_to_file(path, &pallet).unwrap();
+
+        let (pallets, _) = parse_pallets_from_file(path).unwrap();
+        let saved = pallets.get("ascii").unwrap();
+        assert_eq!(pallet.characters(), saved.characters());
+        assert_eq!(pallet.weights(), saved.weights());
+        assert_eq!(1, fs::read_to_string(path).unwrap().matches("ascii:").count());
+
+        fs::remove_file(path).unwrap();
+    }
 }
@@ -1,15 +1,26 @@
 use std::{fmt::Display, error::Error};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use crate::character_pallet::{CharacterPallet, self};
+use crate::character_pallet::{CharacterPallet, CharacterPalletParsingError, self};
+use crate::ansi_dump::AnsiHeaders;
+use crate::mosaic::{MosaicKind, MosaicTable};
+use crate::quality::Interpolation;
+use crate::visualizer::VisualizerKind;
+use crate::time_stretch::{MAX_SPEED, MIN_SPEED};
+use crate::audio_output::{AudioOutput, AudioOutputKind};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// The url or path to use when searching the video
-    #[arg(short, long)]
-    query: String,
+    #[arg(short, long, required = true)]
+    query: Option<String>,
 
     /// Pallet of characters
     #[arg(short, long, default_value = "ascii")] 
@@ -19,91 +30,372 @@ struct Args {
     #[arg(short, long, default_value_t = 100)]
     width: u32,
 
-    /// Limits the frame rate to this (set to 0 for the video's native)
-    #[arg(short, long, default_value_t = 15)]
-    frame_limit: u32,
+    /// Limits the frame rate to this (set to 0 for the video's native) [default: 15]
+    #[arg(short, long)]
+    frame_limit: Option<u32>,
 
     /// Sets the volume (can be over 1.0)
     #[arg(short, long, default_value_t = 1.0)]
     volume: f32,
 
-    /// Preprocesses the frames (barely increases performance and takes up a lot of RAM)
+    /// Plays the video faster or slower, from 0.25 to 4.0 (the audio keeps its pitch)
+    #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+    speed: f64,
+
+    /// Shows the frames this many milliseconds later, for audio that is heard late like on Bluetooth (can be negative)
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    av_offset: i64,
+
+    /// Keeps the full quality even when the frames can't be rendered in time, instead of lowering the frame rate, width or scaling quality
+    #[arg(long, default_value_t = false)]
+    no_adaptive_quality: bool,
+
+    /// Preprocesses all the frames before playing (obsolete, frames are already rendered ahead in the background)
     #[arg(long, default_value_t = false)]
     preprocess: bool,
 
+    /// Compresses the preprocessed frames with zstd (takes less RAM, but a bit more processing)
+    #[arg(long, default_value_t = false, requires = "preprocess")]
+    compress: bool,
+
     /// Disables the use of color
     #[arg(long, default_value_t = false)]
     no_color: bool,
+
+    /// Starts playing at this timestamp (hh:mm:ss.ms)
+    #[arg(long, value_parser = parse_timestamp)]
+    start: Option<Duration>,
+
+    /// Stops playing at this timestamp (hh:mm:ss.ms)
+    #[arg(long, value_parser = parse_timestamp, conflicts_with = "duration")]
+    end: Option<Duration>,
+
+    /// Stops playing after this much time (hh:mm:ss.ms)
+    #[arg(long, value_parser = parse_timestamp)]
+    duration: Option<Duration>,
+
+    /// Plays the audio as loud as it is in the video, instead of evening out the loudness of videos
+    #[arg(long, default_value_t = false)]
+    no_loudness_normalization: bool,
+
+    /// Plays this audio track of videos with several ones, from 0 (see --list-audio-tracks)
+    #[arg(long, default_value_t = 0)]
+    audio_track: usize,
+
+    /// Lists the audio tracks of the video with their language, codec and channels
+    #[arg(long, default_value_t = false, conflicts_with_all = ["edit_pallet", "export"])]
+    list_audio_tracks: bool,
+
+    /// Where the audio is played, null plays it silently (falls back to null when there is no sound card)
+    #[arg(long, value_enum, default_value_t = AudioOutputKind::Device)]
+    audio_output: AudioOutputKind,
+
+    /// Writes the audio that is played to this WAV file instead of playing it
+    #[arg(long, value_name = "FILE", conflicts_with = "audio_output")]
+    write_audio: Option<PathBuf>,
+
+    /// How audio files are shown
+    #[arg(long, value_enum, default_value_t = VisualizerKind::Spectrum)]
+    visualizer: VisualizerKind,
+
+    /// Renders with colored glyphs picked by color instead of the pallet (does not need true color support)
+    #[arg(long, value_enum)]
+    mosaic: Option<MosaicKind>,
+
+    /// Reads the rendered frames from the cache, and saves them there when the video is not cached yet
+    #[arg(long, default_value_t = false)]
+    cache: bool,
+
+    /// Opens the pallet in an editor previewing a frame of the video (the pallet is created if it does not exist)
+    #[arg(long, default_value_t = false)]
+    edit_pallet: bool,
+
+    /// Renders the video to this file instead of playing it, the format is given by the extension (.ctv, .cast, .gif, .mp4, .html, .ans),
+    /// the audio of .mp4 and .html files needs FFMPEG
+    #[arg(long, conflicts_with = "edit_pallet")]
+    export: Option<String>,
+
+    /// Only exports the frame at the start
+    #[arg(long, default_value_t = false, requires = "export")]
+    single_frame: bool,
+
+    /// What is written before each frame of exported .ans files
+    #[arg(long, value_enum, default_value_t = AnsiHeaders::None, requires = "export")]
+    ans_headers: AnsiHeaders,
+
+    /// Puts the audio in the exported html file so that it plays along with the frames
+    #[arg(long, default_value_t = false, requires = "export")]
+    embed_audio: bool,
+}
+
+/// Actions that do not play a video
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Shows or cleans up the cache of rendered frames
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CacheAction {
+    /// Lists the cached videos with their size and rendering settings
+    List,
+    /// Removes the cached videos
+    Prune {
+        /// Only removes the videos that were not played in this many days
+        #[arg(long)]
+        older_than_days: Option<u64>,
+    },
 }
 
+/// Frame limit when `-f` is not given
+const DEFAULT_FRAME_LIMIT: u32 = 15;
+
+/// File from which the character pallets are parsed and to which the pallet editor saves
+pub const PALLET_FILE_PATH: &str = "character-pallets.txt";
+
 #[derive(Debug)]
-pub struct PalletDoesNotExistError;
+pub enum PalletError {
+    PalletDoesNotExistError(String),
+    InvalidPalletError(CharacterPalletParsingError),
+    PalletFileError(CharacterPalletParsingError),
+}
 
-impl Display for PalletDoesNotExistError {
+impl Display for PalletError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "The pallet specified is not in the list of available pallets")
+        match self {
+            PalletError::PalletDoesNotExistError(name) => write!(
+                f,
+                "The pallet '{}' is not in the list of available pallets",
+                name,
+            ),
+            PalletError::InvalidPalletError(e) => write!(f, "{}", e),
+            PalletError::PalletFileError(e) => write!(f, "The pallets could not be read from {}: {}", PALLET_FILE_PATH, e),
+        }
     }
 }
 
-impl Error for PalletDoesNotExistError {}
+impl Error for PalletError {}
 
+#[derive(Debug)]
+pub struct EmptyRangeError;
+
+impl Display for EmptyRangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The end of the video to play needs to be after its start")
+    }
+}
+
+impl Error for EmptyRangeError {}
+
+/// Parses timestamps formatted as `hh:mm:ss.ms`, the hours, minutes and milliseconds can be left out.
+/// The first field can go over its unit (`90` or `90:00`), the next ones are under 60.
+fn parse_timestamp(timestamp: &str) -> Result<Duration, String> {
+    let error = || format!("'{timestamp}' is not a timestamp formatted as hh:mm:ss.ms");
+    let is_number = |text: &str| !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit());
+
+    let parts = timestamp.split(':').collect::<Vec<&str>>();
+    if parts.len() > 3 {
+        return Err(error());
+    }
+
+    // Only the seconds have a fractional part
+    let (seconds, larger_units) = parts.split_last().ok_or_else(error)?;
+    let (whole_seconds, fraction) = match seconds.split_once('.') {
+        Some((w, f)) => (w, Some(f)),
+        None => (*seconds, None),
+    };
+
+    let mut total_seconds: u64 = 0;
+    for (i, field) in larger_units.iter().copied().chain([whole_seconds]).enumerate() {
+        if !is_number(field) {
+            return Err(error());
+        }
+        let value = field.parse::<u64>().map_err(|_| error())?;
+        if i > 0 && value >= 60 {
+            return Err(error());
+        }
+        total_seconds = total_seconds.checked_mul(60)
+            .and_then(|t| t.checked_add(value))
+            .ok_or_else(error)?;
+    }
+
+    let fraction = match fraction {
+        Some(f) if is_number(f) => format!("0.{f}").parse::<f64>().map_err(|_| error())?,
+        Some(_) => return Err(error()),
+        None => 0.0,
+    };
+
+    Ok(Duration::from_secs(total_seconds) + Duration::from_secs_f64(fraction))
+}
+
+fn parse_speed(speed: &str) -> Result<f64, String> {
+    match speed.parse::<f64>() {
+        Ok(s) if (MIN_SPEED..=MAX_SPEED).contains(&s) => Ok(s),
+        _ => Err(format!("'{speed}' is not a speed between {MIN_SPEED} and {MAX_SPEED}")),
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
     query: String,
     pallet: CharacterPallet,
     width: u32,
     frame_limit: u32,
+    frame_limit_given: bool, // Whether the frame limit was chosen or is the default
     volume: f32,
+    speed: f64,
+    normalize_loudness: bool,
+    av_offset: i64,
+    audio_track: usize,
+    list_audio_tracks: bool,
+    audio_output: AudioOutput,
+    adaptive_quality: bool,
+    interpolation: Interpolation,
     color: bool,
     preprocessing: bool, 
+    compress_frames: bool,
+    start: Duration,
+    end: Option<Duration>,
+    mosaic: Option<MosaicTable>,
+    visualizer: VisualizerKind,
+    cache: bool,
+    edit_pallet: bool,
+    export: Option<String>,
+    single_frame: bool,
+    ans_headers: AnsiHeaders,
+    embed_audio: bool,
+    command: Option<Command>,
 }
 
 impl Config {
-    pub fn build(query: String, pallet: String, width: u32, frame_limit: u32, volume: f32, color: bool, preprocessing: bool) -> Result<Config, PalletDoesNotExistError> {
-        let character_pallets = match character_pallet::parse_pallets_from_file("character-pallets.txt") {
-            Ok(p) => p,
-            Err(e) => panic!("Error while parseing: {e}"),
-        };
-
-        let pallet = match character_pallets.get(&pallet) {
-            Some(p) => p,
-            None => return Err(PalletDoesNotExistError),
-        };
-        let pallet = (*pallet).clone();
+    pub fn build(query: String, pallet: String, width: u32, frame_limit: u32, volume: f32, color: bool, preprocessing: bool) -> Result<Config, PalletError> {
+        let pallet = Config::find_pallet(&pallet)?;
 
         let config = Config {
             query,
             pallet,
             width,
             frame_limit,
+            frame_limit_given: true,
             volume,
+            speed: 1.0,
+            normalize_loudness: false,
+            av_offset: 0,
+            audio_track: 0,
+            list_audio_tracks: false,
+            audio_output: AudioOutput::Device,
+            adaptive_quality: false,
+            interpolation: Interpolation::Lanczos,
             color,
             preprocessing,
+            compress_frames: false,
+            start: Duration::ZERO,
+            end: None,
+            mosaic: None,
+            visualizer: VisualizerKind::Spectrum,
+            cache: false,
+            edit_pallet: false,
+            export: None,
+            single_frame: false,
+            ans_headers: AnsiHeaders::None,
+            embed_audio: false,
+            command: None,
         };
 
         Ok(config)
     }
 
-    pub fn build_from_args() -> Result<Config, PalletDoesNotExistError> {
+    pub fn build_from_args() -> Result<Config, Box<dyn Error>> {
         let args = Args::parse();
 
-        let frame_limit = if args.frame_limit == 0 {
-            u32::MAX
-        } else {
-            args.frame_limit
+        let start = args.start.unwrap_or(Duration::ZERO);
+        let end = match (args.end, args.duration) {
+            (Some(end), _) => Some(end),
+            (None, Some(duration)) => Some(start + duration),
+            (None, None) => None,
+        };
+        if end.is_some_and(|e| e <= start) {
+            return Err(Box::new(EmptyRangeError));
+        }
+
+        let frame_limit = match args.frame_limit.unwrap_or(DEFAULT_FRAME_LIMIT) {
+            0 => u32::MAX,
+            l => l,
+        };
+
+        let audio_output = match (args.write_audio, args.audio_output) {
+            (Some(path), _) => AudioOutput::Wav(path),
+            (None, AudioOutputKind::Device) => AudioOutput::Device,
+            (None, AudioOutputKind::Null) => AudioOutput::Null,
+        };
+
+        // The editor can create new pallets so the pallet does not need to exist
+        let pallet = match Config::find_pallet(&args.pallet) {
+            Ok(p) => p,
+            Err(_) if args.edit_pallet => CharacterPallet::new(args.pallet, Vec::new()),
+            Err(e) => return Err(Box::new(e)),
         };
 
-        let config = Config::build(
-            args.query,
-            args.pallet,
-            args.width,
+        let config = Config {
+            // The query is only missing when there is a command
+            query: args.query.unwrap_or_default(),
+            pallet,
+            width: args.width,
             frame_limit,
-            args.volume,
-            !args.no_color,
-            args.preprocess,
-        )?;
+            frame_limit_given: args.frame_limit.is_some(),
+            volume: args.volume,
+            speed: args.speed,
+            normalize_loudness: !args.no_loudness_normalization,
+            av_offset: args.av_offset,
+            audio_track: args.audio_track,
+            list_audio_tracks: args.list_audio_tracks,
+            audio_output,
+            adaptive_quality: !args.no_adaptive_quality,
+            interpolation: Interpolation::Lanczos,
+            color: !args.no_color,
+            preprocessing: args.preprocess,
+            compress_frames: args.compress,
+            start,
+            end,
+            mosaic: args.mosaic.map(MosaicTable::build),
+            visualizer: args.visualizer,
+            cache: args.cache,
+            edit_pallet: args.edit_pallet,
+            export: args.export,
+            single_frame: args.single_frame,
+            ans_headers: args.ans_headers,
+            embed_audio: args.embed_audio,
+            command: args.command,
+        };
 
         Ok(config)
     }
+
+    fn find_pallet(name: &str) -> Result<CharacterPallet, PalletError> {
+        let (character_pallets, invalid_pallets) = match character_pallet::parse_pallets_from_file(PALLET_FILE_PATH) {
+            Ok(p) => p,
+            Err(e) => return Err(PalletError::PalletFileError(e)),
+        };
+
+        // The other invalid pallets are reported so that they can be fixed
+        let mut invalid_pallet = None;
+        for e in invalid_pallets {
+            if e.pallet_name() == Some(name) {
+                invalid_pallet = Some(e);
+            } else {
+                eprintln!("Skipped a pallet of {PALLET_FILE_PATH}: {e}");
+            }
+        }
+
+        match (character_pallets.get(name), invalid_pallet) {
+            (Some(p), _) => Ok(p.clone()),
+            (None, Some(e)) => Err(PalletError::InvalidPalletError(e)),
+            (None, None) => Err(PalletError::PalletDoesNotExistError(name.to_string())),
+        }
+    }
 }
 
 impl Config {
@@ -119,19 +411,230 @@ impl Config {
         self.width
     }
 
+    pub fn set_width(&mut self, width: u32) {
+        self.width = width;
+    }
+
     pub fn frame_limit(&self) -> u32 {
         self.frame_limit
     }
 
+    /// The frame limit if it was chosen with `-f`, `None` when it is the default
+    pub fn given_frame_limit(&self) -> Option<u32> {
+        self.frame_limit_given.then_some(self.frame_limit)
+    }
+
     pub fn volume(&self) -> f32 {
         self.volume
     }
 
+    /// How fast the video is played, 1.0 is the normal speed
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Whether the audio is brought to the same loudness for every video
+    pub fn normalize_loudness(&self) -> bool {
+        self.normalize_loudness
+    }
+
+    /// Milliseconds the frames are shown after the audio they go with is played
+    pub fn av_offset(&self) -> i64 {
+        self.av_offset
+    }
+
+    /// Index of the played audio track among the audio tracks of the video
+    pub fn audio_track(&self) -> usize {
+        self.audio_track
+    }
+
+    pub fn set_audio_track(&mut self, track: usize) {
+        self.audio_track = track;
+    }
+
+    /// Whether the audio tracks are listed instead of playing the video
+    pub fn list_audio_tracks(&self) -> bool {
+        self.list_audio_tracks
+    }
+
+    /// Where the audio is played
+    pub fn audio_output(&self) -> &AudioOutput {
+        &self.audio_output
+    }
+
+    /// Whether the quality is lowered while the frames can't be played in time
+    pub fn adaptive_quality(&self) -> bool {
+        self.adaptive_quality
+    }
+
+    /// How the frames are scaled to the rendered size
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
     pub fn color(&self) -> bool {
         self.color
     }
 
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
     pub fn preprocessing(&self) -> bool {
         self.preprocessing
     }
-}
\ No newline at end of file
+
+    /// Whether the preprocessed frames are compressed with zstd
+    pub fn compress_frames(&self) -> bool {
+        self.compress_frames
+    }
+
+    /// Timestamp in the video where the playback starts
+    pub fn start(&self) -> Duration {
+        self.start
+    }
+
+    /// Timestamp in the video where the playback ends, if it ends before the video does
+    pub fn end(&self) -> Option<Duration> {
+        self.end
+    }
+
+    /// Restricts the playback to a part of the video
+    pub fn set_range(&mut self, start: Duration, end: Option<Duration>) {
+        self.start = start;
+        self.end = end;
+    }
+
+    /// The table of glyphs to use instead of the pallet, if there is one. The pallet is used
+    /// without color when the glyphs of the table need the colors of the terminal.
+    pub fn mosaic(&self) -> Option<&MosaicTable> {
+        self.mosaic.as_ref().filter(|m| self.color || !m.kind().uses_terminal_colors())
+    }
+
+    /// Number of terminal columns taken by each rendered glyph
+    pub fn glyph_width(&self) -> usize {
+        match self.mosaic() {
+            Some(m) => m.glyph_width(),
+            None => self.pallet.glyph_width(),
+        }
+    }
+
+    /// How audio files are shown
+    pub fn visualizer(&self) -> VisualizerKind {
+        self.visualizer
+    }
+
+    /// Whether rendered frames are read from and written to the cache
+    pub fn cache(&self) -> bool {
+        self.cache
+    }
+
+    /// The action to do instead of playing the video, if there is one
+    pub fn command(&self) -> Option<&Command> {
+        self.command.as_ref()
+    }
+
+    pub fn edit_pallet(&self) -> bool {
+        self.edit_pallet
+    }
+
+    /// The file to render the video to instead of playing it, if there is one
+    pub fn export(&self) -> Option<&str> {
+        self.export.as_deref()
+    }
+
+    /// Whether only the frame at the start is exported
+    pub fn single_frame(&self) -> bool {
+        self.single_frame
+    }
+
+    /// What is written before each frame of exported ANSI dumps
+    pub fn ans_headers(&self) -> AnsiHeaders {
+        self.ans_headers
+    }
+
+    /// Whether the audio is put in exported html files
+    pub fn embed_audio(&self) -> bool {
+        self.embed_audio
+    }
+
+    /// Describes the settings that change the rendered frames
+    pub fn render_settings(&self) -> String {
+        format!(
+            "width: {}, pallet: {} {:?} {:?}, color: {}, mosaic: {:?}, frame limit: {}, range: {:?}..{:?}",
+            self.width,
+            self.pallet.name,
+            self.pallet.characters(),
+            self.pallet.weights(),
+            self.color,
+            self.mosaic.as_ref().map(|m| m.kind()),
+            self.frame_limit,
+            self.start,
+            self.end,
+        )
+    }
+
+    pub fn set_pallet(&mut self, pallet: CharacterPallet) {
+        self.pallet = pallet;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cells::Cell;
+    use crate::test_fixtures::default_config_with_width;
+
+    #[test]
+    fn timestamps_are_parsed() {
+        assert_eq!(Ok(Duration::from_secs(12)), parse_timestamp("12"));
+        assert_eq!(Ok(Duration::from_millis(1_500)), parse_timestamp("1.5"));
+        assert_eq!(Ok(Duration::from_secs(90)), parse_timestamp("1:30"));
+        assert_eq!(Ok(Duration::from_millis(3_723_250)), parse_timestamp("01:02:03.250"));
+        assert_eq!(Ok(Duration::from_secs(90)), parse_timestamp("90"));
+        assert_eq!(Ok(Duration::from_secs(5_400)), parse_timestamp("90:00"));
+    }
+
+    #[test]
+    fn blocks_are_not_used_without_color() {
+        let mut config = default_config_with_width(10);
+        config.mosaic = Some(MosaicTable::build(MosaicKind::Blocks));
+        assert!(Cell::for_pixel(255, 0, 0, &config).color.is_some());
+
+        config.set_color(false);
+        assert!(config.mosaic().is_none());
+        assert_eq!(None, Cell::for_pixel(255, 0, 0, &config).color);
+
+        // The emojis don't use the colors of the terminal
+        config.mosaic = Some(MosaicTable::build(MosaicKind::Emoji));
+        assert!(config.mosaic().is_some());
+    }
+
+    #[test]
+    fn invalid_timestamps_are_rejected() {
+        assert!(parse_timestamp("").is_err());
+        assert!(parse_timestamp("1:2:3:4").is_err());
+        assert!(parse_timestamp("-3").is_err());
+        assert!(parse_timestamp("1.5:00").is_err());
+        assert!(parse_timestamp("ten").is_err());
+        assert!(parse_timestamp("1e3").is_err());
+        assert!(parse_timestamp("1:75").is_err());
+        assert!(parse_timestamp("1:00:60").is_err());
+        assert!(parse_timestamp("+5").is_err());
+        assert!(parse_timestamp("5.").is_err());
+        assert!(parse_timestamp("inf").is_err());
+    }
+
+    #[test]
+    fn speeds_are_kept_in_range() {
+        assert_eq!(Ok(0.25), parse_speed("0.25"));
+        assert_eq!(Ok(1.5), parse_speed("1.5"));
+        assert!(parse_speed("4.5").is_err());
+        assert!(parse_speed("0").is_err());
+        assert!(parse_speed("fast").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Keyboard controls of the player, the keys are read in raw mode by a background thread
+//! so that the frames keep being shown while waiting for them
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+
+/// Seconds skipped by the left and right arrows
+const SHORT_SEEK: i64 = 5;
+/// Seconds skipped by the up and down arrows
+const LONG_SEEK: i64 = 60;
+/// Change of volume for each press of `+` or `-`
+const VOLUME_STEP: f32 = 0.1;
+/// Change of speed for each press of `[` or `]`
+const SPEED_STEP: f64 = 0.25;
+/// Longest time before the input thread checks if it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a key asks the player to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlayerCommand {
+    TogglePause,
+    /// Moves this many seconds forward, or backward when negative
+    Seek(i64),
+    /// Shows the next frame, the video stays paused
+    StepFrame,
+    ChangeVolume(f32),
+    ToggleMute,
+    ChangeSpeed(f64),
+    ToggleColor,
+    NextPallet,
+    NextAudioTrack,
+    Quit,
+}
+
+/// The command of a key, if it has one
+pub fn command_for_key(key: KeyEvent) -> Option<PlayerCommand> {
+    let control = key.modifiers.contains(KeyModifiers::CONTROL);
+
+    let command = match key.code {
+        // Ctrl-C does not send a signal in raw mode
+        KeyCode::Char('c') if control => PlayerCommand::Quit,
+        KeyCode::Char('q') | KeyCode::Esc => PlayerCommand::Quit,
+        KeyCode::Char(' ') => PlayerCommand::TogglePause,
+        KeyCode::Left => PlayerCommand::Seek(-SHORT_SEEK),
+        KeyCode::Right => PlayerCommand::Seek(SHORT_SEEK),
+        KeyCode::Down => PlayerCommand::Seek(-LONG_SEEK),
+        KeyCode::Up => PlayerCommand::Seek(LONG_SEEK),
+        KeyCode::Char('.') => PlayerCommand::StepFrame,
+        KeyCode::Char('+') | KeyCode::Char('=') => PlayerCommand::ChangeVolume(VOLUME_STEP),
+        KeyCode::Char('-') => PlayerCommand::ChangeVolume(-VOLUME_STEP),
+        KeyCode::Char('m') => PlayerCommand::ToggleMute,
+        KeyCode::Char(']') => PlayerCommand::ChangeSpeed(SPEED_STEP),
+        KeyCode::Char('[') => PlayerCommand::ChangeSpeed(-SPEED_STEP),
+        KeyCode::Char('c') => PlayerCommand::ToggleColor,
+        KeyCode::Char('p') => PlayerCommand::NextPallet,
+        KeyCode::Char('a') => PlayerCommand::NextAudioTrack,
+        _ => return None,
+    };
+
+    Some(command)
+}
+
+/// Reads the keys while it is kept, the terminal is put back in its normal mode when it is dropped
+pub struct Controls {
+    commands: Receiver<PlayerCommand>,
+    stop: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Controls {
+    /// Starts reading the keys, it fails when there is no terminal to read them from
+    pub fn start() -> io::Result<Controls> {
+        terminal::enable_raw_mode()?;
+
+        let (sender, commands) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let reader_stop = Arc::clone(&stop);
+        let reader = thread::spawn(move || {
+            while !reader_stop.load(Ordering::Relaxed) {
+                // Polling lets the thread stop even if no key is pressed
+                if !event::poll(POLL_INTERVAL).unwrap_or(false) {
+                    continue;
+                }
+
+                match event::read() {
+                    // Windows also reports the releases of the keys
+                    Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => {
+                        let sent = match command_for_key(key) {
+                            Some(command) => sender.send(command),
+                            None => Ok(()),
+                        };
+                        if sent.is_err() {
+                            return;
+                        }
+                    },
+                    Ok(_) => (),
+                    Err(_) => return,
+                }
+            }
+        });
+
+        Ok(Controls {
+            commands,
+            stop,
+            reader: Some(reader),
+        })
+    }
+}
+
+impl Controls {
+    /// The next command that was not handled yet, if there is one
+    pub fn next_command(&self) -> Option<PlayerCommand> {
+        self.commands.try_recv().ok()
+    }
+}
+
+impl Drop for Controls {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn keys_give_commands() {
+        assert_eq!(Some(PlayerCommand::TogglePause), command_for_key(key(KeyCode::Char(' '), KeyModifiers::NONE)));
+        assert_eq!(Some(PlayerCommand::Seek(-SHORT_SEEK)), command_for_key(key(KeyCode::Left, KeyModifiers::NONE)));
+        assert_eq!(Some(PlayerCommand::Seek(LONG_SEEK)), command_for_key(key(KeyCode::Up, KeyModifiers::NONE)));
+        assert_eq!(Some(PlayerCommand::ChangeVolume(-VOLUME_STEP)), command_for_key(key(KeyCode::Char('-'), KeyModifiers::NONE)));
+        assert_eq!(None, command_for_key(key(KeyCode::Char('z'), KeyModifiers::NONE)));
+    }
+
+    #[test]
+    fn control_c_quits_instead_of_toggling_color() {
+        assert_eq!(Some(PlayerCommand::Quit), command_for_key(key(KeyCode::Char('c'), KeyModifiers::CONTROL)));
+        assert_eq!(Some(PlayerCommand::ToggleColor), command_for_key(key(KeyCode::Char('c'), KeyModifiers::NONE)));
+    }
+}
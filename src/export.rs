@@ -0,0 +1,364 @@
+//! Renders a video to a file instead of the terminal, as fast as the frames can be rendered
+
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use crate::ansi_dump::{self, AnsiWriter};
+use crate::asciicast::{self, AsciicastWriter};
+use crate::audio_decoder::{self, AudioInput};
+use crate::cells::CellGrid;
+use crate::config::Config;
+use crate::frames::FrameReader;
+use crate::html::{self, HtmlWriter};
+use crate::rendered_video::{self, GifWriter, Mp4Writer, RenderedVideoError};
+use crate::text_video::{self, TextVideoError, TextVideoHeader, TextVideoWriter};
+use crate::video::VideoError;
+use crate::workspace;
+
+/// The audio of html files is compressed with FFMPEG since it is embedded as base64,
+/// it is encoded to this file of the session's workspace
+const HTML_AUDIO_FILE_NAME: &str = "html-audio.m4a";
+const HTML_AUDIO_MIME_TYPE: &str = "audio/mp4";
+
+#[derive(Debug)]
+pub enum ExportError {
+    VideoError(VideoError),
+    IoError(io::Error),
+    TextVideoError(TextVideoError),
+    RenderedVideoError(RenderedVideoError),
+    UnsupportedFormatError(String),
+}
+
+impl Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportError::VideoError(e) => write!(f, "{}", e),
+            ExportError::IoError(e) => write!(f, "{}", e),
+            ExportError::TextVideoError(e) => write!(f, "{}", e),
+            ExportError::RenderedVideoError(e) => write!(f, "{}", e),
+            ExportError::UnsupportedFormatError(p) => write!(
+                f,
+                "Cannot export to {p}, the supported extensions are: {}",
+                EXPORT_EXTENSIONS.map(|(e, _)| format!(".{e}")).join(", "),
+            ),
+        }
+    }
+}
+
+impl Error for ExportError {}
+
+impl From<VideoError> for ExportError {
+    fn from(e: VideoError) -> Self {
+        ExportError::VideoError(e)
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(e: io::Error) -> Self {
+        ExportError::IoError(e)
+    }
+}
+
+impl From<TextVideoError> for ExportError {
+    fn from(e: TextVideoError) -> Self {
+        ExportError::TextVideoError(e)
+    }
+}
+
+impl From<RenderedVideoError> for ExportError {
+    fn from(e: RenderedVideoError) -> Self {
+        ExportError::RenderedVideoError(e)
+    }
+}
+
+/// The file formats a video can be exported to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    TextVideo,
+    Asciicast,
+    Gif,
+    Mp4,
+    Html,
+    Ansi,
+}
+
+const EXPORT_EXTENSIONS: [(&str, ExportFormat); 6] = [
+    (text_video::TEXT_VIDEO_EXTENSION, ExportFormat::TextVideo),
+    (asciicast::ASCIICAST_EXTENSION, ExportFormat::Asciicast),
+    (rendered_video::GIF_EXTENSION, ExportFormat::Gif),
+    (rendered_video::MP4_EXTENSION, ExportFormat::Mp4),
+    (html::HTML_EXTENSION, ExportFormat::Html),
+    (ansi_dump::ANSI_EXTENSION, ExportFormat::Ansi),
+];
+
+impl ExportFormat {
+    /// Finds the format from the extension of the file
+    pub fn from_path(path: &str) -> Result<ExportFormat, ExportError> {
+        let extension = Path::new(path).extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+
+        EXPORT_EXTENSIONS.iter()
+            .find(|(e, _)| extension.as_deref() == Some(*e))
+            .map(|(_, format)| *format)
+            .ok_or_else(|| ExportError::UnsupportedFormatError(path.to_string()))
+    }
+}
+
+/// Receives the rendered frames in order
+trait FrameSink {
+    /// Adds the frame shown at the index and timestamp in the video
+    fn push(&mut self, index: usize, timestamp: Duration, grid: CellGrid) -> Result<(), ExportError>;
+
+    fn finish(self: Box<Self>) -> Result<(), ExportError>;
+}
+
+impl FrameSink for TextVideoWriter {
+    fn push(&mut self, index: usize, timestamp: Duration, grid: CellGrid) -> Result<(), ExportError> {
+        Ok(TextVideoWriter::push(self, index, timestamp, grid)?)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), ExportError> {
+        Ok(TextVideoWriter::finish(*self)?)
+    }
+}
+
+impl FrameSink for AsciicastWriter {
+    fn push(&mut self, _index: usize, _timestamp: Duration, grid: CellGrid) -> Result<(), ExportError> {
+        // The frames are timed by the frame rate, like when they are played
+        Ok(AsciicastWriter::push(self, &grid)?)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), ExportError> {
+        Ok(AsciicastWriter::finish(*self)?)
+    }
+}
+
+impl FrameSink for GifWriter {
+    fn push(&mut self, _index: usize, _timestamp: Duration, grid: CellGrid) -> Result<(), ExportError> {
+        Ok(GifWriter::push(self, &grid)?)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), ExportError> {
+        Ok(GifWriter::finish(*self)?)
+    }
+}
+
+impl FrameSink for Mp4Writer {
+    fn push(&mut self, _index: usize, _timestamp: Duration, grid: CellGrid) -> Result<(), ExportError> {
+        Ok(Mp4Writer::push(self, &grid)?)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), ExportError> {
+        Ok(Mp4Writer::finish(*self)?)
+    }
+}
+
+impl FrameSink for HtmlWriter {
+    fn push(&mut self, _index: usize, _timestamp: Duration, grid: CellGrid) -> Result<(), ExportError> {
+        Ok(HtmlWriter::push(self, grid)?)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), ExportError> {
+        Ok(HtmlWriter::finish(*self)?)
+    }
+}
+
+impl FrameSink for AnsiWriter {
+    fn push(&mut self, _index: usize, timestamp: Duration, grid: CellGrid) -> Result<(), ExportError> {
+        Ok(AnsiWriter::push(self, timestamp, &grid)?)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), ExportError> {
+        Ok(AnsiWriter::finish(*self)?)
+    }
+}
+
+/// Renders the video at `path` to the file at `output_path`
+pub fn export_video(path: &str, config: &Config, output_path: &str) -> Result<(), ExportError> {
+    let format = ExportFormat::from_path(output_path)?;
+    let mut reader = FrameReader::open(path, config)?;
+
+    let sink: Box<dyn FrameSink> = match format {
+        ExportFormat::TextVideo => {
+            // The audio is stored decoded so that it does not need the video to be played
+            let input = AudioInput::Path(path.to_string());
+            let audio = audio_decoder::decode_to_wav(&input, config.audio_track(), config.start(), config.end()).unwrap_or_default();
+            let header = TextVideoHeader::new(
+                config.render_settings(),
+                reader.native_fps(),
+                reader.target_fps().round() as u32,
+                config.start(),
+                audio,
+            );
+            Box::new(TextVideoWriter::create(Path::new(output_path), &header)?)
+        },
+
+        ExportFormat::Asciicast => {
+            let writer = AsciicastWriter::create(
+                Path::new(output_path),
+                &video_title(path),
+                reader.target_fps(),
+                config.glyph_width(),
+            )?;
+            Box::new(writer)
+        },
+
+        ExportFormat::Gif => Box::new(GifWriter::create(Path::new(output_path), config, reader.target_fps())),
+
+        ExportFormat::Mp4 => Box::new(Mp4Writer::create(Path::new(output_path), path, config, reader.target_fps())?),
+
+        ExportFormat::Html => {
+            let mut audio = None;
+            if config.embed_audio() {
+                let a = encode_audio(path, config, &workspace::temp_path(HTML_AUDIO_FILE_NAME)?);
+                if a.is_empty() {
+                    eprintln!("There is no audio to embed, is FFMPEG installed?");
+                } else {
+                    audio = Some((HTML_AUDIO_MIME_TYPE.to_string(), a));
+                }
+            }
+
+            Box::new(HtmlWriter::create(Path::new(output_path), &video_title(path), reader.target_fps(), audio))
+        },
+
+        ExportFormat::Ansi => Box::new(AnsiWriter::create(Path::new(output_path), config.ans_headers())?),
+    };
+
+    render_frames(&mut reader, config, sink)
+}
+
+/// The played part of the audio encoded by FFMPEG in the format of the extension of `temp_path`,
+/// it is empty when the video has no audio
+fn encode_audio(path: &str, config: &Config, temp_path: &Path) -> Vec<u8> {
+    let _ = fs::remove_file(temp_path);
+
+    // Only the played part of the audio is encoded
+    let mut command = Command::new("ffmpeg");
+    command.args(["-ss", &format!("{:.3}", config.start().as_secs_f64())]);
+    command.arg("-i").arg(workspace::command_path(Path::new(path)));
+    if let Some(end) = config.end() {
+        command.args(["-t", &format!("{:.3}", (end - config.start()).as_secs_f64())]);
+    }
+    command.args(["-map", &format!("0:a:{}", config.audio_track())]);
+    command.arg("-vn").arg(workspace::command_path(temp_path));
+    // The missing file is caught below
+    if command.output().is_err() {
+        eprintln!("Error while using FFMPEG, is it installed?");
+    }
+
+    let audio = fs::read(temp_path).unwrap_or_default();
+    let _ = fs::remove_file(temp_path);
+
+    audio
+}
+
+/// The name of the video file without its extension
+fn video_title(path: &str) -> String {
+    Path::new(path).file_stem()
+        .map_or(path.to_string(), |s| s.to_string_lossy().to_string())
+}
+
+/// Renders all the frames of the reader to the sink, or only the first one with `--single-frame`
+fn render_frames(reader: &mut FrameReader, config: &Config, mut sink: Box<dyn FrameSink>) -> Result<(), ExportError> {
+    // Rendering is done in chunks to use all the cores
+    let chunk_size = if config.single_frame() {
+        1
+    } else {
+        rayon::current_num_threads().max(1)
+    };
+
+    loop {
+        let mut chunk = Vec::new();
+        while chunk.len() < chunk_size {
+            match reader.next_frame() {
+                Some(f) => chunk.push(f),
+                None => break,
+            }
+        }
+        if chunk.is_empty() {
+            break;
+        }
+
+        let grids = chunk.into_par_iter()
+            .map(|(index, timestamp, image)| (index, timestamp, image.cells(config)))
+            .collect::<Vec<(usize, Duration, CellGrid)>>();
+
+        // The sinks need the frames in order
+        for (index, timestamp, grid) in grids {
+            sink.push(index, timestamp, grid)?;
+        }
+
+        if config.single_frame() {
+            break;
+        }
+    }
+
+    sink.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::default_config_streamed;
+    use crate::text_video::TextVideo;
+
+    #[test]
+    fn formats_are_found_from_extensions() {
+        assert_eq!(ExportFormat::TextVideo, ExportFormat::from_path("video.ctv").unwrap());
+        assert_eq!(ExportFormat::TextVideo, ExportFormat::from_path("some/dir/VIDEO.CTV").unwrap());
+        assert_eq!(ExportFormat::Asciicast, ExportFormat::from_path("video.cast").unwrap());
+        assert_eq!(ExportFormat::Gif, ExportFormat::from_path("video.gif").unwrap());
+        assert_eq!(ExportFormat::Mp4, ExportFormat::from_path("video.mp4").unwrap());
+        assert_eq!(ExportFormat::Html, ExportFormat::from_path("video.html").unwrap());
+        assert_eq!(ExportFormat::Ansi, ExportFormat::from_path("video.ans").unwrap());
+        assert!(ExportFormat::from_path("video.txt").is_err());
+        assert!(ExportFormat::from_path("video").is_err());
+    }
+
+    #[test]
+    fn exporting_text_video() {
+        let config = default_config_streamed();
+        let output_path = std::env::temp_dir().join("cli-player-test-export.ctv");
+        let output_path = output_path.to_str().unwrap();
+
+        export_video("test-assets/video.mp4", &config, output_path).unwrap();
+
+        let text_video = TextVideo::open(output_path).unwrap();
+        assert_eq!(15, text_video.header().fps());
+        assert_eq!(config.render_settings(), text_video.header().settings());
+
+        // The video is 8.33s long
+        let mut frames = text_video.into_frames();
+        assert!((120..=130).contains(&frames.len()));
+        let (_, first_frame) = frames.next_frame().unwrap();
+        assert_eq!(50, first_frame.width());
+
+        fs::remove_file(output_path).unwrap();
+    }
+
+    #[test]
+    fn exporting_asciicast() {
+        let config = default_config_streamed();
+        let output_path = std::env::temp_dir().join("cli-player-test-export.cast");
+        let output_path = output_path.to_str().unwrap();
+
+        export_video("test-assets/video.mp4", &config, output_path).unwrap();
+
+        // A header, the frames and the end event
+        let content = fs::read_to_string(output_path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert!(lines[0].starts_with(r#"{"version": 2, "width": 50, "#));
+        assert!((122..=132).contains(&lines.len()));
+        assert!(lines[2].starts_with(&format!("[{:.6}, \"o\", ", 1.0 / 15.0)));
+
+        fs::remove_file(output_path).unwrap();
+    }
+}
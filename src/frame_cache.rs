@@ -0,0 +1,421 @@
+//! Cache of rendered frames on disk, so that playing the same video with the same settings
+//! does not need to decode and render it again
+//!
+//! Each cached video is a frame file named after a hash of the video's content and of the rendering
+//! settings, its header describes the video. The cache is in the user's cache directory, the least
+//! recently used videos are removed when it gets bigger than `MAX_CACHE_SIZE`.
+
+use std::env;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::cells::CellGrid;
+use crate::config::Config;
+use crate::frame_file::{self, FrameFileReader, FrameFileWriter};
+use crate::workspace;
+
+const CACHE_EXTENSION: &str = "frames";
+const MAGIC: &[u8] = b"CLI-PLAYER-FRAMES-2";
+/// Size in bytes the cache is kept under
+const MAX_CACHE_SIZE: u64 = 2_000_000_000;
+/// Bytes hashed at the start and at the end of the video to recognize it
+const HASHED_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum FrameCacheError {
+    IoError(io::Error),
+    InvalidFileError(PathBuf),
+}
+
+impl Display for FrameCacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameCacheError::IoError(e) => write!(f, "{}", e),
+            FrameCacheError::InvalidFileError(p) => write!(
+                f,
+                "{} is not a valid cache file",
+                p.display(),
+            ),
+        }
+    }
+}
+
+impl Error for FrameCacheError {}
+
+impl From<io::Error> for FrameCacheError {
+    fn from(e: io::Error) -> Self {
+        FrameCacheError::IoError(e)
+    }
+}
+
+/// Hashes the content of the video and the settings that change the rendered frames.
+/// Only the size and the first and last `HASHED_SIZE` bytes of the video are read, so that big
+/// videos are recognized quickly, even after being copied or moved.
+pub fn cache_key(path: &str, config: &Config) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    let mut video_hash = Fnv1a::new();
+    video_hash.write(&size.to_le_bytes());
+    let mut bytes = Vec::new();
+    (&mut file).take(HASHED_SIZE).read_to_end(&mut bytes)?;
+    video_hash.write(&bytes);
+    if size > HASHED_SIZE {
+        // The end does not overlap the start that was already hashed
+        file.seek(SeekFrom::Start(HASHED_SIZE.max(size - HASHED_SIZE)))?;
+        bytes.clear();
+        file.take(HASHED_SIZE).read_to_end(&mut bytes)?;
+        video_hash.write(&bytes);
+    }
+
+    let mut settings_hash = Fnv1a::new();
+    settings_hash.write(config.render_settings().as_bytes());
+
+    Ok(format!("{:016x}{:016x}", video_hash.finish(), settings_hash.finish()))
+}
+
+/// The directory of the cache in the user's cache directory, or in the temp folder when there is none
+pub fn cache_directory() -> PathBuf {
+    let user_cache = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|h| PathBuf::from(h).join("Library").join("Caches"))
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+    };
+
+    user_cache.unwrap_or_else(env::temp_dir).join("cli-player").join("frames")
+}
+
+/// A directory of cached videos
+#[derive(Clone, Debug)]
+pub struct FrameCache {
+    directory: PathBuf,
+}
+
+impl FrameCache {
+    pub fn new(directory: PathBuf) -> FrameCache {
+        FrameCache {
+            directory,
+        }
+    }
+
+    /// The cache in the user's cache directory
+    pub fn user_cache() -> FrameCache {
+        FrameCache::new(cache_directory())
+    }
+}
+
+/// Writes the frames to the cache as they are rendered, the file is only added to the cache
+/// once `finish` is called. If the writer is dropped before that, or the program exits, the file is removed.
+pub struct CacheWriter {
+    frames: Option<FrameFileWriter>,
+    temp_path: PathBuf,
+    path: PathBuf,
+    cache: FrameCache, // Where older videos are removed from once the video is added
+}
+
+impl CacheWriter {
+    /// The native frame rate of the video is kept so that it does not need to be opened to play the cache
+    pub fn create(cache: &FrameCache, key: &str, source: &str, native_fps: f64, config: &Config) -> Result<CacheWriter, FrameCacheError> {
+        fs::create_dir_all(&cache.directory)?;
+
+        let path = cache.cache_path(key);
+        let temp_path = path.with_extension("part");
+
+        let mut header = MAGIC.to_vec();
+        frame_file::write_string(&mut header, source);
+        frame_file::write_string(&mut header, &config.render_settings());
+        header.extend(native_fps.to_le_bytes());
+        let frames = FrameFileWriter::create(File::create(&temp_path)?, &header)?;
+        // The writer is not dropped when the program exits
+        workspace::remove_on_cleanup(&temp_path);
+
+        Ok(CacheWriter {
+            frames: Some(frames),
+            temp_path,
+            path,
+            cache: cache.clone(),
+        })
+    }
+}
+
+impl CacheWriter {
+    /// Adds the frame shown at the index and timestamp in the video after the others
+    pub fn push(&mut self, index: usize, timestamp: Duration, grid: CellGrid) -> io::Result<()> {
+        match &mut self.frames {
+            Some(f) => f.push(index, timestamp, grid),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the footer and adds the file to the cache, older videos are removed if the cache is too big
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(frames) = self.frames.take() {
+            frames.finish()?;
+            fs::rename(&self.temp_path, &self.path)?;
+            workspace::keep_on_cleanup(&self.temp_path);
+        }
+
+        // The video was cached even if the others can't be removed
+        let _ = self.cache.evict(MAX_CACHE_SIZE);
+        Ok(())
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        // The file was not finished
+        if self.frames.is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+            workspace::keep_on_cleanup(&self.temp_path);
+        }
+    }
+}
+
+impl FrameCache {
+    fn cache_path(&self, key: &str) -> PathBuf {
+        self.directory.join(format!("{key}.{CACHE_EXTENSION}"))
+    }
+
+    /// Opens the frames of a cached video with the native frame rate of the video,
+    /// gives `None` if it is not in the cache
+    pub fn open(&self, key: &str) -> Option<(FrameFileReader, f64)> {
+        let path = self.cache_path(key);
+        let mut file = BufReader::new(File::open(&path).ok()?);
+        let header = read_header(&mut file, &path).ok()?;
+        let frames = FrameFileReader::read(file.into_inner(), header.length).ok()?;
+
+        // Keeps track of when the cache was last used for pruning
+        let _ = File::options().append(true).open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()));
+
+        Some((frames, header.native_fps))
+    }
+
+    /// Lists the videos in the cache, the most recently used first
+    pub fn list_entries(&self) -> Result<Vec<CacheEntry>, FrameCacheError> {
+        let directory = match fs::read_dir(&self.directory) {
+            Ok(d) => d,
+            // Nothing was cached yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(FrameCacheError::IoError(e)),
+        };
+
+        let mut entries = Vec::new();
+        for entry in directory {
+            let path = entry?.path();
+            if path.extension().map_or(true, |e| e != CACHE_EXTENSION) {
+                continue;
+            }
+
+            let metadata = fs::metadata(&path)?;
+            let (source, settings) = match read_header(&mut BufReader::new(File::open(&path)?), &path) {
+                Ok(h) => (h.source, h.settings),
+                Err(_) => ("(invalid cache file)".to_string(), String::new()),
+            };
+
+            entries.push(CacheEntry {
+                path,
+                source,
+                settings,
+                size: metadata.len(),
+                last_used: metadata.modified()?,
+            });
+        }
+
+        entries.sort_by_key(|e| std::cmp::Reverse(e.last_used));
+        Ok(entries)
+    }
+
+    /// Removes the videos that were not used for longer than `unused_for`, or all of them.
+    /// Gives the number of bytes freed.
+    pub fn prune(&self, unused_for: Option<Duration>) -> Result<u64, FrameCacheError> {
+        let mut freed = 0;
+        for entry in self.list_entries()? {
+            let unused_time = entry.last_used.elapsed().unwrap_or(Duration::ZERO);
+            if unused_for.map_or(true, |d| unused_time >= d) {
+                fs::remove_file(&entry.path)?;
+                freed += entry.size;
+            }
+        }
+
+        Ok(freed)
+    }
+
+    /// Removes the least recently used videos until the cache takes at most `max_size` bytes.
+    /// Gives the number of bytes freed.
+    fn evict(&self, max_size: u64) -> Result<u64, FrameCacheError> {
+        let entries = self.list_entries()?;
+        let mut size: u64 = entries.iter().map(|e| e.size).sum();
+
+        let mut freed = 0;
+        // The least recently used are last
+        for entry in entries.iter().rev() {
+            if size <= max_size {
+                break;
+            }
+            fs::remove_file(&entry.path)?;
+            size -= entry.size;
+            freed += entry.size;
+        }
+
+        Ok(freed)
+    }
+}
+
+struct CacheHeader {
+    source: String,
+    settings: String,
+    native_fps: f64,
+    length: u64,
+}
+
+fn read_header(file: &mut BufReader<File>, path: &Path) -> Result<CacheHeader, FrameCacheError> {
+    let invalid = || FrameCacheError::InvalidFileError(path.to_path_buf());
+
+    let mut magic = vec![0; MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid());
+    }
+
+    let (source, source_length) = frame_file::read_string(file).ok_or_else(invalid)?;
+    let (settings, settings_length) = frame_file::read_string(file).ok_or_else(invalid)?;
+    let mut native_fps = [0; 8];
+    file.read_exact(&mut native_fps)?;
+
+    Ok(CacheHeader {
+        source,
+        settings,
+        native_fps: f64::from_le_bytes(native_fps),
+        length: (MAGIC.len() + source_length + settings_length + native_fps.len()) as u64,
+    })
+}
+
+/// A video in the cache
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub source: String,
+    pub settings: String,
+    pub size: u64,
+    pub last_used: SystemTime,
+}
+
+/// Hash function that stays the same between runs and versions of Rust, unlike `DefaultHasher`
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{default_config_with_width, test_grid};
+
+    /// An empty cache in the temp folder, so that the tests do not touch the user's cache
+    fn test_cache(name: &str) -> FrameCache {
+        let directory = env::temp_dir().join(format!("cli-player-test-cache-{name}"));
+        let _ = fs::remove_dir_all(&directory);
+        FrameCache::new(directory)
+    }
+
+    #[test]
+    fn cached_frames_are_read_back() {
+        let cache = test_cache("read-back");
+        let key = "test-cached-frames";
+
+        let mut writer = CacheWriter::create(&cache, key, "test-assets/video.mp4", 30.0, &default_config_with_width(4)).unwrap();
+        writer.push(3, Duration::from_millis(100), test_grid(0)).unwrap();
+        writer.push(5, Duration::from_millis(166), test_grid(1)).unwrap();
+        writer.finish().unwrap();
+
+        let (mut frames, native_fps) = cache.open(key).unwrap();
+        assert_eq!(30.0, native_fps);
+        assert_eq!(Some((3, test_grid(0))), frames.next_frame());
+        assert_eq!(Some((5, test_grid(1))), frames.next_frame());
+        assert_eq!(None, frames.next_frame());
+        assert_eq!(1, cache.list_entries().unwrap().len());
+
+        fs::remove_dir_all(&cache.directory).unwrap();
+    }
+
+    #[test]
+    fn unfinished_cache_is_not_used() {
+        let cache = test_cache("unfinished");
+        let key = "test-unfinished-cache";
+
+        let mut writer = CacheWriter::create(&cache, key, "test-assets/video.mp4", 30.0, &default_config_with_width(4)).unwrap();
+        writer.push(0, Duration::ZERO, test_grid(0)).unwrap();
+        drop(writer);
+
+        assert!(cache.open(key).is_none());
+        assert!(!cache.cache_path(key).with_extension("part").exists());
+
+        fs::remove_dir_all(&cache.directory).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_videos_are_evicted() {
+        let cache = test_cache("evict");
+        for key in ["old", "new"] {
+            let mut writer = CacheWriter::create(&cache, key, "test-assets/video.mp4", 30.0, &default_config_with_width(4)).unwrap();
+            writer.push(0, Duration::ZERO, test_grid(0)).unwrap();
+            writer.finish().unwrap();
+        }
+        let old = File::options().append(true).open(cache.cache_path("old")).unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+
+        let size = fs::metadata(cache.cache_path("new")).unwrap().len();
+        assert!(cache.evict(size).unwrap() > 0);
+        assert!(cache.open("old").is_none());
+        assert!(cache.open("new").is_some());
+
+        fs::remove_dir_all(&cache.directory).unwrap();
+    }
+
+    #[test]
+    fn key_follows_the_content_of_the_video() {
+        let path = env::temp_dir().join("cli-player-test-cache-key.mp4");
+        let copy = env::temp_dir().join("cli-player-test-cache-key-copy.mp4");
+        let key = |path: &Path| cache_key(path.to_str().unwrap(), &default_config_with_width(4)).unwrap();
+
+        // Bigger than the hashed start and end
+        let mut video = vec![0; 3 * HASHED_SIZE as usize];
+        fs::write(&path, &video).unwrap();
+        fs::copy(&path, &copy).unwrap();
+        assert_eq!(key(&path), key(&copy));
+
+        // Same size, only the end changes
+        *video.last_mut().unwrap() = 1;
+        fs::write(&copy, &video).unwrap();
+        assert_ne!(key(&path), key(&copy));
+
+        fs::write(&copy, b"short video").unwrap();
+        assert_ne!(key(&path), key(&copy));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&copy).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+//! Files of encoded frames that are read back one at a time, used by the cache and by text videos
+//!
+//! The file starts with a header that is up to the user of the file, followed by the encoded
+//! frames and a footer with the palette and where each frame is. The footer is found with its
+//! offset, stored in the last 8 bytes of the file.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+use colored::Color;
+
+use crate::cells::CellGrid;
+use crate::frame_store::{self, EncodedFrame, FrameEncoder};
+
+/// Writes the frames after the header, the file is only readable once `finish` is called
+pub struct FrameFileWriter {
+    file: BufWriter<File>,
+    encoder: FrameEncoder,
+    frames: Vec<FrameInfo>,
+    offset: u64,
+}
+
+impl FrameFileWriter {
+    pub fn create(file: File, header: &[u8]) -> io::Result<FrameFileWriter> {
+        let mut file = BufWriter::new(file);
+        file.write_all(header)?;
+
+        Ok(FrameFileWriter {
+            file,
+            encoder: FrameEncoder::new(true),
+            frames: Vec::new(),
+            offset: header.len() as u64,
+        })
+    }
+}
+
+impl FrameFileWriter {
+    /// Adds the frame shown at the index and timestamp in the video after the others
+    pub fn push(&mut self, index: usize, timestamp: Duration, grid: CellGrid) -> io::Result<()> {
+        let frame = self.encoder.encode(grid);
+        self.file.write_all(&frame.bytes)?;
+
+        self.frames.push(FrameInfo {
+            offset: self.offset,
+            length: frame.bytes.len() as u64,
+            index,
+            timestamp,
+            keyframe: frame.keyframe,
+        });
+        self.offset += frame.bytes.len() as u64;
+
+        Ok(())
+    }
+
+    /// Writes the footer and flushes the file
+    pub fn finish(mut self) -> io::Result<()> {
+        let mut footer = Vec::new();
+        frame_store::write_varint(&mut footer, self.encoder.palette().len() as u64);
+        for color in self.encoder.palette() {
+            frame_store::write_varint(&mut footer, frame_store::color_key(*color) as u64);
+        }
+
+        frame_store::write_varint(&mut footer, self.frames.len() as u64);
+        for frame in &self.frames {
+            frame_store::write_varint(&mut footer, frame.offset);
+            frame_store::write_varint(&mut footer, frame.index as u64);
+            frame_store::write_varint(&mut footer, frame.timestamp.as_micros() as u64);
+            footer.push(frame.keyframe as u8);
+        }
+
+        // The footer is found from the end of the file
+        footer.extend(self.offset.to_le_bytes());
+
+        self.file.write_all(&footer)?;
+        self.file.flush()
+    }
+}
+
+struct FrameInfo {
+    offset: u64,
+    length: u64,
+    index: usize, // Index in the video
+    timestamp: Duration,
+    keyframe: bool,
+}
+
+/// Reads the frames of a file one at a time from the disk
+pub struct FrameFileReader {
+    file: BufReader<File>,
+    palette: Vec<Option<Color>>,
+    frames: Vec<FrameInfo>,
+    current: Option<CellGrid>,
+    next: usize,
+}
+
+impl FrameFileReader {
+    /// Reads the footer of the file, `header_length` is the number of bytes before the first frame
+    pub fn read(file: File, header_length: u64) -> io::Result<FrameFileReader> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid frame file");
+        let mut file = BufReader::new(file);
+
+        let file_length = file.seek(SeekFrom::End(0))?;
+        if file_length < 8 {
+            return Err(invalid());
+        }
+        file.seek(SeekFrom::End(-8))?;
+        let mut footer_offset = [0; 8];
+        file.read_exact(&mut footer_offset)?;
+        let footer_offset = u64::from_le_bytes(footer_offset);
+        if footer_offset < header_length || footer_offset > file_length - 8 {
+            return Err(invalid());
+        }
+
+        file.seek(SeekFrom::Start(footer_offset))?;
+        let mut footer = Vec::new();
+        file.read_to_end(&mut footer)?;
+        let mut reader = footer.into_iter();
+
+        let palette_length = frame_store::read_varint(&mut reader);
+        let palette = (0..palette_length)
+            .map(|_| frame_store::color_from_key(frame_store::read_varint(&mut reader) as u32))
+            .collect();
+
+        let nb_frames = frame_store::read_varint(&mut reader) as usize;
+        let mut frames: Vec<FrameInfo> = Vec::new();
+        for _ in 0..nb_frames {
+            let offset = frame_store::read_varint(&mut reader);
+            let index = frame_store::read_varint(&mut reader) as usize;
+            let timestamp = Duration::from_micros(frame_store::read_varint(&mut reader));
+            let keyframe = reader.next().ok_or_else(invalid)? == 1;
+            frames.push(FrameInfo { offset, length: 0, index, timestamp, keyframe });
+        }
+
+        // Each frame ends where the next one starts
+        let mut end = footer_offset;
+        for frame in frames.iter_mut().rev() {
+            if frame.offset > end || frame.offset < header_length {
+                return Err(invalid());
+            }
+            frame.length = end - frame.offset;
+            end = frame.offset;
+        }
+
+        Ok(FrameFileReader {
+            file,
+            palette,
+            frames,
+            current: None,
+            next: 0,
+        })
+    }
+}
+
+impl FrameFileReader {
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Index in the video of the first frame
+    pub fn first_index(&self) -> Option<usize> {
+        self.frames.first().map(|f| f.index)
+    }
+
+    /// Index in the video of the frame that `next_frame` gives
+    pub fn next_index(&self) -> Option<usize> {
+        self.frames.get(self.next).map(|f| f.index)
+    }
+
+    /// Timestamp in the video of the frame that `next_frame` gives
+    pub fn next_timestamp(&self) -> Option<Duration> {
+        self.frames.get(self.next).map(|f| f.timestamp)
+    }
+
+    /// Gives the next frame with its index in the video
+    pub fn next_frame(&mut self) -> Option<(usize, CellGrid)> {
+        let info = self.frames.get(self.next)?;
+
+        let mut bytes = vec![0; info.length as usize];
+        self.file.seek(SeekFrom::Start(info.offset)).ok()?;
+        self.file.read_exact(&mut bytes).ok()?;
+        let frame = EncodedFrame { bytes, keyframe: info.keyframe };
+
+        let grid = frame_store::decode_frame(&frame, &self.palette, true, self.current.take());
+        self.current = Some(grid.clone());
+        let index = info.index;
+        self.next += 1;
+
+        Some((index, grid))
+    }
+
+    /// Makes `next_frame` give the first frame at or after the index in the video
+    pub fn seek(&mut self, index: usize) {
+        let position = self.frames.partition_point(|f| f.index < index);
+        self.seek_to(position);
+    }
+
+    /// Makes `next_frame` give the first frame shown at or after the timestamp
+    pub fn seek_timestamp(&mut self, timestamp: Duration) {
+        let position = self.frames.partition_point(|f| f.timestamp < timestamp);
+        self.seek_to(position);
+    }
+
+    /// Decodes the frames from the keyframe before the position up to it
+    fn seek_to(&mut self, position: usize) {
+        let keyframe = self.frames[..position].iter()
+            .rposition(|f| f.keyframe)
+            .unwrap_or(position);
+
+        self.current = None;
+        self.next = keyframe;
+        while self.next < position {
+            if self.next_frame().is_none() {
+                break;
+            }
+        }
+    }
+}
+
+pub fn write_string(bytes: &mut Vec<u8>, string: &str) {
+    bytes.extend((string.len() as u32).to_le_bytes());
+    bytes.extend(string.as_bytes());
+}
+
+/// Reads a string written by `write_string`, gives the number of bytes read along with it
+pub fn read_string(file: &mut impl Read) -> Option<(String, usize)> {
+    let mut length = [0; 4];
+    file.read_exact(&mut length).ok()?;
+    let length = u32::from_le_bytes(length) as usize;
+
+    let mut string = vec![0; length];
+    file.read_exact(&mut string).ok()?;
+
+    Some((String::from_utf8(string).ok()?, 4 + length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::test_fixtures::test_grid;
+
+    #[test]
+    fn frames_are_read_back() {
+        let path = std::env::temp_dir().join("cli-player-test-frame-file");
+        let header = b"some header";
+
+        let mut writer = FrameFileWriter::create(File::create(&path).unwrap(), header).unwrap();
+        for i in 0..100 {
+            writer.push(i * 2 + 10, Duration::from_millis(i as u64 * 40), test_grid(i)).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = FrameFileReader::read(File::open(&path).unwrap(), header.len() as u64).unwrap();
+        assert_eq!(100, reader.len());
+        assert_eq!(Some(10), reader.first_index());
+        for i in 0..100 {
+            assert_eq!(Some(Duration::from_millis(i as u64 * 40)), reader.next_timestamp());
+            assert_eq!(Some((i * 2 + 10, test_grid(i))), reader.next_frame());
+        }
+        assert_eq!(None, reader.next_frame());
+
+        // Goes to the first frame at or after the index
+        reader.seek(151);
+        assert_eq!(Some((152, test_grid(71))), reader.next_frame());
+        reader.seek(0);
+        assert_eq!(Some((10, test_grid(0))), reader.next_frame());
+        reader.seek_timestamp(Duration::from_millis(1001));
+        assert_eq!(Some((62, test_grid(26))), reader.next_frame());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unfinished_files_are_rejected() {
+        let path = std::env::temp_dir().join("cli-player-test-unfinished-frame-file");
+
+        let mut writer = FrameFileWriter::create(File::create(&path).unwrap(), b"").unwrap();
+        writer.push(0, Duration::ZERO, test_grid(1)).unwrap();
+        drop(writer);
+
+        assert!(FrameFileReader::read(File::open(&path).unwrap(), 0).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
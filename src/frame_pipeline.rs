@@ -0,0 +1,273 @@
+//! Decodes and renders frames ahead of the playback in a background thread
+
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use crate::cells::CellGrid;
+use crate::config::Config;
+use crate::frame_cache::CacheWriter;
+use crate::frames::FrameReader;
+use crate::image::TextImage;
+use crate::quality::{QualityState, QUALITY_LEVELS};
+
+/// Number of rendered frames that can wait to be played
+const PIPELINE_DEPTH: usize = 32;
+
+enum PipelineMessage {
+    Frame {
+        generation: u64,
+        timestamp: Duration,
+        image: TextImage,
+    },
+    End {
+        generation: u64,
+    },
+    /// The reader moved to the position of the seek, it comes before the frames of the generation
+    Seeked {
+        generation: u64,
+        result: opencv::Result<()>,
+    },
+}
+
+enum PipelineControl {
+    Seek {
+        generation: u64,
+        position: Duration,
+    },
+    /// The frames are played at this speed
+    SetSpeed(f64),
+    /// The next frames are rendered with this config, before the quality level is applied
+    SetConfig(Box<Config>),
+}
+
+/// Receives the frames rendered by the background thread.
+///
+/// Every seek starts a new generation, the frames of older generations that were still
+/// in the buffer are thrown away when received.
+pub struct FramePipeline {
+    receiver: Receiver<PipelineMessage>,
+    control: Sender<PipelineControl>,
+    generation: u64,
+    ended: bool,
+}
+
+impl FramePipeline {
+    /// Starts rendering the frames of the reader, the thread stops when the pipeline is dropped.
+    /// The frames are also written to the cache, unless there is a seek or the quality is lowered before the end.
+    /// With a quality state the frames are rendered at its level and the render time is saved in it.
+    pub fn spawn(reader: FrameReader, cache_writer: Option<CacheWriter>, config: &Config, quality: Option<Arc<QualityState>>) -> FramePipeline {
+        let (sender, receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let (control, control_receiver) = mpsc::channel();
+
+        let producer = FrameProducer {
+            reader,
+            cache_writer,
+            config: config.clone(),
+            base_config: config.clone(),
+            quality,
+            level: 0,
+            speed: 1.0,
+        };
+        thread::spawn(move || producer.run(sender, control_receiver));
+
+        FramePipeline {
+            receiver,
+            control,
+            generation: 0,
+            ended: false,
+        }
+    }
+}
+
+impl FramePipeline {
+    /// Waits for the next rendered frame, gives its presentation timestamp in the video along with it
+    pub fn next_frame(&mut self) -> Option<(Duration, TextImage)> {
+        if self.ended {
+            return None;
+        }
+
+        loop {
+            match self.receiver.recv() {
+                Ok(PipelineMessage::Frame { generation, timestamp, image }) if generation == self.generation => {
+                    return Some((timestamp, image));
+                },
+                Ok(PipelineMessage::End { generation }) if generation == self.generation => {
+                    self.ended = true;
+                    return None;
+                },
+                // Comes from before a seek
+                Ok(_) => (),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Renders fewer frames of the video when it is played faster so that as many frames are
+    /// shown each second, and more when it is slower if the video has them
+    pub fn set_speed(&mut self, speed: f64) {
+        let _ = self.control.send(PipelineControl::SetSpeed(speed));
+    }
+
+    /// Renders the next frames with the config, the frames already rendered are kept until the next seek
+    pub fn set_config(&mut self, config: &Config) {
+        let _ = self.control.send(PipelineControl::SetConfig(Box::new(config.clone())));
+    }
+
+    /// Waits for the reader to move to the position, the frames rendered before are thrown away
+    pub fn seek(&mut self, position: Duration) -> opencv::Result<()> {
+        self.generation += 1;
+        self.ended = false;
+
+        let _ = self.control.send(PipelineControl::Seek {
+            generation: self.generation,
+            position,
+        });
+
+        loop {
+            match self.receiver.recv() {
+                Ok(PipelineMessage::Seeked { generation, result }) if generation == self.generation => return result,
+                // Comes from before the seek
+                Ok(_) => (),
+                // The thread stopped, there are no frames to seek in
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+}
+
+struct FrameProducer {
+    reader: FrameReader,
+    cache_writer: Option<CacheWriter>,
+    config: Config, // The config of the quality level
+    base_config: Config,
+    quality: Option<Arc<QualityState>>,
+    level: usize,
+    speed: f64,
+}
+
+impl FrameProducer {
+    fn run(mut self, sender: SyncSender<PipelineMessage>, control: Receiver<PipelineControl>) {
+        // Rendering is done in chunks to use all the cores
+        let chunk_size = rayon::current_num_threads().max(1);
+        let mut generation = 0;
+        let mut ended = false;
+
+        loop {
+            // Once the end is reached there is nothing to do until the next seek
+            let message = if ended {
+                match control.recv() {
+                    Ok(m) => Some(m),
+                    Err(_) => return,
+                }
+            } else {
+                match control.try_recv() {
+                    Ok(m) => Some(m),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            };
+
+            match message {
+                Some(PipelineControl::Seek { generation: new_generation, position }) => {
+                    generation = new_generation;
+                    ended = false;
+                    // The cache needs all the frames in order
+                    self.cache_writer = None;
+                    let result = self.reader.seek(position);
+                    if sender.send(PipelineMessage::Seeked { generation, result }).is_err() {
+                        return;
+                    }
+                    continue;
+                },
+                Some(PipelineControl::SetSpeed(speed)) => {
+                    self.speed = speed;
+                    self.set_fps_factor();
+                    continue;
+                },
+                Some(PipelineControl::SetConfig(config)) => {
+                    self.base_config = *config;
+                    self.set_level(self.level);
+                    continue;
+                },
+                None => (),
+            }
+
+            if let Some(level) = self.quality.as_ref().map(|q| q.level()) {
+                if level != self.level {
+                    self.set_level(level);
+                }
+            }
+
+            let render_start = Instant::now();
+            let mut chunk = Vec::new();
+            while chunk.len() < chunk_size {
+                match self.reader.next_frame() {
+                    Some(f) => chunk.push(f),
+                    None => {
+                        ended = true;
+                        break;
+                    },
+                }
+            }
+
+            let config = &self.config;
+            let rendered = chunk.into_par_iter()
+                .map(|(index, timestamp, image)| {
+                    let grid = image.cells(config);
+                    let text_image = TextImage::build_from_cells(&grid);
+                    (index, timestamp, grid, text_image)
+                })
+                .collect::<Vec<(usize, Duration, CellGrid, TextImage)>>();
+            if let (Some(quality), false) = (&self.quality, rendered.is_empty()) {
+                quality.record_render_time(render_start.elapsed() / rendered.len() as u32);
+            }
+
+            // Sending blocks while the buffer is full, it fails when the pipeline was dropped
+            for (index, timestamp, grid, image) in rendered {
+                // The video is played even if it can't be cached
+                if let Some(writer) = &mut self.cache_writer {
+                    if writer.push(index, timestamp, grid).is_err() {
+                        self.cache_writer = None;
+                    }
+                }
+
+                if sender.send(PipelineMessage::Frame { generation, timestamp, image }).is_err() {
+                    return;
+                }
+            }
+
+            if ended {
+                if let Some(writer) = self.cache_writer.take() {
+                    let _ = writer.finish();
+                }
+
+                if sender.send(PipelineMessage::End { generation }).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Renders the next frames at the quality level
+    fn set_level(&mut self, level: usize) {
+        self.config = QUALITY_LEVELS[level].apply(&self.base_config);
+        self.level = level;
+        self.set_fps_factor();
+        // The cache would have frames of different qualities
+        self.cache_writer = None;
+    }
+
+    /// Keeps the share of the frames of the quality level and speed
+    fn set_fps_factor(&mut self) {
+        let factor = QUALITY_LEVELS[self.level].fps_factor() / self.speed;
+        self.reader.set_fps_factor(factor);
+        // The cache needs all the frames of the frame rate
+        if factor != 1.0 {
+            self.cache_writer = None;
+        }
+    }
+}
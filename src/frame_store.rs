@@ -0,0 +1,406 @@
+//! Compact in-memory storage of rendered frames
+//!
+//! Frames are stored as cell grids where colors are indices in a palette shared by all the frames.
+//! Most frames only store the cells that changed since the previous frame, with a full frame
+//! every `KEYFRAME_INTERVAL` frames so that seeking does not need to decode the whole video.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use colored::Color;
+
+use crate::cells::{Cell, CellGrid};
+
+/// Maximum number of frames between two full frames
+const KEYFRAME_INTERVAL: usize = 64;
+const ZSTD_LEVEL: i32 = 3;
+
+pub struct EncodedFrame {
+    pub bytes: Vec<u8>,
+    pub keyframe: bool, // Keyframes can be decoded without the previous frame
+}
+
+/// Encodes frames relative to the previous one, the frames need to be decoded
+/// in the same order with the palette of the encoder
+pub struct FrameEncoder {
+    palette: Vec<Option<Color>>,
+    palette_indices: HashMap<u32, u32>,
+    compress: bool,
+    previous: Option<CellGrid>,
+    nb_encoded: usize,
+}
+
+impl FrameEncoder {
+    /// Creates an encoder, `compress` additionally compresses the frames with zstd
+    pub fn new(compress: bool) -> FrameEncoder {
+        FrameEncoder {
+            palette: Vec::new(),
+            palette_indices: HashMap::new(),
+            compress,
+            previous: None,
+            nb_encoded: 0,
+        }
+    }
+}
+
+impl FrameEncoder {
+    pub fn encode(&mut self, grid: CellGrid) -> EncodedFrame {
+        let previous = self.previous.take();
+        let keyframe = match &previous {
+            Some(p) => {
+                self.nb_encoded % KEYFRAME_INTERVAL == 0
+                    || p.width() != grid.width()
+                    || p.height() != grid.height()
+            },
+            None => true,
+        };
+
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, grid.width() as u64);
+        write_varint(&mut bytes, grid.height() as u64);
+
+        if keyframe {
+            for cell in grid.cells() {
+                self.write_cell(&mut bytes, cell);
+            }
+        } else {
+            let previous = previous.expect("Frames that are not keyframes have a previous frame");
+            self.write_changes(&mut bytes, previous.cells(), grid.cells());
+        }
+
+        if self.compress {
+            bytes = zstd::bulk::compress(&bytes, ZSTD_LEVEL)
+                .expect("Compressing in memory should not fail");
+        }
+
+        self.previous = Some(grid);
+        self.nb_encoded += 1;
+        EncodedFrame { bytes, keyframe }
+    }
+
+    /// Colors of the encoded frames
+    pub fn palette(&self) -> &[Option<Color>] {
+        &self.palette
+    }
+
+    /// Writes the cells as runs of unchanged cells followed by runs of changed cells
+    fn write_changes(&mut self, bytes: &mut Vec<u8>, previous: &[Cell], cells: &[Cell]) {
+        let mut i = 0;
+        while i < cells.len() {
+            let unchanged = cells[i..].iter().zip(&previous[i..])
+                .take_while(|(c, p)| c == p)
+                .count();
+            let changed = cells[(i + unchanged)..].iter().zip(&previous[(i + unchanged)..])
+                .take_while(|(c, p)| c != p)
+                .count();
+
+            write_varint(bytes, unchanged as u64);
+            write_varint(bytes, changed as u64);
+            for cell in &cells[(i + unchanged)..(i + unchanged + changed)] {
+                self.write_cell(bytes, cell);
+            }
+
+            i += unchanged + changed;
+        }
+    }
+
+    fn write_cell(&mut self, bytes: &mut Vec<u8>, cell: &Cell) {
+        let color_index = self.palette_index(cell.color);
+        write_varint(bytes, cell.character as u64);
+        write_varint(bytes, color_index as u64);
+    }
+
+    fn palette_index(&mut self, color: Option<Color>) -> u32 {
+        let key = color_key(color);
+        match self.palette_indices.get(&key) {
+            Some(i) => *i,
+            None => {
+                let index = self.palette.len() as u32;
+                self.palette.push(color);
+                self.palette_indices.insert(key, index);
+                index
+            },
+        }
+    }
+}
+
+/// Decodes a frame made by a `FrameEncoder`, `previous` is needed unless the frame is a keyframe
+pub fn decode_frame(frame: &EncodedFrame, palette: &[Option<Color>], compressed: bool, previous: Option<CellGrid>) -> CellGrid {
+    let bytes = if compressed {
+        Cow::Owned(zstd::stream::decode_all(frame.bytes.as_slice())
+            .unwrap_or_default())
+    } else {
+        Cow::Borrowed(&frame.bytes)
+    };
+    let mut reader = bytes.iter().copied();
+
+    let width = read_varint(&mut reader) as usize;
+    let height = read_varint(&mut reader) as usize;
+
+    let read_cell = |reader: &mut std::iter::Copied<std::slice::Iter<u8>>| {
+        let character = char::from_u32(read_varint(reader) as u32).unwrap_or('�');
+        let color = palette.get(read_varint(reader) as usize).copied().flatten();
+        Cell::new(character, color)
+    };
+
+    let mut grid = match previous {
+        Some(p) if !frame.keyframe && p.width() == width && p.height() == height => p,
+        // Frames that are not keyframes are decoded over the previous frame, if it is missing
+        // (which only happens with corrupted data) the frame is decoded over a blank one
+        _ => CellGrid::new(width, height, vec![Cell::new(' ', None); width * height]),
+    };
+
+    let cells = grid.cells_mut();
+    if frame.keyframe {
+        for cell in cells.iter_mut() {
+            *cell = read_cell(&mut reader);
+        }
+        return grid;
+    }
+
+    let mut i = 0;
+    while i < cells.len() {
+        let unchanged = read_varint(&mut reader) as usize;
+        let changed = read_varint(&mut reader) as usize;
+        if unchanged + changed == 0 {
+            break;
+        }
+
+        i = (i + unchanged).min(cells.len());
+        let end = (i + changed).min(cells.len());
+        for cell in &mut cells[i..end] {
+            *cell = read_cell(&mut reader);
+        }
+        i = end;
+    }
+
+    grid
+}
+
+/// Stores the frames in the order they are played, they can be read back one after the other
+/// with `next_frame` or from anywhere with `seek`
+pub struct FrameStore {
+    frames: Vec<EncodedFrame>,
+    encoder: FrameEncoder,
+    compress: bool,
+    current: Option<CellGrid>,
+    next: usize,
+}
+
+impl FrameStore {
+    /// Creates an empty store, `compress` additionally compresses the frames with zstd
+    pub fn new(compress: bool) -> FrameStore {
+        FrameStore {
+            frames: Vec::new(),
+            encoder: FrameEncoder::new(compress),
+            compress,
+            current: None,
+            next: 0,
+        }
+    }
+}
+
+impl FrameStore {
+    /// Adds a frame after the others
+    pub fn push(&mut self, grid: CellGrid) {
+        let frame = self.encoder.encode(grid);
+        self.frames.push(frame);
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Index of the frame `next_frame` will give
+    pub fn position(&self) -> usize {
+        self.next
+    }
+
+    /// Number of bytes taken by the encoded frames
+    pub fn encoded_size(&self) -> usize {
+        self.frames.iter().map(|f| f.bytes.len()).sum()
+    }
+
+    /// Gives the frame following the last one read
+    pub fn next_frame(&mut self) -> Option<CellGrid> {
+        let previous = self.current.take();
+        let frame = self.frames.get(self.next)?;
+        let grid = decode_frame(frame, self.encoder.palette(), self.compress, previous);
+        self.current = Some(grid.clone());
+        self.next += 1;
+
+        Some(grid)
+    }
+
+    /// Makes `next_frame` give the frame at the index,
+    /// the frames are decoded from the closest keyframe before it
+    pub fn seek(&mut self, index: usize) {
+        let index = index.min(self.frames.len());
+        let keyframe = self.frames[..index].iter()
+            .rposition(|f| f.keyframe)
+            .unwrap_or(index);
+
+        self.current = None;
+        self.next = keyframe;
+        while self.next < index {
+            self.next_frame();
+        }
+    }
+}
+
+/// Gives a different number for each color so that they can be hashed and saved
+pub fn color_key(color: Option<Color>) -> u32 {
+    let basic_color = |i: u32| 0x0100_0000 | i;
+
+    match color {
+        None => 0x0200_0000,
+        Some(Color::TrueColor { r, g, b }) => (r as u32) << 16 | (g as u32) << 8 | b as u32,
+        Some(Color::Black) => basic_color(0),
+        Some(Color::Red) => basic_color(1),
+        Some(Color::Green) => basic_color(2),
+        Some(Color::Yellow) => basic_color(3),
+        Some(Color::Blue) => basic_color(4),
+        Some(Color::Magenta) => basic_color(5),
+        Some(Color::Cyan) => basic_color(6),
+        Some(Color::White) => basic_color(7),
+        Some(Color::BrightBlack) => basic_color(8),
+        Some(Color::BrightRed) => basic_color(9),
+        Some(Color::BrightGreen) => basic_color(10),
+        Some(Color::BrightYellow) => basic_color(11),
+        Some(Color::BrightBlue) => basic_color(12),
+        Some(Color::BrightMagenta) => basic_color(13),
+        Some(Color::BrightCyan) => basic_color(14),
+        Some(Color::BrightWhite) => basic_color(15),
+    }
+}
+
+/// Gives back the color from a number made by `color_key`
+pub fn color_from_key(key: u32) -> Option<Color> {
+    const BASIC_COLORS: [Color; 16] = [
+        Color::Black,
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::White,
+        Color::BrightBlack,
+        Color::BrightRed,
+        Color::BrightGreen,
+        Color::BrightYellow,
+        Color::BrightBlue,
+        Color::BrightMagenta,
+        Color::BrightCyan,
+        Color::BrightWhite,
+    ];
+
+    match key >> 24 {
+        0 => Some(Color::TrueColor { r: (key >> 16) as u8, g: (key >> 8) as u8, b: key as u8 }),
+        1 => BASIC_COLORS.get((key & 0xFF) as usize).copied(),
+        _ => None,
+    }
+}
+
+/// Writes the number in as few bytes as possible (LEB128)
+pub fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Reads a number written by `write_varint`, a missing byte is read as 0
+pub fn read_varint(reader: &mut impl Iterator<Item = u8>) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    for byte in reader.by_ref() {
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::test_grid;
+
+    fn test_store(compress: bool) -> FrameStore {
+        let mut store = FrameStore::new(compress);
+        for i in 0..150 {
+            store.push(test_grid(i));
+        }
+
+        store
+    }
+
+    #[test]
+    fn frames_are_read_back() {
+        for compress in [false, true] {
+            let mut store = test_store(compress);
+
+            for i in 0..150 {
+                assert_eq!(Some(test_grid(i)), store.next_frame());
+            }
+            assert_eq!(None, store.next_frame());
+        }
+    }
+
+    #[test]
+    fn seeking() {
+        let mut store = test_store(false);
+
+        store.seek(100);
+        assert_eq!(Some(test_grid(100)), store.next_frame());
+        store.seek(3);
+        assert_eq!(Some(test_grid(3)), store.next_frame());
+        assert_eq!(Some(test_grid(4)), store.next_frame());
+
+        store.seek(500);
+        assert_eq!(None, store.next_frame());
+    }
+
+    #[test]
+    fn frames_take_less_memory() {
+        let grid_size = 40 * 20 * std::mem::size_of::<Cell>();
+        let store = test_store(false);
+
+        assert!(store.encoded_size() < 150 * grid_size / 10);
+        assert!(test_store(true).encoded_size() < store.encoded_size());
+    }
+
+    #[test]
+    fn color_keys() {
+        let colors = [None, Some(Color::Red), Some(Color::BrightWhite), Some(Color::TrueColor { r: 1, g: 2, b: 3 })];
+        for color in colors {
+            assert_eq!(color, color_from_key(color_key(color)));
+        }
+    }
+
+    #[test]
+    fn varints() {
+        let mut bytes = Vec::new();
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64] {
+            write_varint(&mut bytes, value);
+        }
+
+        let mut reader = bytes.into_iter();
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64] {
+            assert_eq!(value, read_varint(&mut reader));
+        }
+    }
+}
@@ -1,150 +1,506 @@
-use opencv::videoio::{VideoCapture, VideoCaptureTrait, self};
+use std::sync::Arc;
+use std::time::Duration;
+
+use opencv::videoio::{VideoCapture, VideoCaptureTrait, VideoCaptureTraitConst, self};
 use opencv::core::UMat;
 use opencv::videostab::{VideoFileSource, VideoFileSourceTrait};
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 
 use crate::{VideoError, Config};
-use crate::image::{TextImage, Image, ImageAsString};
+use crate::audio_decoder::{self, AudioDecoder, AudioInput};
+use crate::cells::CellGrid;
+use crate::frame_cache::{self, CacheWriter, FrameCache};
+use crate::frame_file::FrameFileReader;
+use crate::frame_pipeline::FramePipeline;
+use crate::frame_store::FrameStore;
+use crate::image::{Image, ImageAsString, TextImage};
+use crate::quality::{QualityController, QualityState};
+use crate::visualizer::{AudioFrames, DEFAULT_VISUALIZER_FPS};
+
+/// The cover art of audio files takes this fraction of the width
+const COVER_WIDTH_DIVISOR: u32 = 3;
 
 pub struct FramesManager {
     frames: Frames,
-    target_fps: u32,
-    error_per_frame: f64,            
-    error: f64,
+    native_fps: f64,
+    target_fps: f64,
+    position: Duration, // Position in the video of the next frame
+    quality: Option<QualityController>,
 }
 
 impl FramesManager {
-    fn new(frames: Frames, fps: u32, mut target_fps: u32) -> FramesManager {
-        if target_fps > fps {
-            target_fps = fps;
-        }
-
-        let error_per_frame = (fps as f64 / target_fps as f64) - 1.0;
-
+    fn new(frames: Frames, native_fps: f64, target_fps: f64, first_index: usize) -> FramesManager {
         FramesManager {
             frames,
+            native_fps,
             target_fps,
-            error_per_frame,
-            error: 0.0
+            position: Duration::from_secs_f64(first_index as f64 / native_fps),
+            quality: None,
         }
     }
 
     pub fn build(path: &str, config: &Config) -> Result<FramesManager, VideoError> {
+        // Playing from the cache does not need to open or decode the video
+        let cache = FrameCache::user_cache();
+        let cache_key = if config.cache() {
+            frame_cache::cache_key(path, config).ok()
+        } else {
+            None
+        };
+        if let Some((cached_frames, native_fps)) = cache_key.as_deref().and_then(|k| cache.open(k)) {
+            let first_index = cached_frames.first_index().unwrap_or(0);
+            let frames = Frames::File { reader: cached_frames, end: None };
+            return Ok(FramesManager::new(frames, native_fps, target_fps(native_fps, config), first_index));
+        }
+
+        let reader = FrameReader::open(path, config)?;
+        let native_fps = reader.native_fps();
+        let target_fps = reader.target_fps();
+        let first_index = reader.first_index();
+
+        // The video is not cached, it will be cached while being rendered
+        let cache_writer = cache_key.and_then(|k| CacheWriter::create(&cache, &k, path, native_fps, config).ok());
+
+        // Only the frames rendered while playing can be rendered at a lower quality
+        let quality = if config.adaptive_quality() && !config.preprocessing() {
+            Some(QualityController::new(target_fps))
+        } else {
+            None
+        };
+
+        let frames = if config.preprocessing() {
+            Frames::build_preprocessed(reader, cache_writer, config)?
+        } else {
+            Frames::build_pipelined(reader, cache_writer, config, quality.as_ref().map(|q| q.state()))
+        };
+
+        let mut frames_manager = FramesManager::new(frames, native_fps, target_fps, first_index);
+        frames_manager.quality = quality;
+
+        Ok(frames_manager) 
+    }
+}
+
+impl FramesManager {
+    /// Plays the frames of a frame file, like a text video, restricted to the range of the config
+    pub fn build_from_frame_file(mut reader: FrameFileReader, native_fps: f64, target_fps: f64, config: &Config) -> FramesManager {
+        reader.seek_timestamp(config.start());
+        let first_index = reader.next_index().unwrap_or(0);
+
+        let frames = Frames::File { reader, end: config.end() };
+        FramesManager::new(frames, native_fps, target_fps, first_index)
+    }
+}
+
+impl FramesManager {
+    /// Plays frames that are already rendered, one after the other at the frame rate
+    pub fn build_from_text_frames(frames: Vec<TextImage>, fps: u32) -> FramesManager {
+        let frames = Frames::Text { frames, position: 0, fps: fps as f64 };
+        FramesManager::new(frames, fps as f64, fps as f64, 0)
+    }
+}
+
+impl FramesManager {
+    /// Shows the audio with the visualizer of the config, under the cover art if there is one
+    pub fn build_from_audio(input: &AudioInput, config: &Config) -> Result<FramesManager, VideoError> {
+        let decoder = match AudioDecoder::open_track(input, config.audio_track()) {
+            Ok(d) => d,
+            Err(e) => return Err(VideoError::AudioDecodeError(e)),
+        };
+        let fps = match config.frame_limit() {
+            0 | u32::MAX => DEFAULT_VISUALIZER_FPS,
+            x => x as f64,
+        };
+
+        let cover = audio_decoder::cover_art(input)
+            .and_then(|c| Image::decode(&c))
+            .map(|image| {
+                let mut cover_config = config.clone();
+                cover_config.set_width((config.width() / COVER_WIDTH_DIVISOR).max(1));
+                image.cells(&cover_config)
+            });
+
+        let frames = AudioFrames::new(decoder, cover, config, fps);
+        let first_index = frames.index();
+        Ok(FramesManager::new(Frames::Visualizer(frames), fps, fps, first_index))
+    }
+}
+
+impl FramesManager {
+    /// Gives the next frame with its presentation timestamp in the video
+    pub fn next_frame(&mut self) -> Option<(Duration, Box<dyn ImageAsString>)> {
+        let (timestamp, frame) = self.frames.next_frame()?;
+        self.position = timestamp + Duration::from_secs_f64(1.0 / self.native_fps);
+
+        Some((timestamp, frame))
+    }
+
+    /// The frame rate the frames are shown at, it is only the average for variable frame rates
+    pub fn fps(&self) -> f64 {
+        self.target_fps
+    }
+
+    /// Moves to the frame shown at the position in the video,
+    /// a position past the end of the video moves to the end
+    pub fn seek(&mut self, position: Duration) -> Result<(), VideoError> {
+        self.frames.seek(position)?;
+        self.position = position;
+
+        Ok(())
+    }
+
+    /// Position in the video of the next frame
+    pub fn position(&self) -> Duration {
+        self.position
+    }
+
+    /// The controller of the rendering quality, if the quality can be lowered
+    pub fn quality_mut(&mut self) -> Option<&mut QualityController> {
+        self.quality.as_mut()
+    }
+
+    /// Renders the frames for this playback speed, as many frames are then shown each second.
+    /// The other frames are already rendered, the player drops or repeats them.
+    pub fn set_speed(&mut self, speed: f64) {
+        if let Frames::Pipelined(pipeline) = &mut self.frames {
+            pipeline.set_speed(speed);
+        }
+    }
+
+    /// Renders the frames from the position with the config, like after a color or pallet change.
+    /// It gives false when the frames were already rendered before playing, they can't change.
+    pub fn set_config(&mut self, config: &Config, position: Duration) -> Result<bool, VideoError> {
+        match &mut self.frames {
+            Frames::Pipelined(pipeline) => pipeline.set_config(config),
+            Frames::Visualizer(frames) => frames.set_config(config),
+            _ => return Ok(false),
+        }
+
+        // The frames rendered with the previous config are dropped
+        self.seek(position)?;
+        Ok(true)
+    }
+}
+
+/// Drops frames to lower the frame rate to the target, going by the timestamps of the frames
+/// so that fractional and variable frame rates keep the right pace
+pub struct FrameDecimator {
+    interval: Option<Duration>, // Time between two kept frames, there is none when all the frames are kept
+    next_deadline: Option<Duration>,
+}
+
+impl FrameDecimator {
+    pub fn new(fps: f64, target_fps: f64) -> FrameDecimator {
+        let interval = if target_fps < fps && target_fps > 0.0 {
+            Some(Duration::from_secs_f64(1.0 / target_fps))
+        } else {
+            None
+        };
+
+        FrameDecimator {
+            interval,
+            next_deadline: None,
+        }
+    }
+
+    /// Whether the frame shown at the timestamp is kept
+    pub fn keeps(&mut self, timestamp: Duration) -> bool {
+        // Timestamps are rounded to the millisecond by some containers
+        const TOLERANCE: Duration = Duration::from_millis(1);
+
+        let Some(interval) = self.interval else {
+            return true;
+        };
+
+        match self.next_deadline {
+            Some(d) if timestamp + TOLERANCE < d => false,
+            Some(d) => {
+                // The deadlines follow each other so that the rounding errors do not add up,
+                // unless there was a gap in the frames
+                let next_deadline = if timestamp >= d + interval { timestamp + interval } else { d + interval };
+                self.next_deadline = Some(next_deadline);
+                true
+            },
+            None => {
+                self.next_deadline = Some(timestamp + interval);
+                true
+            },
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.next_deadline = None;
+    }
+}
+
+/// The frame rate the frames of a video are shown at, lowered to the frame limit
+fn target_fps(native_fps: f64, config: &Config) -> f64 {
+    match config.frame_limit() {
+        0 => native_fps,
+        x => native_fps.min(x as f64),
+    }
+}
+
+/// Reads the frames to show from a video file, starting at the start of the range to play
+pub struct FrameReader {
+    capture: VideoCapture,
+    decimator: FrameDecimator,
+    native_fps: f64,
+    target_fps: f64,
+    first_index: usize, // Index in the video of the first frame to read
+    end: Option<Duration>,
+}
+
+impl FrameReader {
+    pub fn open(path: &str, config: &Config) -> Result<FrameReader, VideoError> {
         let mut source = match VideoFileSource::new(&path, false) {
             Ok(c) => c,
             Err(e) => return Err(VideoError::OpenCvError(e))
         };
-        let fps = match source.fps() {
-            Ok(f) => f as u32,
+        let native_fps = match source.fps() {
+            Ok(f) => f,
             Err(e) => return Err(VideoError::OpenCvError(e)),
         };
 
-        let capture = match VideoCapture::from_file(&path, videoio::CAP_ANY) {
+        let target_fps = target_fps(native_fps, config);
+
+        let mut capture = match VideoCapture::from_file(&path, videoio::CAP_ANY) {
             Ok(c) => c,
             Err(e) => return Err(VideoError::OpenCvError(e))
         };
 
-        let target_fps = match config.frame_limit() {
-            0 => u32::MAX,
-            x => x,
-        };
-
-        let frames = if config.preprocessing() {
-            Frames::build_preprocessed(capture, config)?
-        } else {
-            Frames::build_streamed(capture)
+        // Seeks before reading so that only the frames that will be played are processed
+        if config.start() > Duration::ZERO {
+            let start_ms = config.start().as_secs_f64() * 1000.0;
+            if let Err(e) = capture.set(videoio::CAP_PROP_POS_MSEC, start_ms) {
+                return Err(VideoError::OpenCvError(e));
+            }
+        }
+        let first_index = match capture.get(videoio::CAP_PROP_POS_FRAMES) {
+            Ok(i) => i as usize,
+            Err(e) => return Err(VideoError::OpenCvError(e)),
         };
 
-        let frames_manager = FramesManager::new(frames, fps, target_fps);
+        let decimator = FrameDecimator::new(native_fps, target_fps);
 
-        Ok(frames_manager) 
+        Ok(FrameReader {
+            capture,
+            decimator,
+            native_fps,
+            target_fps,
+            first_index,
+            end: config.end(),
+        })
     }
 }
 
-impl FramesManager {
-    pub fn next_frame(&mut self) -> Option<Box<dyn ImageAsString>> {
-        self.error += self.error_per_frame;
+impl FrameReader {
+    pub fn native_fps(&self) -> f64 {
+        self.native_fps
+    }
+
+    /// Frame rate once the frames dropped by the decimator are removed,
+    /// it is only the average for variable frame rates
+    pub fn target_fps(&self) -> f64 {
+        self.target_fps
+    }
+
+    pub fn first_index(&self) -> usize {
+        self.first_index
+    }
+
+    /// Reads the next frame to show with its index and presentation timestamp in the video,
+    /// skipping the ones dropped by the decimator.
+    /// Gives `None` once the end of the video or of the range is reached.
+    pub fn next_frame(&mut self) -> Option<(usize, Duration, Image)> {
+        loop {
+            // Grabbing does not convert the frame to an image
+            if !self.capture.grab().unwrap_or(false) {
+                return None;
+            }
 
-        while self.error >= 1.0 {
-            self.frames.next_frame();
-            self.error -= 1.0;
+            let index = (self.capture.get(videoio::CAP_PROP_POS_FRAMES).ok()? as usize).saturating_sub(1);
+            let timestamp = self.grabbed_timestamp(index);
+            if self.end.is_some_and(|e| timestamp >= e) {
+                return None;
+            }
+            if !self.decimator.keeps(timestamp) {
+                continue;
+            }
+
+            let mut buffer = UMat::new(opencv::core::UMatUsageFlags::USAGE_DEFAULT);
+            return match self.capture.retrieve(&mut buffer, 0) {
+                Ok(true) => Some((index, timestamp, Image::new(buffer))),
+                Ok(false) => None,
+                Err(_) => None,
+            };
         }
+    }
 
-        self.frames.next_frame()
+    /// Presentation timestamp of the frame that was just grabbed, when the container does not
+    /// have one it is guessed from the index
+    fn grabbed_timestamp(&self, index: usize) -> Duration {
+        match self.capture.get(videoio::CAP_PROP_POS_MSEC) {
+            Ok(ms) if ms.is_finite() && (ms > 0.0 || index == 0) => Duration::from_secs_f64(ms / 1000.0),
+            _ => Duration::from_secs_f64(index as f64 / self.native_fps),
+        }
     }
 
-    pub fn fps(&self) -> u32 {
-        self.target_fps
+    /// Keeps this share of the frames of the target frame rate,
+    /// over 1.0 more frames are kept if the video has them
+    pub fn set_fps_factor(&mut self, factor: f64) {
+        self.decimator = FrameDecimator::new(self.native_fps, self.target_fps * factor);
+    }
+
+    /// Makes `next_frame` read from the position in the video
+    pub fn seek(&mut self, position: Duration) -> opencv::Result<()> {
+        self.decimator.reset();
+        self.capture.set(videoio::CAP_PROP_POS_MSEC, position.as_secs_f64() * 1000.0)?;
+        Ok(())
     }
 }
 
 enum Frames {
-    Streamed(VideoCapture),
-    Preprocessed(Vec<TextImage>)
+    Pipelined(FramePipeline),
+    /// Frames read from a frame file, like the cache
+    File {
+        reader: FrameFileReader,
+        end: Option<Duration>,
+    },
+    Preprocessed {
+        store: FrameStore,
+        timestamps: Vec<Duration>, // Timestamp in the video of each stored frame
+    },
+    /// Frames that are already rendered, they are shown at a constant frame rate
+    Text {
+        frames: Vec<TextImage>,
+        position: usize,
+        fps: f64,
+    },
+    /// Frames drawn from the audio of an audio file
+    Visualizer(AudioFrames),
 }
 
 impl Frames {
-    pub fn build_preprocessed(mut capture: VideoCapture, config: &Config) -> Result<Frames, VideoError> {
+    /// Processes all the frames of the reader
+    pub fn build_preprocessed(
+        mut reader: FrameReader,
+        mut cache_writer: Option<CacheWriter>,
+        config: &Config,
+    ) -> Result<Frames, VideoError> {
         const FRAME_CHUNK_SIZE: usize = 10;
 
-        let mut frames: Vec<TextImage> = Vec::new();
-        let mut buffer = UMat::new(opencv::core::UMatUsageFlags::USAGE_DEFAULT);
+        let mut store = FrameStore::new(config.compress_frames());
+        let mut timestamps = Vec::new();
         let mut frame_chunk = Vec::new();
 
-        while match capture.read(&mut buffer) {
-            Ok(b) => b,
-            Err(e) => return Err(VideoError::OpenCvError(e)),
-        } {
-            let frame = Image::new(buffer);
-        
+        // The frames are stored relative to the previous one so they need to be pushed in order
+        let mut store_chunk = |frame_chunk: Vec<(usize, Duration, Image)>| {
+            let cell_grids = frame_chunk.into_par_iter()
+                .map(|(i, t, f)| (i, t, f.cells(&config)))
+                .collect::<Vec<(usize, Duration, CellGrid)>>();
+
+            for (index, timestamp, grid) in cell_grids {
+                // The video is played even if it can't be cached
+                if let Some(writer) = &mut cache_writer {
+                    if writer.push(index, timestamp, grid.clone()).is_err() {
+                        cache_writer = None;
+                    }
+                }
+
+                timestamps.push(timestamp);
+                store.push(grid);
+            }
+        };
+
+        while let Some(frame) = reader.next_frame() {
             frame_chunk.push(frame);
         
             if frame_chunk.len() == FRAME_CHUNK_SIZE {
-                let text_images = frame_chunk.into_par_iter()
-                    .map(|f| TextImage::build_from_image(f, &config))
-                    .collect::<Vec<TextImage>>();
-            
-                text_images.into_iter().for_each(|ti| frames.push(ti));
+                store_chunk(frame_chunk);
                 frame_chunk = Vec::new();
             }
-            
-            buffer = UMat::new(opencv::core::UMatUsageFlags::USAGE_DEFAULT);
         }
 
         // Processes the frame chunk that was not complete
-        let text_images = frame_chunk.into_par_iter()
-        .map(|f| TextImage::build_from_image(f, &config))
-        .collect::<Vec<TextImage>>();
+        store_chunk(frame_chunk);
 
-        text_images.into_iter().for_each(|ti| frames.push(ti));
+        if let Some(writer) = cache_writer {
+            let _ = writer.finish();
+        }
 
-        Ok(Frames::Preprocessed(frames))
+        Ok(Frames::Preprocessed { store, timestamps })
     }
 
-    pub fn build_streamed(capture: VideoCapture) -> Frames {
-        Frames::Streamed(capture)
+    /// Renders the frames in the background, only a few frames ahead are kept in memory
+    pub fn build_pipelined(reader: FrameReader, cache_writer: Option<CacheWriter>, config: &Config, quality: Option<Arc<QualityState>>) -> Frames {
+        Frames::Pipelined(FramePipeline::spawn(reader, cache_writer, config, quality))
     }
 }
 
 impl Frames {
-    pub fn next_frame(&mut self) -> Option<Box<dyn ImageAsString>> {
+    /// Gives the next frame with its presentation timestamp in the video
+    pub fn next_frame(&mut self) -> Option<(Duration, Box<dyn ImageAsString>)> {
         match self {
-            Frames::Streamed(cap) => {
-                let mut buffer = UMat::new(opencv::core::UMatUsageFlags::USAGE_DEFAULT);
-                match cap.read(&mut buffer) {
-                    Ok(_) => (),
-                    Err(_) => return None,
-                };
-
-                Some(Box::new(Image::new(buffer)))
+            Frames::Pipelined(pipeline) => {
+                let (timestamp, frame) = pipeline.next_frame()?;
+                Some((timestamp, Box::new(frame)))
             },
 
-            Frames::Preprocessed(frames) => {
-                let current_frame = frames.remove(0);
-                Some(Box::new(current_frame))
-            }
+            Frames::File { reader, end } => {
+                let timestamp = reader.next_timestamp()?;
+                if end.is_some_and(|e| timestamp >= e) {
+                    return None;
+                }
+                let (_, frame) = reader.next_frame()?;
+                Some((timestamp, Box::new(frame)))
+            },
+
+            Frames::Preprocessed { store, timestamps } => {
+                // The store is read in order so the frame is at the same position in the timestamps
+                let timestamp = *timestamps.get(store.position())?;
+                let frame = store.next_frame()?;
+                Some((timestamp, Box::new(frame)))
+            },
+
+            Frames::Text { frames, position, fps } => {
+                let frame = frames.get(*position)?.clone();
+                let timestamp = Duration::from_secs_f64(*position as f64 / *fps);
+                *position += 1;
+                Some((timestamp, Box::new(frame)))
+            },
+
+            Frames::Visualizer(frames) => {
+                let (timestamp, grid) = frames.next_frame()?;
+                Some((timestamp, Box::new(TextImage::build_from_cells(&grid))))
+            },
         }
     }
+
+    /// Moves to the first frame shown at or after the position
+    pub fn seek(&mut self, position: Duration) -> Result<(), VideoError> {
+        match self {
+            Frames::Pipelined(pipeline) => {
+                if let Err(e) = pipeline.seek(position) {
+                    return Err(VideoError::OpenCvError(e));
+                }
+            },
+
+            Frames::File { reader, .. } => reader.seek_timestamp(position),
+
+            Frames::Preprocessed { store, timestamps } => {
+                store.seek(timestamps.partition_point(|t| *t < position));
+            },
+
+            Frames::Text { frames, position: p, fps } => {
+                *p = ((position.as_secs_f64() * *fps).ceil() as usize).min(frames.len());
+            },
+
+            Frames::Visualizer(frames) => frames.seek(position),
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -199,10 +555,97 @@ mod tests {
         frames.next_frame().unwrap();
     }
 
+    #[test]
+    fn seek_frames_manager_streamed() {
+        let config = &default_config_streamed();
+        let mut frames = FramesManager::build("./test-assets/video-streamed.mp4", config).unwrap();
+
+        frames.seek(Duration::from_secs(2)).unwrap();
+        let (timestamp, _) = frames.next_frame().unwrap();
+        let timestamp = timestamp.as_secs_f64();
+        assert!((timestamp - 2.0).abs() < 0.1, "the next frame was at {timestamp}s");
+    }
+
+    #[test]
+    fn seek_frames_manager_preprocessed() {
+        let config = &default_config_preprocessed();
+        let mut frames = FramesManager::build("./test-assets/video-preprocessed.mp4", config).unwrap();
+
+        frames.seek(Duration::from_secs(2)).unwrap();
+        let (timestamp, _) = frames.next_frame().unwrap();
+        let timestamp = timestamp.as_secs_f64();
+        assert!((timestamp - 2.0).abs() < 0.1, "the next frame was at {timestamp}s");
+
+        frames.seek(Duration::from_secs(0)).unwrap();
+        let (timestamp, _) = frames.next_frame().unwrap();
+        assert!(timestamp.as_secs_f64() < 0.1, "the next frame was at {timestamp:?}");
+
+        // Seeking past the end leaves no frames
+        frames.seek(Duration::from_secs(3600)).unwrap();
+        assert!(frames.next_frame().is_none());
+    }
+
+    #[test]
+    fn trimmed_preprocessing() {
+        let mut config = default_config_preprocessed();
+        config.set_range(Duration::from_secs(2), Some(Duration::from_secs(3)));
+        let mut frames = FramesManager::build("./test-assets/video-preprocessed.mp4", &config).unwrap();
+
+        let position = frames.position().as_secs_f64();
+        assert!((position - 2.0).abs() < 0.1, "position was {position}s");
+
+        let mut nb_frames = 0;
+        while frames.next_frame().is_some() {
+            nb_frames += 1;
+        }
+        // The frame limit is 15 fps
+        assert!((14..=16).contains(&nb_frames), "{nb_frames} frames were played");
+    }
+
     #[test]
     fn big_frame_limit() {
         let config = &default_config_big_limiter();
         let mut frames = FramesManager::build("./test-assets/video-preprocessed.mp4", config).unwrap();
         frames.next_frame().unwrap();
     }
+
+    #[test]
+    fn timestamps_are_increasing() {
+        let config = &default_config_streamed();
+        let mut frames = FramesManager::build("./test-assets/video-streamed.mp4", config).unwrap();
+
+        let (first, _) = frames.next_frame().unwrap();
+        let (second, _) = frames.next_frame().unwrap();
+        // The video is 30 fps played at 15 fps
+        let interval = (second - first).as_secs_f64();
+        assert!((interval - 1.0 / 15.0).abs() < 0.01, "interval was {interval}s");
+    }
+
+    /// Number of frames kept by the decimator over a second of video
+    fn kept_frames(fps: f64, target_fps: f64) -> usize {
+        let mut decimator = FrameDecimator::new(fps, target_fps);
+        (0..(fps.round() as usize))
+            .filter(|i| decimator.keeps(Duration::from_secs_f64(*i as f64 / fps)))
+            .count()
+    }
+
+    #[test]
+    fn decimation_of_fractional_frame_rates() {
+        assert_eq!(15, kept_frames(30.0, 15.0));
+        assert_eq!(15, kept_frames(29.97, 15.0));
+        assert_eq!(15, kept_frames(23.976, 15.0));
+        assert_eq!(24, kept_frames(24.0, 30.0));
+        assert_eq!(60, kept_frames(60.0, 60.0));
+    }
+
+    #[test]
+    fn decimation_of_variable_frame_rates() {
+        let mut decimator = FrameDecimator::new(30.0, 10.0);
+        let kept = [0, 33, 66, 100, 400, 433, 466, 500, 533]
+            .into_iter()
+            .filter(|ms| decimator.keeps(Duration::from_millis(*ms)))
+            .collect::<Vec<u64>>();
+        // The gap in the frames does not make the following ones be kept faster
+        assert_eq!(vec![0, 100, 400, 500], kept);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,217 @@
+//! Exports the rendered frames as a single html page with a small player, to share them where
+//! terminal recordings cannot be played
+//!
+//! The frames are delta encoded like in the frame files, gzipped and put in the page as base64.
+//! The player of `assets/html-player.html` decompresses them with the `DecompressionStream` of
+//! the browser and draws each frame as colored spans.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use colored::Color;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::cells::CellGrid;
+use crate::frame_store::{self, FrameEncoder};
+use crate::mosaic;
+
+pub const HTML_EXTENSION: &str = "html";
+
+const PLAYER_TEMPLATE: &str = include_str!("../assets/html-player.html");
+
+/// Writes the frames in an html page played at the frame rate, with the audio if there is some
+pub struct HtmlWriter {
+    path: PathBuf,
+    title: String,
+    fps: f64,
+    audio: Option<(String, Vec<u8>)>, // The mime type and the content of the audio file
+    encoder: FrameEncoder,
+    frames: GzEncoder<Vec<u8>>,
+}
+
+impl HtmlWriter {
+    /// The page is only written when the writer is finished, `audio` is a mime type and a file
+    pub fn create(path: &Path, title: &str, fps: f64, audio: Option<(String, Vec<u8>)>) -> HtmlWriter {
+        HtmlWriter {
+            path: path.to_path_buf(),
+            title: title.to_string(),
+            fps,
+            audio,
+            encoder: FrameEncoder::new(false),
+            frames: GzEncoder::new(Vec::new(), Compression::best()),
+        }
+    }
+}
+
+impl HtmlWriter {
+    /// Adds the frame after the others, it is shown one frame duration after the previous one
+    pub fn push(&mut self, grid: CellGrid) -> io::Result<()> {
+        let frame = self.encoder.encode(grid);
+
+        let mut bytes = vec![frame.keyframe as u8];
+        frame_store::write_varint(&mut bytes, frame.bytes.len() as u64);
+        bytes.extend_from_slice(&frame.bytes);
+
+        self.frames.write_all(&bytes)
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        let frames = self.frames.finish()?;
+        let palette = self.encoder.palette().iter()
+            .map(|c| css_color(*c))
+            .collect::<Vec<String>>()
+            .join(",");
+        let audio = match &self.audio {
+            Some((mime_type, a)) => format!("data:{mime_type};base64,{}", base64(a)),
+            None => String::new(),
+        };
+
+        // The title is the only text that is not made here
+        let html = fill_template(PLAYER_TEMPLATE, &[
+            ("TITLE", &escape_html(&self.title)),
+            ("FPS", &self.fps.to_string()),
+            ("PALETTE", &format!("[{palette}]")),
+            ("FRAMES", &base64(&frames)),
+            ("AUDIO", &audio),
+        ]);
+
+        fs::write(&self.path, html)
+    }
+}
+
+/// The color as a CSS string, or `null` for the default color
+fn css_color(color: Option<Color>) -> String {
+    match color {
+        Some(c) => {
+            let (r, g, b) = mosaic::color_rgb(c);
+            format!("\"#{r:02x}{g:02x}{b:02x}\"")
+        },
+        None => "null".to_string(),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Replaces the `{{NAME}}` placeholders of the template with their values in a single pass,
+/// so that a value containing a placeholder is left as it is
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut text = String::with_capacity(template.len() + values.iter().map(|(_, v)| v.len()).sum::<usize>());
+
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        text.push_str(&rest[..start]);
+        rest = &rest[start + 2..];
+
+        let value = rest.find("}}").and_then(|end| {
+            values.iter().find(|(name, _)| *name == &rest[..end]).map(|(_, v)| (end, v))
+        });
+        match value {
+            Some((end, value)) => {
+                text.push_str(value);
+                rest = &rest[end + 2..];
+            },
+            // Not a placeholder
+            None => text.push_str("{{"),
+        }
+    }
+    text.push_str(rest);
+
+    text
+}
+
+/// Encodes the bytes in standard base64 with padding
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = (chunk[0] as u32) << 16
+            | (chunk.get(1).copied().unwrap_or(0) as u32) << 8
+            | chunk.get(2).copied().unwrap_or(0) as u32;
+
+        // A chunk of n bytes gives n + 1 characters
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(group >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+    use crate::cells::Cell;
+
+    #[test]
+    fn base64_is_padded() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zg==", base64(b"f"));
+        assert_eq!("Zm8=", base64(b"fo"));
+        assert_eq!("Zm9v", base64(b"foo"));
+        assert_eq!("Zm9vYmFy", base64(b"foobar"));
+        assert_eq!("+/8=", base64(&[0xFB, 0xFF]));
+    }
+
+    #[test]
+    fn placeholders_in_the_values_are_not_replaced() {
+        let values = [("TITLE", "{{FRAMES}}"), ("FRAMES", "AAAA")];
+        assert_eq!("<{{FRAMES}}> AAAA {{OTHER}} {{", fill_template("<{{TITLE}}> {{FRAMES}} {{OTHER}} {{", &values));
+    }
+
+    #[test]
+    fn frames_are_put_in_the_page() {
+        let path = std::env::temp_dir().join("cli-player-test.html");
+        let red = Some(Color::TrueColor { r: 255, g: 0, b: 0 });
+
+        let mut writer = HtmlWriter::create(&path, "<video>", 12.0, None);
+        writer.push(CellGrid::new(2, 1, vec![Cell::new('a', red), Cell::new('b', None)])).unwrap();
+        writer.push(CellGrid::new(2, 1, vec![Cell::new('a', red), Cell::new('c', None)])).unwrap();
+        writer.finish().unwrap();
+
+        let html = fs::read_to_string(&path).unwrap();
+        assert!(html.contains("<title>&lt;video&gt;</title>"));
+        assert!(html.contains("const FPS = 12;"));
+        assert!(html.contains(r##"const PALETTE = ["#ff0000",null];"##));
+        assert!(html.contains(r#"const AUDIO = "";"#));
+        assert!(!html.contains("{{"));
+
+        // A keyframe with all the cells then a frame with the changed cell
+        let frames = html.split("const FRAMES = \"").nth(1).unwrap().split('"').next().unwrap();
+        let mut bytes = Vec::new();
+        GzDecoder::new(decode_base64(frames).as_slice()).read_to_end(&mut bytes).unwrap();
+        assert_eq!(vec![1, 6, 2, 1, 97, 0, 98, 1, 0, 6, 2, 1, 1, 1, 99, 1], bytes);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn decode_base64(text: &str) -> Vec<u8> {
+        const ALPHABET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+        let values: Vec<u32> = text.trim_end_matches('=').chars()
+            .map(|c| ALPHABET.find(c).unwrap() as u32)
+            .collect();
+        let mut bytes = Vec::new();
+        for chunk in values.chunks(4) {
+            let group = chunk.iter().enumerate().fold(0, |g, (i, v)| g | v << (18 - i * 6));
+            for i in 0..chunk.len() - 1 {
+                bytes.push((group >> (16 - i * 8)) as u8);
+            }
+        }
+
+        bytes
+    }
+}
@@ -1,12 +1,13 @@
 use std::sync::Mutex;
 
-use opencv::core::{UMat, Size, Point, VecN};
-use opencv::imgproc;
+use opencv::core::{UMat, Size, Point, VecN, Vector};
+use opencv::{imgcodecs, imgproc};
 use opencv::prelude::{UMatTraitConst, MatTraitConst};
-use colored::Colorize;
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 
+use crate::cells::{Cell, CellGrid};
 use crate::config::Config;
+use crate::quality::Interpolation;
 
 pub trait ImageAsString {
     fn as_string(&self, config: &Config) -> String;
@@ -27,13 +28,28 @@ impl Image {
     }
 }
 
+impl Image {
+    /// Decodes an image file, like a jpeg or png, `None` if it is not an image
+    pub fn decode(bytes: &[u8]) -> Option<Image> {
+        let decoded = imgcodecs::imdecode(&Vector::<u8>::from_slice(bytes), imgcodecs::IMREAD_COLOR).ok()?;
+        if decoded.cols() == 0 {
+            return None;
+        }
+
+        let mut content = UMat::new(opencv::core::UMatUsageFlags::USAGE_DEFAULT);
+        decoded.copy_to(&mut content).ok()?;
+        Some(Image::new(content))
+    }
+}
+
 impl Image {
     pub fn content(&self) -> &UMat {
         &self.content
     }
 
-    fn scale(&self, width: u32) -> UMat {
-        const INTERPOLATION: i32 = imgproc::INTER_LANCZOS4;
+    /// Scales the image so that each pixel is a glyph taking `glyph_width` columns,
+    /// the image is then `width` columns wide
+    fn scale(&self, width: u32, glyph_width: usize, interpolation: Interpolation) -> UMat {
         const HEIGHT_TO_WIDHT: f64 = 2.0;
 
         let mut old_size = Size::default();
@@ -41,27 +57,38 @@ impl Image {
         self.content.locate_roi(&mut old_size, &mut useless)
             .expect("Image should have size");
 
-        let downscale_factor = width as f64 / old_size.width as f64;
-        let height = ((old_size.height as f64 * downscale_factor) / HEIGHT_TO_WIDHT) as i32;
-        let size = Size::from((width as i32, height));
+        // Wide glyphs take multiple columns so they cover more of the image horizontally
+        let nb_glyphs = (width as usize / glyph_width).max(1);
+        let glyph_height_to_width = HEIGHT_TO_WIDHT / glyph_width as f64;
+
+        let downscale_factor = nb_glyphs as f64 / old_size.width as f64;
+        let height = ((old_size.height as f64 * downscale_factor) / glyph_height_to_width) as i32;
+        let size = Size::from((nb_glyphs as i32, height));
 
         let mut scaled_image = UMat::new(opencv::core::UMatUsageFlags::USAGE_DEFAULT);
-        // NOTE: See to change the interpolation for performance
-        imgproc::resize(&self.content, &mut scaled_image, size, 0.0, 0.0, INTERPOLATION)
+        let interpolation = match interpolation {
+            Interpolation::Nearest => imgproc::INTER_NEAREST,
+            Interpolation::Linear => imgproc::INTER_LINEAR,
+            Interpolation::Lanczos => imgproc::INTER_LANCZOS4,
+        };
+        imgproc::resize(&self.content, &mut scaled_image, size, 0.0, 0.0, interpolation)
             .expect("Scaling should not fail given positive size");
 
         scaled_image
     }
 }
 
-impl ImageAsString for Image {
-    // NOTE: Most of the lag of the program seems to come from this function
-    fn as_string(&self, config: &Config) -> String {
-        if let Some(s) = &*self.as_string.lock().unwrap() {
-            return s.clone();
-        }
+impl Image {
+    /// Renders the image to text without going through the cache of `as_string`,
+    /// useful when the image needs to be rendered with multiple configs
+    pub fn render(&self, config: &Config) -> String {
+        self.cells(config).to_text()
+    }
 
-        let scaled_image = self.scale(config.width());
+    /// Picks the glyph and color of each cell of the rendered image
+    // NOTE: Most of the lag of the program seems to come from this function
+    pub fn cells(&self, config: &Config) -> CellGrid {
+        let scaled_image = self.scale(config.width(), config.glyph_width(), config.interpolation());
 
         
         // Gets the rows
@@ -74,36 +101,30 @@ impl ImageAsString for Image {
         }
 
         // Render the rows in parralel
-        let text_rows = rows.into_par_iter().map(|row| {
-            let mut text_row = String::new();
-            for x in 0..config.width() {
-                let pixel: &VecN<u8, 3> = row.at(x as i32)
+        let cell_rows = rows.into_par_iter().map(|row| {
+            let mut cell_row = Vec::new();
+            for x in 0..row.cols() {
+                let pixel: &VecN<u8, 3> = row.at(x)
                     .expect("Pixel should not be out of range");
 
-                let red = pixel[2];
-                let green = pixel[1];
-                let blue = pixel[0];
-
-                let luminosity = ((red as u32 + green as u32 + blue as u32) / 3) as u8;
-                let character = config.pallet().character_for_luminosity(luminosity).unwrap_or('�');
-
-                if !config.color() {
-                    text_row.push(character);
-                } else {
-                    let string = character.to_string();
-                    let colored_string = string.truecolor(red, green, blue);
-                    // Without this .to_string() the output is not colored
-                    text_row.push_str(&colored_string.to_string());
-                }
+                // The pixels are blue, green, red
+                cell_row.push(Cell::for_pixel(pixel[2], pixel[1], pixel[0], config));
             }
 
-            text_row.push('\n');
+            cell_row
+        }).collect::<Vec<Vec<Cell>>>();
+
+        CellGrid::from_rows(cell_rows)
+    }
+}
 
-            text_row
-        }).collect::<Vec<String>>();
+impl ImageAsString for Image {
+    fn as_string(&self, config: &Config) -> String {
+        if let Some(s) = &*self.as_string.lock().unwrap() {
+            return s.clone();
+        }
 
-        let mut out = String::new();
-        text_rows.into_iter().for_each(|tr| out.push_str(&tr));
+        let out = self.render(config);
 
         *self.as_string.lock().unwrap() = Some(out);
         self.as_string(&config)
@@ -111,6 +132,7 @@ impl ImageAsString for Image {
 }
 
 /// Only stores the text representing the image
+#[derive(Clone)]
 pub struct TextImage {
     text: String,
 }
@@ -126,6 +148,15 @@ impl TextImage {
         let text = image.as_string(&config);
         TextImage::new(text)
     }
+
+    pub fn build_from_cells(cells: &CellGrid) -> TextImage {
+        TextImage::new(cells.to_text())
+    }
+
+    /// Uses text that is already rendered, like the frames of an ANSI dump
+    pub fn build_from_text(text: String) -> TextImage {
+        TextImage::new(text)
+    }
 }
 
 impl ImageAsString for TextImage {
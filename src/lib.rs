@@ -2,10 +2,35 @@ pub mod character_pallet;
 pub mod video;
 pub mod image;
 pub mod video_player;
+pub mod controls;
+pub mod terminal_session;
 pub mod config;
 pub mod audio_manager;
+pub mod audio_decoder;
+pub mod audio_output;
+pub mod loudness;
+pub mod time_stretch;
 pub mod wating_animation;
 pub mod frames;
+pub mod frame_pipeline;
+pub mod quality;
+pub mod frame_store;
+pub mod frame_file;
+pub mod frame_cache;
+pub mod text_video;
+pub mod asciicast;
+pub mod raster;
+pub mod rendered_video;
+pub mod html;
+pub mod ansi_dump;
+pub mod export;
+pub mod cells;
+pub mod pallet_editor;
+pub mod mosaic;
+pub mod visualizer;
+pub mod workspace;
+#[cfg(test)]
+mod test_fixtures;
 
 pub use config::Config;
 pub use audio_manager::AudioManager;
@@ -0,0 +1,451 @@
+//! Evens out the loudness of videos and avoids sudden volume changes
+//!
+//! The loudness of the audio track is measured like EBU R128 does (K-weighted and gated) and a
+//! gain brings it to the same level as every other video, like ReplayGain. The gain changes
+//! smoothly, so volume changes, starts and stops fade instead of jumping, and a soft limiter
+//! keeps gains over 1.0 from clipping. The fades happen while the audio is played, nothing
+//! waits for them.
+
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+
+/// Loudness the audio is brought to, in LUFS (the reference level of ReplayGain 2)
+pub const TARGET_LOUDNESS: f64 = -18.0;
+/// Most the audio is made louder by, in dB, so that quiet tracks don't have their noise raised too much
+const MAX_BOOST: f64 = 12.0;
+
+/// Blocks of audio that are measured, they overlap by 75%
+const BLOCK_DURATION: Duration = Duration::from_millis(400);
+const BLOCK_STEP: Duration = Duration::from_millis(100);
+/// Blocks quieter than this are silence, in LUFS
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Blocks this much quieter than the average are left out, in LU
+const RELATIVE_GATE: f64 = -10.0;
+
+/// Time for the gain to get most of the way to a new value
+const GAIN_SMOOTHING: Duration = Duration::from_millis(30);
+/// Time for the normalization to get most of the way to a new loudness, it is measured while playing
+const NORMALIZATION_SMOOTHING: Duration = Duration::from_secs(5);
+/// Gain under which a fade is over, the audio can be cut without a click
+const SILENT_GAIN: f32 = 0.01;
+/// Level from which the peaks are softly squashed when the gain is over 1.0
+const LIMITER_THRESHOLD: f32 = 0.8;
+
+/// Two-pole filter, the K-weighting is made of two of them
+#[derive(Clone, Copy, Debug)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad { b, a, state: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The filters of ITU BS.1770 (a high shelf for the head and a high pass) at the sample rate
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+/// Measures the integrated loudness of interleaved samples given as they are decoded.
+/// All the channels are weighted the same.
+pub struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>, // For each channel
+    step_length: usize, // Samples in a step of the blocks
+    step_energy: f64, // Sum of the squared weighted samples of the step being read
+    step_position: usize, // Samples of the step read so far
+    steps: Vec<f64>, // Mean energy of the last steps, a block is made of `BLOCK_DURATION / BLOCK_STEP` of them
+    blocks: Vec<f64>, // Mean energy of each block
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: u16, sample_rate: u32) -> LoudnessMeter {
+        let channels = channels.max(1) as usize;
+
+        LoudnessMeter {
+            channels,
+            filters: vec![k_weighting(sample_rate); channels],
+            step_length: ((sample_rate as f64 * BLOCK_STEP.as_secs_f64()) as usize).max(1) * channels,
+            step_energy: 0.0,
+            step_position: 0,
+            steps: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+}
+
+impl LoudnessMeter {
+    pub fn push(&mut self, samples: &[f32]) {
+        let steps_per_block = (BLOCK_DURATION.as_millis() / BLOCK_STEP.as_millis()) as usize;
+
+        for &sample in samples {
+            let [shelf, high_pass] = &mut self.filters[self.step_position % self.channels];
+            let weighted = high_pass.process(shelf.process(sample as f64));
+            self.step_energy += weighted * weighted;
+
+            self.step_position += 1;
+            if self.step_position == self.step_length {
+                self.steps.push(self.step_energy / (self.step_length / self.channels) as f64);
+                self.step_energy = 0.0;
+                self.step_position = 0;
+
+                if self.steps.len() >= steps_per_block {
+                    let block = &self.steps[self.steps.len() - steps_per_block..];
+                    self.blocks.push(block.iter().sum::<f64>() / steps_per_block as f64);
+                }
+            }
+        }
+    }
+
+    /// Integrated loudness in LUFS, `None` for silence or audio shorter than a block
+    pub fn loudness(&self) -> Option<f64> {
+        let audible: Vec<f64> = self.blocks.iter()
+            .copied()
+            .filter(|&e| energy_to_loudness(e) > ABSOLUTE_GATE)
+            .collect();
+        if audible.is_empty() {
+            return None;
+        }
+
+        let threshold = energy_to_loudness(mean(&audible)) + RELATIVE_GATE;
+        let gated: Vec<f64> = audible.into_iter()
+            .filter(|&e| energy_to_loudness(e) > threshold)
+            .collect();
+
+        Some(energy_to_loudness(mean(&gated)))
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// Gain bringing audio of this loudness to the target loudness
+pub fn normalization_gain(loudness: f64) -> f32 {
+    decibels_to_gain(TARGET_LOUDNESS - loudness)
+}
+
+/// Gain of a change in dB, like a ReplayGain tag gives
+pub fn decibels_to_gain(decibels: f64) -> f32 {
+    10f64.powf(decibels.min(MAX_BOOST) / 20.0) as f32
+}
+
+/// Softly squashes the samples over the threshold so that they stay under 1.0
+fn limit(sample: f32) -> f32 {
+    let level = sample.abs();
+    if level <= LIMITER_THRESHOLD {
+        return sample;
+    }
+
+    let headroom = 1.0 - LIMITER_THRESHOLD;
+    let limited = LIMITER_THRESHOLD + headroom * ((level - LIMITER_THRESHOLD) / headroom).tanh();
+    limited.copysign(sample)
+}
+
+/// The gain of the audio being played, shared with the sources
+#[derive(Debug)]
+pub struct GainControl {
+    volume: AtomicU32, // The bits of the f32 volume picked by the user
+    normalization: AtomicU32, // The bits of the f32 gain evening out the loudness
+    paused: AtomicBool, // The audio fades out and the sources are not read
+    analysis: AtomicUsize, // Number of the last loudness analysis started, older ones are ignored
+}
+
+impl Default for GainControl {
+    fn default() -> GainControl {
+        GainControl {
+            volume: AtomicU32::new(1.0f32.to_bits()),
+            normalization: AtomicU32::new(1.0f32.to_bits()),
+            paused: AtomicBool::new(false),
+            analysis: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl GainControl {
+    pub fn set_volume(&self, volume: f32) {
+        self.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_normalization(&self, gain: f32) {
+        self.normalization.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Fades the audio out and stops reading the sources, or fades it back in
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Number of a new analysis, the ones started before are outdated
+    pub fn start_analysis(&self) -> usize {
+        self.analysis.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Whether no analysis was started after this one
+    pub fn is_current_analysis(&self, analysis: usize) -> bool {
+        self.analysis.load(Ordering::Relaxed) == analysis
+    }
+
+    fn volume(&self) -> f32 {
+        f32::from_bits(self.volume.load(Ordering::Relaxed))
+    }
+
+    fn normalization(&self) -> f32 {
+        f32::from_bits(self.normalization.load(Ordering::Relaxed))
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+}
+
+/// Applies the gain of the control to the source, the gain moves smoothly to its target
+/// and starts from silence so that the source fades in. Once faded out, a paused source
+/// plays silence without being read and a stopped source ends, so the next one can be played.
+pub struct GainSource<S> {
+    source: S,
+    control: Arc<GainControl>,
+    stopped: Arc<AtomicBool>, // Fades out and ends the source
+    gain: f32,
+    normalization: f32, // Follows the one of the control slowly, it changes as the loudness is measured
+    smoothing: f32, // Share of the way to the target gone at each frame
+    normalization_smoothing: f32,
+    silent: bool, // Faded out, the source is not read
+    channel: u16, // Channel of the next sample, the gain changes between frames
+}
+
+impl<S: Source<Item = f32>> GainSource<S> {
+    pub fn new(source: S, control: Arc<GainControl>, stopped: Arc<AtomicBool>) -> GainSource<S> {
+        let sample_rate = source.sample_rate() as f32;
+        let normalization = control.normalization();
+
+        GainSource {
+            source,
+            control,
+            stopped,
+            gain: 0.0,
+            normalization,
+            smoothing: smoothing(GAIN_SMOOTHING, sample_rate),
+            normalization_smoothing: smoothing(NORMALIZATION_SMOOTHING, sample_rate),
+            silent: false,
+            channel: 0,
+        }
+    }
+}
+
+/// Share of the way to a target to go at each frame to get most of the way there in `duration`
+fn smoothing(duration: Duration, sample_rate: f32) -> f32 {
+    let frames = duration.as_secs_f32() * sample_rate;
+    1.0 - (-1.0 / frames.max(1.0)).exp()
+}
+
+impl<S: Source<Item = f32>> Iterator for GainSource<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            let stopped = self.stopped.load(Ordering::Relaxed);
+            let fading_out = stopped || self.control.is_paused();
+
+            self.normalization += (self.control.normalization() - self.normalization) * self.normalization_smoothing;
+            let target = if fading_out { 0.0 } else { self.control.volume() * self.normalization };
+            self.gain += (target - self.gain) * self.smoothing;
+
+            self.silent = fading_out && self.gain < SILENT_GAIN;
+            if self.silent && stopped {
+                return None;
+            }
+        }
+        self.channel = (self.channel + 1) % self.source.channels().max(1);
+
+        if self.silent {
+            return Some(0.0);
+        }
+        let sample = self.source.next()?;
+
+        let sample = sample * self.gain;
+        if self.gain > 1.0 {
+            Some(limit(sample))
+        } else {
+            Some(sample)
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Source for GainSource<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, amplitude: f32, sample_rate: u32, seconds: f32) -> Vec<f32> {
+        (0..(seconds * sample_rate as f32) as usize)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    /// A constant sample in mono, that counts the samples read
+    struct ConstantSource {
+        read: usize,
+    }
+
+    impl Iterator for ConstantSource {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            self.read += 1;
+            Some(0.5)
+        }
+    }
+
+    impl Source for ConstantSource {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            8000
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    #[test]
+    fn loudness_of_a_sine() {
+        // A 1 kHz sine 20 dB under full scale is at -23 LUFS
+        for sample_rate in [44100, 48000] {
+            let mut meter = LoudnessMeter::new(1, sample_rate);
+            for chunk in sine(1000.0, 0.1, sample_rate, 3.0).chunks(1000) {
+                meter.push(chunk);
+            }
+
+            let loudness = meter.loudness().unwrap();
+            assert!((loudness + 23.0).abs() < 0.1, "loudness was {loudness}");
+        }
+
+        let mut meter = LoudnessMeter::new(2, 48000);
+        meter.push(&[0.0; 48000 * 2]);
+        assert_eq!(None, meter.loudness());
+    }
+
+    #[test]
+    fn quiet_parts_are_gated() {
+        let mut loud = LoudnessMeter::new(1, 48000);
+        loud.push(&sine(1000.0, 0.1, 48000, 2.0));
+
+        let mut with_silence = LoudnessMeter::new(1, 48000);
+        with_silence.push(&sine(1000.0, 0.1, 48000, 2.0));
+        with_silence.push(&sine(1000.0, 0.001, 48000, 4.0));
+
+        let difference = (loud.loudness().unwrap() - with_silence.loudness().unwrap()).abs();
+        assert!(difference < 0.5, "difference was {difference}");
+    }
+
+    #[test]
+    fn gain_is_limited() {
+        assert!((normalization_gain(-24.0) - 2.0).abs() < 0.01);
+        assert!((normalization_gain(-12.0) - 0.5).abs() < 0.01);
+        assert!((normalization_gain(-70.0) - 10f32.powf(MAX_BOOST as f32 / 20.0)).abs() < 0.01);
+        assert!((decibels_to_gain(-6.02) - 0.5).abs() < 0.01);
+
+        assert_eq!(0.5, limit(0.5));
+        assert!(limit(4.0) <= 1.0 && limit(0.9) > 0.85);
+        assert_eq!(-limit(1.5), limit(-1.5));
+    }
+
+    #[test]
+    fn stopped_sources_fade_out_and_end() {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut source = GainSource::new(ConstantSource { read: 0 }, Arc::new(GainControl::default()), Arc::clone(&stopped));
+
+        // The source fades in
+        let samples: Vec<f32> = source.by_ref().take(2000).collect();
+        assert!(samples[0] < 0.05 && (samples[1999] - 0.5).abs() < 0.01);
+
+        stopped.store(true, Ordering::Relaxed);
+        let samples: Vec<f32> = source.collect();
+        // It ends after less than 200 ms, without jumping
+        assert!(!samples.is_empty() && samples.len() < 1600, "ended after {} samples", samples.len());
+        assert!(samples.windows(2).all(|w| (w[0] - w[1]).abs() < 0.05));
+    }
+
+    #[test]
+    fn paused_sources_are_not_read() {
+        let control = Arc::new(GainControl::default());
+        let mut source = GainSource::new(ConstantSource { read: 0 }, Arc::clone(&control), Arc::new(AtomicBool::new(false)));
+        source.by_ref().take(2000).for_each(drop);
+
+        control.set_paused(true);
+        source.by_ref().take(2000).for_each(drop);
+        let read = source.source.read;
+        assert!(read < 4000);
+        // Silence is played meanwhile
+        assert_eq!(Some(0.0), source.next());
+        assert_eq!(read, source.source.read);
+
+        control.set_paused(false);
+        source.by_ref().take(2000).for_each(drop);
+        assert_eq!(read + 2000, source.source.read);
+    }
+}
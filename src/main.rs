@@ -1,19 +1,72 @@
-use std::process;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
 
 use cli_player::{Video, Config};
-use cli_player::video_player;
+use cli_player::audio_decoder::{self, AudioInput};
+use cli_player::config::{Command, CacheAction};
+use cli_player::frame_cache::{FrameCache, FrameCacheError};
+use cli_player::{export, video, video_player};
+use cli_player::pallet_editor;
 use cli_player::wating_animation;
+use cli_player::workspace;
 
 #[tokio::main]
 async fn main() {
+    // The temporary files are removed even if the program stops early
+    workspace::install_cleanup_handlers();
+    run().await;
+    workspace::cleanup();
+}
+
+async fn run() {
     let config = match Config::build_from_args() {
         Ok(c) => c,
         Err(e) => {
             eprintln!("Error while creating config: {e}");
-            process::exit(1);
+            workspace::exit(1);
         }
     };
 
+    if let Some(command) = config.command() {
+        if let Err(e) = run_command(command) {
+            eprintln!("Error while managing the cache: {e}");
+            workspace::exit(1);
+        }
+        return;
+    }
+
+    if config.list_audio_tracks() {
+        if let Err(e) = list_audio_tracks(&config).await {
+            eprintln!("Error while listing the audio tracks: {e}");
+            workspace::exit(1);
+        }
+        return;
+    }
+
+    if config.edit_pallet() {
+        if let Err(e) = pallet_editor::edit_pallet(config) {
+            eprintln!("Error while editing the pallet: {e}");
+            workspace::exit(1);
+        }
+        return;
+    }
+
+    if let Some(output_path) = config.export() {
+        let animation = wating_animation::spawn_animation_thread("Exporting");
+        let result = export_video(&config, output_path).await;
+        animation.end();
+
+        match result {
+            Ok(()) => println!("Exported to {output_path}"),
+            Err(e) => {
+                eprintln!("Error while exporting the video: {e}");
+                workspace::exit(1);
+            },
+        }
+        return;
+    }
+
     let animation = wating_animation::spawn_animation_thread("Processing");
     let video = match Video::build_from_path(config.query(), &config) {
         Ok(v) => { animation.end(); v },
@@ -24,7 +77,7 @@ async fn main() {
             let animation = wating_animation::spawn_animation_thread("Downloading and processing");
             match Video::build_from_url(config.query(), &config).await {
                 Ok(v) => { animation.end(); v },
-                Err(e) => { eprint!("Error while tring to download: {e}"); process::exit(1); }
+                Err(e) => { eprint!("Error while tring to download: {e}"); workspace::exit(1); }
             }
         },
     };
@@ -34,3 +87,66 @@ async fn main() {
         Err(e) => eprintln!("Error while playing the video: {e}"),
     };
 }
+
+/// Exports the video on the computer, or downloads it first
+async fn export_video(config: &Config, output_path: &str) -> Result<(), Box<dyn Error>> {
+    let path = find_video(config).await?;
+
+    export::export_video(&path, config, output_path)?;
+    Ok(())
+}
+
+/// Prints the audio tracks of the video on the computer, or downloads it first
+async fn list_audio_tracks(config: &Config) -> Result<(), Box<dyn Error>> {
+    let path = find_video(config).await?;
+
+    let tracks = audio_decoder::audio_tracks(&AudioInput::Path(path))?;
+    if tracks.is_empty() {
+        println!("The video has no audio track");
+    }
+    for track in tracks {
+        println!("{track}");
+    }
+
+    Ok(())
+}
+
+/// Path of the video of the query, it is downloaded if it is not on the computer
+async fn find_video(config: &Config) -> Result<String, Box<dyn Error>> {
+    if Path::new(config.query()).exists() {
+        Ok(config.query().to_string())
+    } else {
+        Ok(video::download_video(config.query()).await?)
+    }
+}
+
+fn run_command(command: &Command) -> Result<(), FrameCacheError> {
+    match command {
+        Command::Cache { action: CacheAction::List } => {
+            let entries = FrameCache::user_cache().list_entries()?;
+            if entries.is_empty() {
+                println!("The cache is empty");
+            }
+
+            let mut total_size = 0;
+            for entry in entries {
+                let days = entry.last_used.elapsed().unwrap_or(Duration::ZERO).as_secs() / (24 * 60 * 60);
+                println!("{} ({:.1} MB, last played {days} days ago)", entry.source, megabytes(entry.size));
+                println!("    {}", entry.settings);
+                total_size += entry.size;
+            }
+            println!("Total: {:.1} MB", megabytes(total_size));
+        },
+        Command::Cache { action: CacheAction::Prune { older_than_days } } => {
+            let unused_for = older_than_days.map(|d| Duration::from_secs(d * 24 * 60 * 60));
+            let freed = FrameCache::user_cache().prune(unused_for)?;
+            println!("Freed {:.1} MB", megabytes(freed));
+        },
+    }
+
+    Ok(())
+}
+
+fn megabytes(bytes: u64) -> f64 {
+    bytes as f64 / 1_000_000.0
+}
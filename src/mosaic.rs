@@ -0,0 +1,205 @@
+//! Renders images by picking the glyph of the closest color instead of the closest luminosity
+
+use clap::ValueEnum;
+use colored::Color;
+
+use crate::character_pallet;
+
+/// The tables of glyphs available for mosaic rendering
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MosaicKind {
+    /// Colored square emojis
+    Emoji,
+    /// Shaded blocks in the 16 basic terminal colors
+    Blocks,
+}
+
+/// Default colors of xterm, most terminals are close to them
+const BASIC_COLORS: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::White, (229, 229, 229)),
+    (Color::BrightBlack, (127, 127, 127)),
+    (Color::BrightRed, (255, 0, 0)),
+    (Color::BrightGreen, (0, 255, 0)),
+    (Color::BrightYellow, (255, 255, 0)),
+    (Color::BrightBlue, (92, 92, 255)),
+    (Color::BrightMagenta, (255, 0, 255)),
+    (Color::BrightCyan, (0, 255, 255)),
+    (Color::BrightWhite, (255, 255, 255)),
+];
+
+/// The color as it would likely be displayed by the terminal
+pub fn color_rgb(color: Color) -> (u8, u8, u8) {
+    match color {
+        Color::TrueColor { r, g, b } => (r, g, b),
+        c => BASIC_COLORS.iter()
+            .find(|(basic_color, _)| *basic_color == c)
+            .map_or((229, 229, 229), |(_, rgb)| *rgb),
+    }
+}
+
+/// A glyph as it appears in the terminal
+#[derive(Clone)]
+pub struct MosaicGlyph {
+    character: char,
+    color: Option<Color>, // Color the glyph is printed in
+    rgb: (u8, u8, u8), // Average color of the glyph once displayed
+}
+
+impl MosaicGlyph {
+    fn new(character: char, color: Option<Color>, rgb: (u8, u8, u8)) -> MosaicGlyph {
+        MosaicGlyph {
+            character,
+            color,
+            rgb,
+        }
+    }
+}
+
+impl MosaicGlyph {
+    pub fn character(&self) -> char {
+        self.character
+    }
+
+    pub fn color(&self) -> Option<Color> {
+        self.color
+    }
+
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        self.rgb
+    }
+}
+
+/// Stores glyphs with their average color
+#[derive(Clone)]
+pub struct MosaicTable {
+    kind: MosaicKind,
+    glyphs: Vec<MosaicGlyph>,
+}
+
+impl MosaicTable {
+    pub fn build(kind: MosaicKind) -> MosaicTable {
+        let glyphs = match kind {
+            MosaicKind::Emoji => MosaicTable::emoji_glyphs(),
+            MosaicKind::Blocks => MosaicTable::block_glyphs(),
+        };
+
+        MosaicTable {
+            kind,
+            glyphs,
+        }
+    }
+
+    fn emoji_glyphs() -> Vec<MosaicGlyph> {
+        // The colors are the ones of the most common emoji fonts, they vary a bit between fonts
+        [
+            ('⬛', (41, 47, 51)),
+            ('⬜', (230, 231, 232)),
+            ('🟥', (221, 46, 68)),
+            ('🟧', (244, 144, 12)),
+            ('🟨', (253, 203, 88)),
+            ('🟩', (120, 177, 89)),
+            ('🟦', (85, 172, 238)),
+            ('🟪', (170, 142, 214)),
+            ('🟫', (193, 105, 79)),
+        ].into_iter()
+            .map(|(c, rgb)| MosaicGlyph::new(c, None, rgb))
+            .collect()
+    }
+
+    fn block_glyphs() -> Vec<MosaicGlyph> {
+        // Shades and how much of the cell they cover, the rest is the (assumed black) background
+        const SHADES: [(char, f32); 4] = [('█', 1.0), ('▓', 0.75), ('▒', 0.5), ('░', 0.25)];
+
+        let mut glyphs = vec![MosaicGlyph::new(' ', None, (0, 0, 0))];
+        for (color, (r, g, b)) in BASIC_COLORS.into_iter().skip(1) {
+            for (character, coverage) in SHADES {
+                let rgb = (
+                    (r as f32 * coverage) as u8,
+                    (g as f32 * coverage) as u8,
+                    (b as f32 * coverage) as u8,
+                );
+                glyphs.push(MosaicGlyph::new(character, Some(color), rgb));
+            }
+        }
+
+        glyphs
+    }
+}
+
+impl MosaicKind {
+    /// Whether the glyphs are printed in the colors of the terminal, the emojis have their own
+    pub fn uses_terminal_colors(self) -> bool {
+        self == MosaicKind::Blocks
+    }
+}
+
+impl MosaicTable {
+    pub fn kind(&self) -> MosaicKind {
+        self.kind
+    }
+
+    /// Number of terminal columns taken by each glyph of the table
+    pub fn glyph_width(&self) -> usize {
+        self.glyphs.first()
+            .and_then(|g| character_pallet::display_width(g.character))
+            .unwrap_or(1)
+    }
+
+    /// Finds the glyph of the character if it is in the table
+    pub fn glyph_for_character(&self, character: char) -> Option<&MosaicGlyph> {
+        self.glyphs.iter().find(|g| g.character == character)
+    }
+
+    /// Gives the glyph that looks the closest to the color
+    pub fn glyph_for_color(&self, red: u8, green: u8, blue: u8) -> &MosaicGlyph {
+        self.glyphs.iter()
+            .min_by(|a, b| {
+                color_distance(a.rgb, (red, green, blue))
+                    .total_cmp(&color_distance(b.rgb, (red, green, blue)))
+            })
+            .expect("Mosaic tables should not be empty")
+    }
+}
+
+/// Approximates how different two colors look, using the "redmean" weighting
+fn color_distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> f32 {
+    let red_mean = (a.0 as f32 + b.0 as f32) / 2.0;
+    let delta_red = a.0 as f32 - b.0 as f32;
+    let delta_green = a.1 as f32 - b.1 as f32;
+    let delta_blue = a.2 as f32 - b.2 as f32;
+
+    (2.0 + red_mean / 256.0) * delta_red * delta_red
+        + 4.0 * delta_green * delta_green
+        + (2.0 + (255.0 - red_mean) / 256.0) * delta_blue * delta_blue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closest_glyph_is_picked() {
+        let emoji = MosaicTable::build(MosaicKind::Emoji);
+        assert_eq!('🟥', emoji.glyph_for_color(240, 20, 30).character());
+        assert_eq!('⬛', emoji.glyph_for_color(0, 0, 0).character());
+        assert_eq!('⬜', emoji.glyph_for_color(255, 255, 255).character());
+
+        let blocks = MosaicTable::build(MosaicKind::Blocks);
+        assert_eq!(' ', blocks.glyph_for_color(0, 0, 0).character());
+        assert_eq!('█', blocks.glyph_for_color(0, 250, 5).character());
+        assert_eq!('▒', blocks.glyph_for_color(0, 128, 0).character());
+    }
+
+    #[test]
+    fn glyph_widths() {
+        assert_eq!(2, MosaicTable::build(MosaicKind::Emoji).glyph_width());
+        assert_eq!(1, MosaicTable::build(MosaicKind::Blocks).glyph_width());
+    }
+}
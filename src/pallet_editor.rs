@@ -0,0 +1,238 @@
+//! Terminal editor to build character pallets while previewing them on a frame of a video
+
+use std::error::Error;
+use std::io::{self, Write};
+
+use colored::Colorize;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, queue, terminal};
+use opencv::core::UMat;
+use opencv::videoio::{self, VideoCapture, VideoCaptureTrait, VideoCaptureTraitConst};
+
+use crate::character_pallet::{self, CharacterPallet};
+use crate::config::{Config, PALLET_FILE_PATH};
+use crate::image::Image;
+use crate::terminal_session::TerminalSession;
+use crate::VideoError;
+
+struct PalletEditor {
+    config: Config,
+    pallet: CharacterPallet,
+    cursor: usize,
+    unsaved_changes: bool,
+    quit_requested: bool,
+    message: String,
+}
+
+impl PalletEditor {
+    fn new(config: Config) -> PalletEditor {
+        let pallet = config.pallet().clone();
+
+        PalletEditor {
+            config,
+            pallet,
+            cursor: 0,
+            unsaved_changes: false,
+            quit_requested: false,
+            message: String::new(),
+        }
+    }
+}
+
+impl PalletEditor {
+    fn run(&mut self, frame: &Image, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        loop {
+            self.draw(frame, out)?;
+
+            match event::read()? {
+                // Windows also reports the releases of the keys
+                Event::Key(key) if key.kind != KeyEventKind::Release => {
+                    if !self.handle_key(key) {
+                        return Ok(());
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+
+    /// Applies the action of the key, returns false when the editor should close
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        let last_index = self.pallet.characters().len().saturating_sub(1);
+        self.message.clear();
+
+        match key.code {
+            KeyCode::Esc => return self.request_quit(),
+            // Ctrl-C does not send a signal in raw mode
+            KeyCode::Char('c') if control => return self.request_quit(),
+            KeyCode::Char('s') if control => self.save(),
+            KeyCode::Char(c) if !control && !key.modifiers.contains(KeyModifiers::ALT) => {
+                // A rejected character still cancels the request to quit, like the other keys
+                if self.pallet.accepts(c) {
+                    let index = if self.pallet.characters().is_empty() { 0 } else { self.cursor + 1 };
+                    self.pallet.insert(index, c);
+                    self.cursor = index;
+                    self.unsaved_changes = true;
+                } else {
+                    self.message = format!(
+                        "'{c}' does not take the same number of columns as the other characters",
+                    );
+                }
+            },
+            KeyCode::Backspace | KeyCode::Delete if !self.pallet.characters().is_empty() => {
+                self.pallet.remove(self.cursor);
+                self.cursor = self.cursor.min(self.pallet.characters().len().saturating_sub(1));
+                self.unsaved_changes = true;
+            },
+            KeyCode::Left if self.cursor > 0 => {
+                if shift {
+                    self.pallet.swap(self.cursor, self.cursor - 1);
+                    self.unsaved_changes = true;
+                }
+                self.cursor -= 1;
+            },
+            KeyCode::Right if self.cursor < last_index => {
+                if shift {
+                    self.pallet.swap(self.cursor, self.cursor + 1);
+                    self.unsaved_changes = true;
+                }
+                self.cursor += 1;
+            },
+            KeyCode::Up if !self.pallet.characters().is_empty() => {
+                let weight = self.pallet.weights()[self.cursor];
+                self.pallet.set_weight(self.cursor, weight + 1);
+                self.unsaved_changes = true;
+            },
+            KeyCode::Down if !self.pallet.characters().is_empty() => {
+                let weight = self.pallet.weights()[self.cursor];
+                self.pallet.set_weight(self.cursor, weight - 1);
+                self.unsaved_changes = true;
+            },
+            _ => (),
+        }
+
+        self.quit_requested = false;
+        true
+    }
+
+    /// Returns false when the editor can close, unsaved changes need a second request
+    fn request_quit(&mut self) -> bool {
+        if self.unsaved_changes && !self.quit_requested {
+            self.quit_requested = true;
+            self.message = "Unsaved changes, press esc again to quit without saving".to_string();
+            return true;
+        }
+
+        false
+    }
+
+    fn save(&mut self) {
+        match character_pallet::save_pallet_to_file(PALLET_FILE_PATH, &self.pallet) {
+            Ok(()) => {
+                self.unsaved_changes = false;
+                self.message = format!("Saved '{}' to {PALLET_FILE_PATH}", self.pallet.name);
+            },
+            Err(e) => self.message = format!("Error while saving: {e}"),
+        }
+    }
+
+    fn draw(&mut self, frame: &Image, out: &mut impl Write) -> io::Result<()> {
+        self.config.set_pallet(self.pallet.clone());
+
+        queue!(out, cursor::MoveTo(0, 0), terminal::Clear(terminal::ClearType::All))?;
+
+        // In raw mode a new line does not bring the cursor back to the start of the line
+        let preview = frame.render(&self.config).replace('\n', "\r\n");
+        write!(out, "{preview}\r\n")?;
+
+        let characters = self.pallet.characters().iter().enumerate().map(|(i, c)| {
+            if i == self.cursor {
+                c.to_string().reversed().to_string()
+            } else {
+                c.to_string()
+            }
+        }).collect::<String>();
+        write!(out, "Pallet '{}' (emptiest to densest): {characters}\r\n", self.pallet.name)?;
+
+        if let Some(character) = self.pallet.characters().get(self.cursor) {
+            write!(
+                out,
+                "Character {}/{} '{character}' with a weight of {}\r\n",
+                self.cursor + 1,
+                self.pallet.characters().len(),
+                self.pallet.weights()[self.cursor],
+            )?;
+        } else {
+            write!(out, "The pallet is empty, type a character to add it\r\n")?;
+        }
+
+        write!(
+            out,
+            "[type] insert  [backspace] remove  [left/right] select  [shift+left/right] move  \
+            [up/down] weight  [ctrl+s] save  [esc] quit\r\n",
+        )?;
+        write!(out, "{}", self.message)?;
+
+        out.flush()
+    }
+}
+
+/// Reads a frame from the middle of the video, the first frames are often black
+fn read_still_frame(path: &str) -> Result<Image, VideoError> {
+    let mut capture = match VideoCapture::from_file(path, videoio::CAP_ANY) {
+        Ok(c) => c,
+        Err(e) => return Err(VideoError::OpenCvError(e)),
+    };
+
+    let frame_count = match capture.get(videoio::CAP_PROP_FRAME_COUNT) {
+        Ok(c) => c,
+        Err(e) => return Err(VideoError::OpenCvError(e)),
+    };
+    if let Err(e) = capture.set(videoio::CAP_PROP_POS_FRAMES, (frame_count / 2.0).floor()) {
+        return Err(VideoError::OpenCvError(e));
+    }
+
+    let mut buffer = UMat::new(opencv::core::UMatUsageFlags::USAGE_DEFAULT);
+    match capture.read(&mut buffer) {
+        Ok(true) => Ok(Image::new(buffer)),
+        Ok(false) => Err(VideoError::NoStream),
+        Err(e) => Err(VideoError::OpenCvError(e)),
+    }
+}
+
+/// Opens the editor on the pallet of the config, previewing it on a frame of the video in the query
+pub fn edit_pallet(config: Config) -> Result<(), Box<dyn Error>> {
+    let frame = read_still_frame(config.query())?;
+    let mut editor = PalletEditor::new(config);
+
+    // The terminal is restored when the session is dropped, even if the editor failed
+    let _session = TerminalSession::enter()?;
+    terminal::enable_raw_mode()?;
+
+    editor.run(&frame, &mut io::stdout())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::default_config_with_width;
+
+    fn press(editor: &mut PalletEditor, code: KeyCode) -> bool {
+        editor.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    #[test]
+    fn quitting_with_unsaved_changes_asks_again_after_a_rejected_character() {
+        let config = default_config_with_width(10);
+        let mut editor = PalletEditor::new(config);
+
+        assert!(press(&mut editor, KeyCode::Char('x')));
+        assert!(press(&mut editor, KeyCode::Esc));
+        // Too wide for the pallet
+        assert!(press(&mut editor, KeyCode::Char('🟥')));
+        assert!(press(&mut editor, KeyCode::Esc));
+        assert!(!press(&mut editor, KeyCode::Esc));
+    }
+}
@@ -0,0 +1,197 @@
+//! Lowers the rendering quality while the frames can't be rendered or printed in time,
+//! and raises it again once there is headroom
+//!
+//! The player measures how long printing each frame takes and the renderer how long rendering
+//! takes, the slowest of the two is compared to the time there is for each frame.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Config;
+
+/// How the frames are scaled down to the rendered size, from the fastest to the best looking
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Nearest,
+    Linear,
+    Lanczos,
+}
+
+/// Settings of a quality level, relative to the ones the video was started with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QualityLevel {
+    width_factor: f64,
+    fps_factor: f64,
+    interpolation: Interpolation,
+}
+
+/// From the best quality to the fastest one
+pub const QUALITY_LEVELS: [QualityLevel; 5] = [
+    QualityLevel { width_factor: 1.0, fps_factor: 1.0, interpolation: Interpolation::Lanczos },
+    QualityLevel { width_factor: 1.0, fps_factor: 1.0, interpolation: Interpolation::Linear },
+    QualityLevel { width_factor: 0.75, fps_factor: 1.0, interpolation: Interpolation::Linear },
+    QualityLevel { width_factor: 0.75, fps_factor: 0.5, interpolation: Interpolation::Linear },
+    QualityLevel { width_factor: 0.5, fps_factor: 0.5, interpolation: Interpolation::Nearest },
+];
+
+/// Share of the time of a frame that can be used before the quality is lowered
+const LOWER_ABOVE_LOAD: f64 = 0.9;
+/// Share of the time of a frame under which the quality is raised
+const RAISE_BELOW_LOAD: f64 = 0.45;
+/// Frames to wait after a change before measuring again, the frames rendered ahead need to be played
+const SETTLE_FRAMES: usize = 48;
+/// Weight of each new measure in the average load
+const LOAD_SMOOTHING: f64 = 0.1;
+
+impl QualityLevel {
+    pub fn fps_factor(&self) -> f64 {
+        self.fps_factor
+    }
+
+    /// The config of the video with the settings of the level
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        config.set_width(((config.width() as f64 * self.width_factor) as u32).max(1));
+        config.set_interpolation(self.interpolation);
+
+        config
+    }
+
+    /// Short description of what was lowered
+    pub fn describe(&self) -> String {
+        format!(
+            "width {:.0}%, {:.0}% of the frames, {:?} scaling",
+            self.width_factor * 100.0,
+            self.fps_factor * 100.0,
+            self.interpolation,
+        )
+    }
+}
+
+/// The quality level and render time, shared by the player and the renderer
+#[derive(Debug, Default)]
+pub struct QualityState {
+    level: AtomicUsize,
+    render_micros: AtomicU64, // Time taken to render a frame, on average
+}
+
+impl QualityState {
+    /// Index of the level in `QUALITY_LEVELS`
+    pub fn level(&self) -> usize {
+        self.level.load(Ordering::Relaxed)
+    }
+
+    /// Saves how long the last frames took to render
+    pub fn record_render_time(&self, render_time: Duration) {
+        self.render_micros.store(render_time.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render_time(&self) -> Duration {
+        Duration::from_micros(self.render_micros.load(Ordering::Relaxed))
+    }
+}
+
+/// Picks the quality level from the time taken by each frame
+pub struct QualityController {
+    state: Arc<QualityState>,
+    fps: f64, // Frame rate at the best quality
+    load: f64, // Average share of the time of a frame that is used
+    frames_since_change: usize,
+}
+
+impl QualityController {
+    pub fn new(fps: f64) -> QualityController {
+        QualityController {
+            state: Arc::new(QualityState::default()),
+            fps,
+            load: 0.0,
+            frames_since_change: 0,
+        }
+    }
+}
+
+impl QualityController {
+    /// The state to give to the renderer
+    pub fn state(&self) -> Arc<QualityState> {
+        Arc::clone(&self.state)
+    }
+
+    pub fn level(&self) -> usize {
+        self.state.level()
+    }
+
+    pub fn current_level(&self) -> &QualityLevel {
+        &QUALITY_LEVELS[self.level()]
+    }
+
+    /// Measures the frame that was just printed, gives whether the level changed
+    pub fn record_frame(&mut self, output_time: Duration) -> bool {
+        self.frames_since_change += 1;
+
+        let frame_time = 1.0 / (self.fps * self.current_level().fps_factor);
+        let used_time = output_time.max(self.state.render_time()).as_secs_f64();
+        self.load += (used_time / frame_time - self.load) * LOAD_SMOOTHING;
+
+        if self.frames_since_change < SETTLE_FRAMES {
+            return false;
+        }
+
+        let level = self.level();
+        let new_level = if self.load > LOWER_ABOVE_LOAD {
+            (level + 1).min(QUALITY_LEVELS.len() - 1)
+        } else if self.load < RAISE_BELOW_LOAD {
+            level.saturating_sub(1)
+        } else {
+            level
+        };
+        if new_level == level {
+            return false;
+        }
+
+        self.state.level.store(new_level, Ordering::Relaxed);
+        self.frames_since_change = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::default_config_with_width;
+
+    #[test]
+    fn quality_is_lowered_then_raised() {
+        let mut controller = QualityController::new(10.0);
+
+        // Printing takes more than the 100ms of a frame
+        for _ in 0..SETTLE_FRAMES * 3 {
+            controller.record_frame(Duration::from_millis(150));
+        }
+        assert!(controller.level() >= 2, "level is {}", controller.level());
+
+        // Rendering is slow too
+        controller.state().record_render_time(Duration::from_millis(500));
+        for _ in 0..SETTLE_FRAMES * 10 {
+            controller.record_frame(Duration::from_millis(10));
+        }
+        assert_eq!(QUALITY_LEVELS.len() - 1, controller.level());
+
+        // There is headroom again
+        controller.state().record_render_time(Duration::from_millis(5));
+        for _ in 0..SETTLE_FRAMES * 10 {
+            controller.record_frame(Duration::from_millis(5));
+        }
+        assert_eq!(0, controller.level());
+    }
+
+    #[test]
+    fn levels_lower_the_config() {
+        let config = default_config_with_width(100);
+
+        let lowered = QUALITY_LEVELS[4].apply(&config);
+        assert_eq!(50, lowered.width());
+        assert_eq!(Interpolation::Nearest, lowered.interpolation());
+        assert_eq!(QUALITY_LEVELS[0].apply(&config).width(), config.width());
+    }
+}
@@ -0,0 +1,253 @@
+//! Draws rendered frames as pixels, to export them as images or videos
+//!
+//! The glyphs come from a bundled 8x16 bitmap font in the unifont `.hex` format: one glyph per
+//! line, the code point then the 16 rows of 8 pixels as hexadecimal. The glyphs were rasterized
+//! from DejaVu Sans Mono, under the license in `assets/LICENSE-DejaVu`. The braille patterns and
+//! block elements are drawn exactly.
+
+use std::collections::HashMap;
+
+use crate::cells::{Cell, CellGrid};
+use crate::character_pallet::CharacterPallet;
+use crate::config::Config;
+use crate::mosaic::{self, MosaicKind, MosaicTable};
+
+pub const GLYPH_WIDTH: usize = 8;
+pub const GLYPH_HEIGHT: usize = 16;
+
+const BUNDLED_FONT: &str = include_str!("../assets/font-8x16.hex");
+/// Color of the glyphs that have no color
+const DEFAULT_COLOR: (u8, u8, u8) = (229, 229, 229);
+
+/// Glyphs of a monospace font, each row of a glyph is a byte with the leftmost pixel as the highest bit
+pub struct BitmapFont {
+    glyphs: HashMap<char, [u8; GLYPH_HEIGHT]>,
+}
+
+impl BitmapFont {
+    pub fn bundled() -> BitmapFont {
+        BitmapFont::parse(BUNDLED_FONT)
+    }
+
+    /// Reads a font in the `.hex` format, invalid lines are skipped
+    pub fn parse(hex: &str) -> BitmapFont {
+        let mut glyphs = HashMap::new();
+        for line in hex.lines() {
+            let Some((code_point, rows)) = line.trim().split_once(':') else {
+                continue;
+            };
+            let Some(character) = u32::from_str_radix(code_point, 16).ok().and_then(char::from_u32) else {
+                continue;
+            };
+            if rows.len() != GLYPH_HEIGHT * 2 {
+                continue;
+            }
+
+            let mut glyph = [0; GLYPH_HEIGHT];
+            let parsed = glyph.iter_mut().enumerate().all(|(i, row)| {
+                match u8::from_str_radix(&rows[i * 2..i * 2 + 2], 16) {
+                    Ok(r) => { *row = r; true },
+                    Err(_) => false,
+                }
+            });
+            if parsed {
+                glyphs.insert(character, glyph);
+            }
+        }
+
+        BitmapFont {
+            glyphs,
+        }
+    }
+}
+
+impl BitmapFont {
+    /// Gives the glyph of the character, fullwidth forms use the glyph of their ascii character
+    pub fn glyph(&self, character: char) -> Option<&[u8; GLYPH_HEIGHT]> {
+        if let Some(g) = self.glyphs.get(&character) {
+            return Some(g);
+        }
+
+        let ascii = match character {
+            '\u{3000}' => ' ',
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(character as u32 - 0xFF01 + 0x21)?,
+            _ => return None,
+        };
+        self.glyphs.get(&ascii)
+    }
+}
+
+/// An image with 3 bytes per pixel, row by row
+pub struct RgbImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl RgbImage {
+    fn new(width: usize, height: usize) -> RgbImage {
+        RgbImage {
+            width,
+            height,
+            pixels: vec![0; width * height * 3],
+        }
+    }
+}
+
+impl RgbImage {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The red, green and blue bytes of the pixels
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let i = (y * self.width + x) * 3;
+        (self.pixels[i], self.pixels[i + 1], self.pixels[i + 2])
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, (r, g, b): (u8, u8, u8)) {
+        let i = (y * self.width + x) * 3;
+        self.pixels[i..i + 3].copy_from_slice(&[r, g, b]);
+    }
+}
+
+/// Draws the cells of the frames like a terminal with a black background would
+pub struct Rasterizer {
+    font: BitmapFont,
+    pallet: CharacterPallet,
+    emoji: MosaicTable,
+    columns_per_cell: usize,
+}
+
+impl Rasterizer {
+    pub fn new(config: &Config) -> Rasterizer {
+        Rasterizer {
+            font: BitmapFont::bundled(),
+            pallet: config.pallet().clone(),
+            emoji: MosaicTable::build(MosaicKind::Emoji),
+            columns_per_cell: config.glyph_width(),
+        }
+    }
+}
+
+impl Rasterizer {
+    /// Size in pixels of the image of the frame
+    pub fn image_size(&self, grid: &CellGrid) -> (usize, usize) {
+        (grid.width() * self.cell_width(), grid.height() * GLYPH_HEIGHT)
+    }
+
+    pub fn rasterize(&self, grid: &CellGrid) -> RgbImage {
+        let (width, height) = self.image_size(grid);
+        let mut image = RgbImage::new(width, height);
+
+        for (y, row) in grid.rows().enumerate() {
+            for (x, cell) in row.iter().enumerate() {
+                self.draw_cell(&mut image, x * self.cell_width(), y * GLYPH_HEIGHT, cell);
+            }
+        }
+
+        image
+    }
+
+    fn cell_width(&self) -> usize {
+        GLYPH_WIDTH * self.columns_per_cell
+    }
+
+    fn draw_cell(&self, image: &mut RgbImage, left: usize, top: usize, cell: &Cell) {
+        let cell_width = self.cell_width();
+
+        // Emojis are squares of a single color
+        if let Some(glyph) = self.emoji.glyph_for_character(cell.character) {
+            for y in 1..GLYPH_HEIGHT - 1 {
+                for x in 1..cell_width - 1 {
+                    image.set_pixel(left + x, top + y, glyph.rgb());
+                }
+            }
+            return;
+        }
+
+        let color = cell.color.map_or(DEFAULT_COLOR, mosaic::color_rgb);
+        let glyph = self.font.glyph(cell.character);
+        let fallback_coverage = self.fallback_coverage(cell.character);
+
+        for y in 0..GLYPH_HEIGHT {
+            for x in 0..cell_width {
+                let is_lit = match glyph {
+                    // Wide glyphs are stretched over the columns of the cell
+                    Some(g) => g[y] & (0x80 >> (x / self.columns_per_cell)) != 0,
+                    None => dither_threshold(x, y) < fallback_coverage,
+                };
+
+                if is_lit {
+                    image.set_pixel(left + x, top + y, color);
+                }
+            }
+        }
+    }
+
+    /// How much of the cell is covered by a character missing from the font,
+    /// it goes by the place of the character in the pallet
+    fn fallback_coverage(&self, character: char) -> f32 {
+        let characters = self.pallet.characters();
+        match characters.iter().position(|c| *c == character) {
+            Some(i) => (i + 1) as f32 / characters.len() as f32,
+            None => 0.5,
+        }
+    }
+}
+
+/// Threshold of the 4x4 ordered dithering matrix at the pixel, between 0 and 1
+fn dither_threshold(x: usize, y: usize) -> f32 {
+    const BAYER: [[u8; 4]; 4] = [
+        [0, 8, 2, 10],
+        [12, 4, 14, 6],
+        [3, 11, 1, 9],
+        [15, 7, 13, 5],
+    ];
+
+    (BAYER[y % 4][x % 4] as f32 + 0.5) / 16.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use colored::Color;
+    use crate::test_fixtures::default_config_with_width;
+
+    #[test]
+    fn bundled_font_has_the_pallet_characters() {
+        let font = BitmapFont::bundled();
+        for c in ' '..='~' {
+            assert!(font.glyph(c).is_some(), "{c} is missing");
+        }
+        assert!(font.glyph('⣿').is_some());
+        assert!(font.glyph('▒').is_some());
+        assert_eq!(font.glyph('＠'), font.glyph('@'));
+        assert_eq!(Some(&[0xFF; GLYPH_HEIGHT]), font.glyph('█'));
+    }
+
+    #[test]
+    fn cells_are_drawn_in_their_color() {
+        let config = default_config_with_width(2);
+        let rasterizer = Rasterizer::new(&config);
+        let grid = CellGrid::from_rows(vec![vec![
+            Cell::new('█', Some(Color::TrueColor { r: 10, g: 20, b: 30 })),
+            Cell::new(' ', None),
+            Cell::new('█', None),
+        ]]);
+
+        let image = rasterizer.rasterize(&grid);
+        assert_eq!((24, 16), (image.width(), image.height()));
+        assert_eq!((10, 20, 30), image.pixel(3, 5));
+        assert_eq!((0, 0, 0), image.pixel(11, 5));
+        assert_eq!(DEFAULT_COLOR, image.pixel(20, 15));
+    }
+}
@@ -0,0 +1,282 @@
+//! Exports the frames drawn as pixels, to watch the rendered video without a terminal
+
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use gif::{Encoder, Frame, Repeat};
+use opencv::core::{Mat, MatTraitManual, Scalar, Size, CV_8UC3};
+use opencv::videoio::{VideoWriter, VideoWriterTrait, VideoWriterTraitConst};
+
+use crate::cells::CellGrid;
+use crate::config::Config;
+use crate::raster::{Rasterizer, RgbImage};
+use crate::workspace;
+
+pub const GIF_EXTENSION: &str = "gif";
+pub const MP4_EXTENSION: &str = "mp4";
+
+/// The video is written to this file of the session's workspace before the audio is added
+const MP4_VIDEO_FILE_NAME: &str = "export-video.mp4";
+
+/// Speed of the color quantization of the gif frames, from 1 (best) to 30 (fastest)
+const GIF_QUANTIZATION_SPEED: i32 = 10;
+
+#[derive(Debug)]
+pub enum RenderedVideoError {
+    IoError(io::Error),
+    GifError(gif::EncodingError),
+    OpenCvError(opencv::Error),
+    FrameTooBigError(usize, usize),
+    VideoWriterError(PathBuf),
+}
+
+impl Display for RenderedVideoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenderedVideoError::IoError(e) => write!(f, "{}", e),
+            RenderedVideoError::GifError(e) => write!(f, "{}", e),
+            RenderedVideoError::OpenCvError(e) => write!(f, "{}", e),
+            RenderedVideoError::FrameTooBigError(w, h) => write!(
+                f,
+                "The frames are too big to be exported ({w}x{h} pixels), lower the width",
+            ),
+            RenderedVideoError::VideoWriterError(p) => write!(
+                f,
+                "Could not write a video to {}",
+                p.display(),
+            ),
+        }
+    }
+}
+
+impl Error for RenderedVideoError {}
+
+impl From<io::Error> for RenderedVideoError {
+    fn from(e: io::Error) -> Self {
+        RenderedVideoError::IoError(e)
+    }
+}
+
+impl From<gif::EncodingError> for RenderedVideoError {
+    fn from(e: gif::EncodingError) -> Self {
+        RenderedVideoError::GifError(e)
+    }
+}
+
+impl From<opencv::Error> for RenderedVideoError {
+    fn from(e: opencv::Error) -> Self {
+        RenderedVideoError::OpenCvError(e)
+    }
+}
+
+/// Writes the frames as an animated gif, looping forever
+pub struct GifWriter {
+    path: PathBuf,
+    encoder: Option<Encoder<BufWriter<File>>>,
+    rasterizer: Rasterizer,
+    fps: f64,
+    nb_frames: u32,
+}
+
+impl GifWriter {
+    /// The file is only created with the first frame since it needs the size of the frames
+    pub fn create(path: &Path, config: &Config, fps: f64) -> GifWriter {
+        GifWriter {
+            path: path.to_path_buf(),
+            encoder: None,
+            rasterizer: Rasterizer::new(config),
+            fps,
+            nb_frames: 0,
+        }
+    }
+}
+
+impl GifWriter {
+    pub fn push(&mut self, grid: &CellGrid) -> Result<(), RenderedVideoError> {
+        let image = self.rasterizer.rasterize(grid);
+        let (width, height) = gif_size(&image)?;
+
+        let encoder = match &mut self.encoder {
+            Some(e) => e,
+            None => {
+                let file = BufWriter::new(File::create(&self.path)?);
+                let mut encoder = Encoder::new(file, width, height, &[])?;
+                encoder.set_repeat(Repeat::Infinite)?;
+                self.encoder.insert(encoder)
+            },
+        };
+
+        let mut frame = Frame::from_rgb_speed(width, height, image.pixels(), GIF_QUANTIZATION_SPEED);
+        frame.delay = gif_delay(self.nb_frames, self.fps);
+        encoder.write_frame(&frame)?;
+        self.nb_frames += 1;
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), RenderedVideoError> {
+        // The trailer of the file is written when the encoder is dropped
+        drop(self.encoder);
+        Ok(())
+    }
+}
+
+fn gif_size(image: &RgbImage) -> Result<(u16, u16), RenderedVideoError> {
+    match (u16::try_from(image.width()), u16::try_from(image.height())) {
+        (Ok(w), Ok(h)) => Ok((w, h)),
+        _ => Err(RenderedVideoError::FrameTooBigError(image.width(), image.height())),
+    }
+}
+
+/// Delay after the frame in hundredths of a second, the rounding errors do not add up over the frames
+fn gif_delay(frame: u32, fps: f64) -> u16 {
+    let time = |frame: u32| (frame as f64 * 100.0 / fps).round() as u64;
+    u16::try_from(time(frame + 1) - time(frame)).unwrap_or(u16::MAX)
+}
+
+/// Writes the frames as a mp4 video, with the audio of the source video if FFMPEG is installed
+pub struct Mp4Writer {
+    path: PathBuf,
+    temp_path: PathBuf, // Where the video is written before the audio is added
+    writer: Option<VideoWriter>,
+    rasterizer: Rasterizer,
+    fps: f64,
+    source_path: String,
+    config: Config,
+}
+
+impl Mp4Writer {
+    /// The file is only created with the first frame since it needs the size of the frames
+    pub fn create(path: &Path, source_path: &str, config: &Config, fps: f64) -> Result<Mp4Writer, RenderedVideoError> {
+        Ok(Mp4Writer {
+            path: path.to_path_buf(),
+            temp_path: workspace::temp_path(MP4_VIDEO_FILE_NAME)?,
+            writer: None,
+            rasterizer: Rasterizer::new(config),
+            fps,
+            source_path: source_path.to_string(),
+            config: config.clone(),
+        })
+    }
+}
+
+impl Mp4Writer {
+    pub fn push(&mut self, grid: &CellGrid) -> Result<(), RenderedVideoError> {
+        let image = self.rasterizer.rasterize(grid);
+        let (width, height) = (image.width() as i32, image.height() as i32);
+
+        let writer = match &mut self.writer {
+            Some(w) => w,
+            None => {
+                let fourcc = VideoWriter::fourcc('m', 'p', '4', 'v')?;
+                let temp_path = self.temp_path.to_string_lossy();
+                let writer = VideoWriter::new(&temp_path, fourcc, self.fps, Size::new(width, height), true)?;
+                if !writer.is_opened()? {
+                    return Err(RenderedVideoError::VideoWriterError(self.temp_path.clone()));
+                }
+                self.writer.insert(writer)
+            },
+        };
+
+        // OpenCV wants the pixels as blue, green, red
+        let mut frame = Mat::new_rows_cols_with_default(height, width, CV_8UC3, Scalar::all(0.0))?;
+        for (bgr, rgb) in frame.data_bytes_mut()?.chunks_exact_mut(3).zip(image.pixels().chunks_exact(3)) {
+            bgr.copy_from_slice(&[rgb[2], rgb[1], rgb[0]]);
+        }
+        writer.write(&frame)?;
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), RenderedVideoError> {
+        let Some(mut writer) = self.writer else {
+            return Ok(());
+        };
+        writer.release()?;
+
+        match self.add_audio() {
+            Ok(true) => {
+                fs::remove_file(&self.temp_path)?;
+                return Ok(());
+            },
+            Ok(false) => eprintln!("FFMPEG could not add the audio, the video is exported without it"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => eprintln!("FFMPEG is not installed, the video is exported without audio"),
+            Err(e) => eprintln!("FFMPEG could not be run ({e}), the video is exported without audio"),
+        }
+
+        // The workspace may not be on the same file system so the video is copied
+        fs::copy(&self.temp_path, &self.path)?;
+        fs::remove_file(&self.temp_path)?;
+
+        Ok(())
+    }
+
+    /// Copies the video with the played part of the source's audio to the path,
+    /// whether FFMPEG succeeded
+    fn add_audio(&self) -> io::Result<bool> {
+        let start = self.config.start();
+
+        let mut command = Command::new("ffmpeg");
+        command.arg("-y");
+        command.arg("-i").arg(workspace::command_path(&self.temp_path));
+        command.args(["-ss", &format!("{:.3}", start.as_secs_f64())]);
+        if let Some(end) = self.config.end() {
+            command.args(["-t", &format!("{:.3}", (end - start).as_secs_f64())]);
+        }
+        command.arg("-i").arg(workspace::command_path(Path::new(&self.source_path)));
+        command.args(["-map", "0:v", "-map", &format!("1:a:{}", self.config.audio_track()), "-c:v", "copy", "-c:a", "aac", "-shortest"]);
+        command.arg(workspace::command_path(&self.path));
+
+        Ok(command.output()?.status.success())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{default_config_with_width, filled_grid};
+
+    #[test]
+    fn gif_delays_do_not_drift() {
+        assert_eq!(10, gif_delay(0, 10.0));
+        let total: u32 = (0..15).map(|f| gif_delay(f, 15.0) as u32).sum();
+        assert_eq!(100, total);
+        assert!((0..15).all(|f| (6..=7).contains(&gif_delay(f, 15.0))));
+    }
+
+    #[test]
+    fn gif_delays_after_655_seconds() {
+        // 655.36 seconds is where the times in hundredths of a second no longer fit in a u16
+        let frame = 15 * 700;
+        assert!((6..=7).contains(&gif_delay(frame, 15.0)));
+        let total: u32 = (frame..frame + 15).map(|f| gif_delay(f, 15.0) as u32).sum();
+        assert_eq!(100, total);
+    }
+
+    #[test]
+    fn exporting_gif() {
+        let config = default_config_with_width(3);
+        let path = std::env::temp_dir().join("cli-player-test.gif");
+        let grid = |c| filled_grid(c, 3, 2);
+
+        let mut writer = GifWriter::create(&path, &config, 10.0);
+        writer.push(&grid('@')).unwrap();
+        writer.push(&grid('.')).unwrap();
+        writer.finish().unwrap();
+
+        let mut decoder = gif::DecodeOptions::new().read_info(File::open(&path).unwrap()).unwrap();
+        assert_eq!((24, 32), (decoder.width(), decoder.height()));
+        let mut nb_frames = 0;
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(10, frame.delay);
+            nb_frames += 1;
+        }
+        assert_eq!(2, nb_frames);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+//! Takes over the terminal while something is drawn in it: the alternate screen is used so that
+//! the scrollback is left as it was, and the cursor is hidden. The terminal is restored when the
+//! session ends, and also on panics and signals through `restore`.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crossterm::{cursor, execute, queue, style, terminal};
+
+/// Whether a session took over the terminal and it needs to be restored
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The terminal is restored when it is dropped
+pub struct TerminalSession {
+    _private: (), // Only `enter` makes sessions
+}
+
+impl TerminalSession {
+    /// Switches to the alternate screen and hides the cursor
+    pub fn enter() -> io::Result<TerminalSession> {
+        ACTIVE.store(true, Ordering::SeqCst);
+
+        if let Err(e) = execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide) {
+            restore();
+            return Err(e);
+        }
+
+        Ok(TerminalSession { _private: () })
+    }
+}
+
+impl TerminalSession {
+    /// Draws the text over what was drawn before. The terminals supporting synchronized updates
+    /// (DEC mode 2026) show it all at once, so the frames don't tear.
+    pub fn draw(&self, text: &str) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+
+        queue!(stdout, terminal::BeginSynchronizedUpdate, cursor::MoveTo(0, 0))?;
+        stdout.write_all(text.as_bytes())?;
+        // What was drawn before can be longer
+        queue!(stdout, terminal::Clear(terminal::ClearType::FromCursorDown), terminal::EndSynchronizedUpdate)?;
+
+        stdout.flush()
+    }
+}
+
+impl Drop for TerminalSession {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Puts the terminal back as it was before the session, the raw mode is left too.
+/// It does nothing when no session took over the terminal.
+pub fn restore() {
+    if !ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    let _ = terminal::disable_raw_mode();
+    // The output may have stopped in the middle of a frame, with a color set
+    let _ = execute!(
+        io::stdout(),
+        style::ResetColor,
+        terminal::EndSynchronizedUpdate,
+        cursor::Show,
+        terminal::LeaveAlternateScreen,
+    );
+}
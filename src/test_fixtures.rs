@@ -0,0 +1,38 @@
+//! Configs and frames shared by the tests of the modules
+
+use colored::Color;
+
+use crate::cells::{Cell, CellGrid};
+use crate::config::Config;
+
+/// Config rendering the test video like it is played, 50 characters wide at 15 frames per second
+pub fn default_config_streamed() -> Config {
+    Config::build("video.mp4".to_string(), "ascii".to_string(), 50, 15, 1.0, true, false)
+        .unwrap()
+}
+
+/// Config rendering frames `width` characters wide in color, without a video
+pub fn default_config_with_width(width: u32) -> Config {
+    Config::build("".to_string(), "ascii".to_string(), width, 0, 1.0, true, false)
+        .unwrap()
+}
+
+/// A grid of one character without color
+pub fn filled_grid(character: char, width: usize, height: usize) -> CellGrid {
+    CellGrid::new(width, height, vec![Cell::new(character, None); width * height])
+}
+
+/// A different grid for each frame, only a small part of it changes from one frame to the next
+pub fn test_grid(frame: usize) -> CellGrid {
+    let cells = (0..(40 * 20)).map(|i| {
+        if i % 50 == frame % 50 {
+            Cell::new('@', Some(Color::TrueColor { r: frame as u8, g: 10, b: 200 }))
+        } else if i % 3 == 0 {
+            Cell::new('.', Some(Color::Red))
+        } else {
+            Cell::new(' ', None)
+        }
+    }).collect();
+
+    CellGrid::new(40, 20, cells)
+}
@@ -0,0 +1,257 @@
+//! Rendered videos saved in a single file, they can be played without OpenCV or FFMPEG
+//!
+//! A text video (`.ctv`) is a frame file whose header has the settings the video was rendered
+//! with, its frame rates, where it starts in the original video and its audio track.
+
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cells::CellGrid;
+use crate::frame_file::{self, FrameFileReader, FrameFileWriter};
+
+pub const TEXT_VIDEO_EXTENSION: &str = "ctv";
+const MAGIC: &[u8] = b"CLI-PLAYER-CTV-1";
+const AUDIO_COMPRESSION_LEVEL: i32 = 9;
+
+#[derive(Debug)]
+pub enum TextVideoError {
+    IoError(io::Error),
+    InvalidFileError(PathBuf),
+}
+
+impl Display for TextVideoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextVideoError::IoError(e) => write!(f, "{}", e),
+            TextVideoError::InvalidFileError(p) => write!(
+                f,
+                "{} is not a valid text video",
+                p.display(),
+            ),
+        }
+    }
+}
+
+impl Error for TextVideoError {}
+
+impl From<io::Error> for TextVideoError {
+    fn from(e: io::Error) -> Self {
+        TextVideoError::IoError(e)
+    }
+}
+
+/// Whether the file at the path is a text video, going by its extension
+pub fn is_text_video(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|e| e.eq_ignore_ascii_case(TEXT_VIDEO_EXTENSION))
+}
+
+/// Describes a text video
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextVideoHeader {
+    settings: String,
+    native_fps: f64,
+    fps: u32,
+    start: Duration,
+    audio: Vec<u8>, // WAV file of the audio track
+}
+
+impl TextVideoHeader {
+    pub fn new(settings: String, native_fps: f64, fps: u32, start: Duration, audio: Vec<u8>) -> TextVideoHeader {
+        TextVideoHeader {
+            settings,
+            native_fps,
+            fps,
+            start,
+            audio,
+        }
+    }
+
+    fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = MAGIC.to_vec();
+        frame_file::write_string(&mut bytes, &self.settings);
+        bytes.extend(self.native_fps.to_le_bytes());
+        bytes.extend(self.fps.to_le_bytes());
+        bytes.extend((self.start.as_micros() as u64).to_le_bytes());
+
+        let audio = zstd::stream::encode_all(self.audio.as_slice(), AUDIO_COMPRESSION_LEVEL)?;
+        bytes.extend((audio.len() as u64).to_le_bytes());
+        bytes.extend(audio);
+
+        Ok(bytes)
+    }
+
+    /// Reads the header, gives its length in bytes along with it
+    fn read(file: &mut impl Read) -> Option<(TextVideoHeader, u64)> {
+        let mut magic = vec![0; MAGIC.len()];
+        file.read_exact(&mut magic).ok()?;
+        if magic != MAGIC {
+            return None;
+        }
+
+        let (settings, settings_length) = frame_file::read_string(file)?;
+
+        let mut native_fps = [0; 8];
+        file.read_exact(&mut native_fps).ok()?;
+        let mut fps = [0; 4];
+        file.read_exact(&mut fps).ok()?;
+        let mut start = [0; 8];
+        file.read_exact(&mut start).ok()?;
+
+        let mut audio_length = [0; 8];
+        file.read_exact(&mut audio_length).ok()?;
+        let audio_length = u64::from_le_bytes(audio_length);
+        let mut audio = Vec::new();
+        file.take(audio_length).read_to_end(&mut audio).ok()?;
+        if audio.len() as u64 != audio_length {
+            return None;
+        }
+        let audio = zstd::stream::decode_all(audio.as_slice()).ok()?;
+
+        let header = TextVideoHeader {
+            settings,
+            native_fps: f64::from_le_bytes(native_fps),
+            fps: u32::from_le_bytes(fps),
+            start: Duration::from_micros(u64::from_le_bytes(start)),
+            audio,
+        };
+        let length = (MAGIC.len() + settings_length + 8 + 4 + 8 + 8) as u64 + audio_length;
+
+        Some((header, length))
+    }
+}
+
+impl TextVideoHeader {
+    /// The rendering settings of the video
+    pub fn settings(&self) -> &str {
+        &self.settings
+    }
+
+    /// Frame rate of the original video, used to turn frame indices into timestamps
+    pub fn native_fps(&self) -> f64 {
+        self.native_fps
+    }
+
+    /// Frame rate at which the frames are played
+    pub fn fps(&self) -> u32 {
+        self.fps
+    }
+
+    /// Timestamp in the original video where the text video starts
+    pub fn start(&self) -> Duration {
+        self.start
+    }
+
+    /// The audio track as a WAV file, starting at `start`
+    pub fn audio(&self) -> &[u8] {
+        &self.audio
+    }
+}
+
+/// Writes the frames of a text video as they are rendered
+pub struct TextVideoWriter {
+    frames: FrameFileWriter,
+}
+
+impl TextVideoWriter {
+    pub fn create(path: &Path, header: &TextVideoHeader) -> Result<TextVideoWriter, TextVideoError> {
+        let frames = FrameFileWriter::create(File::create(path)?, &header.to_bytes()?)?;
+
+        Ok(TextVideoWriter {
+            frames,
+        })
+    }
+}
+
+impl TextVideoWriter {
+    /// Adds the frame shown at the index and timestamp in the original video after the others
+    pub fn push(&mut self, index: usize, timestamp: Duration, grid: CellGrid) -> Result<(), TextVideoError> {
+        Ok(self.frames.push(index, timestamp, grid)?)
+    }
+
+    pub fn finish(self) -> Result<(), TextVideoError> {
+        Ok(self.frames.finish()?)
+    }
+}
+
+/// A text video opened for playing
+pub struct TextVideo {
+    header: TextVideoHeader,
+    frames: FrameFileReader,
+}
+
+impl TextVideo {
+    pub fn open(path: &str) -> Result<TextVideo, TextVideoError> {
+        let invalid = || TextVideoError::InvalidFileError(PathBuf::from(path));
+
+        let mut file = BufReader::new(File::open(path)?);
+        let (header, header_length) = TextVideoHeader::read(&mut file).ok_or_else(invalid)?;
+        let frames = FrameFileReader::read(file.into_inner(), header_length)
+            .map_err(|_| invalid())?;
+
+        Ok(TextVideo {
+            header,
+            frames,
+        })
+    }
+}
+
+impl TextVideo {
+    pub fn header(&self) -> &TextVideoHeader {
+        &self.header
+    }
+
+    pub fn into_frames(self) -> FrameFileReader {
+        self.frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::test_fixtures::filled_grid;
+
+    #[test]
+    fn text_videos_are_read_back() {
+        let path = std::env::temp_dir().join("cli-player-test.ctv");
+        let header = TextVideoHeader::new(
+            "width: 2".to_string(),
+            29.97,
+            15,
+            Duration::from_millis(1500),
+            b"RIFF fake audio".to_vec(),
+        );
+        let grid = |c| filled_grid(c, 2, 1);
+
+        let mut writer = TextVideoWriter::create(&path, &header).unwrap();
+        writer.push(45, Duration::from_millis(1501), grid('a')).unwrap();
+        writer.push(47, Duration::from_millis(1568), grid('b')).unwrap();
+        writer.finish().unwrap();
+
+        let text_video = TextVideo::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(&header, text_video.header());
+
+        let mut frames = text_video.into_frames();
+        assert_eq!(Some((45, grid('a'))), frames.next_frame());
+        assert_eq!(Some(Duration::from_millis(1568)), frames.next_timestamp());
+        assert_eq!(Some((47, grid('b'))), frames.next_frame());
+        assert_eq!(None, frames.next_frame());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(matches!(
+            TextVideo::open("test-assets/video.mp4"),
+            Err(TextVideoError::InvalidFileError(_)),
+        ));
+        assert!(is_text_video("some/video.ctv"));
+        assert!(is_text_video("some/VIDEO.CTV"));
+        assert!(!is_text_video("some/video.mp4"));
+    }
+}
@@ -0,0 +1,335 @@
+//! Plays audio faster or slower without changing its pitch
+//!
+//! The audio is cut in overlapping segments that are put back together closer or further apart
+//! (WSOLA). Each segment is picked near where it should be, at the offset that best continues the
+//! previous one, so that the waves line up and no echo or crackle is heard.
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+
+/// Slowest speed the audio and frames can be played at
+pub const MIN_SPEED: f64 = 0.25;
+/// Fastest speed the audio and frames can be played at
+pub const MAX_SPEED: f64 = 4.0;
+
+/// Length of the segments, long enough to hold a few periods of low voices
+const SEGMENT_DURATION: Duration = Duration::from_millis(40);
+/// How far from where it should be a segment can be picked
+const SEARCH_RANGE: Duration = Duration::from_millis(10);
+/// Only one sample in this many is compared when looking for the best segment
+const SEARCH_STRIDE: usize = 2;
+
+/// The speed of the audio, shared with the sources being played
+#[derive(Debug)]
+pub struct SpeedControl {
+    speed: AtomicU32, // The bits of the f32 speed
+}
+
+impl Default for SpeedControl {
+    fn default() -> SpeedControl {
+        SpeedControl { speed: AtomicU32::new(1.0f32.to_bits()) }
+    }
+}
+
+impl SpeedControl {
+    pub fn speed(&self) -> f64 {
+        f32::from_bits(self.speed.load(Ordering::Relaxed)) as f64
+    }
+
+    /// Sets the speed, it is kept between `MIN_SPEED` and `MAX_SPEED`
+    pub fn set_speed(&self, speed: f64) {
+        let speed = speed.clamp(MIN_SPEED, MAX_SPEED) as f32;
+        self.speed.store(speed.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Plays the source at the speed of the control, the speed can change while it is played
+pub struct TimeStretch<S> {
+    source: S,
+    control: Arc<SpeedControl>,
+    channels: usize,
+    sample_rate: u32,
+    hop: usize, // Frames between the starts of two segments in the output, half a segment
+    search_range: usize, // In frames
+    window: Vec<f32>, // Weight of each frame of a segment, the overlapping halves add up to 1
+    input: Vec<f32>, // Interleaved samples of the source that can still be used
+    input_length: Option<usize>, // Frames of the source in the input once it ended, the rest is silence
+    next_position: f64, // Frame of the input where the next segment should start
+    previous_end: Option<usize>, // Frame of the input following the first half of the last segment
+    overlap: Option<Vec<f32>>, // The weighted second half of the last segment
+    output: VecDeque<f32>,
+    finished: bool,
+}
+
+impl<S: Source<Item = f32>> TimeStretch<S> {
+    pub fn new(source: S, control: Arc<SpeedControl>) -> TimeStretch<S> {
+        let channels = source.channels().max(1) as usize;
+        let sample_rate = source.sample_rate();
+        let hop = ((SEGMENT_DURATION.as_secs_f64() * sample_rate as f64) as usize / 2).max(1);
+
+        // Squared sine, the halves of two segments overlapping by half add up to 1
+        let window = (0..hop * 2)
+            .map(|i| (PI * i as f32 / (hop * 2) as f32).sin().powi(2))
+            .collect();
+
+        TimeStretch {
+            source,
+            control,
+            channels,
+            sample_rate,
+            hop,
+            search_range: (SEARCH_RANGE.as_secs_f64() * sample_rate as f64) as usize,
+            window,
+            input: Vec::new(),
+            input_length: None,
+            next_position: 0.0,
+            previous_end: None,
+            overlap: None,
+            output: VecDeque::new(),
+            finished: false,
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> TimeStretch<S> {
+    /// Reads the source until the input has this many frames, silence is added once it ended
+    fn fill_input(&mut self, frames: usize) {
+        while self.input.len() < frames * self.channels {
+            if self.input_length.is_none() {
+                match self.source.next() {
+                    Some(sample) => {
+                        self.input.push(sample);
+                        continue;
+                    },
+                    None => self.input_length = Some(self.input.len() / self.channels),
+                }
+            }
+            self.input.resize(frames * self.channels, 0.0);
+        }
+    }
+
+    /// Puts the next segment in the output, gives false once the whole source was played
+    fn add_segment(&mut self) -> bool {
+        let speed = self.control.speed();
+        let segment_length = self.hop * 2;
+
+        // At the normal speed the segments follow each other, the audio is left untouched
+        let (search_start, search_end) = match self.previous_end {
+            Some(end) if speed == 1.0 => {
+                self.next_position = end as f64;
+                (end, end)
+            },
+            Some(_) => {
+                let position = self.next_position.round() as usize;
+                (position.saturating_sub(self.search_range), position + self.search_range)
+            },
+            None => (0, 0),
+        };
+
+        self.fill_input(search_end + segment_length);
+        if self.input_length.is_some_and(|l| self.next_position >= l as f64) {
+            return false;
+        }
+
+        let start = match self.previous_end {
+            Some(end) if search_end > search_start => self.best_segment(search_start, search_end, end),
+            _ => search_start,
+        };
+
+        let channels = self.channels;
+        let segment = &self.input[start * channels..(start + segment_length) * channels];
+        let (first_half, second_half) = segment.split_at(self.hop * channels);
+        match &self.overlap {
+            Some(overlap) => {
+                for (i, (sample, previous)) in first_half.iter().zip(overlap).enumerate() {
+                    self.output.push_back(previous + sample * self.window[i / channels]);
+                }
+            },
+            // Nothing comes before the first segment
+            None => self.output.extend(first_half),
+        }
+        self.overlap = Some(second_half.iter()
+            .enumerate()
+            .map(|(i, sample)| sample * self.window[self.hop + i / channels])
+            .collect());
+
+        self.previous_end = Some(start + self.hop);
+        self.next_position += self.hop as f64 * speed;
+        self.drop_used_input();
+
+        true
+    }
+
+    /// Start of the segment between `search_start` and `search_end` that best continues the
+    /// audio following the last segment, which starts at `previous_end`
+    fn best_segment(&self, search_start: usize, search_end: usize, previous_end: usize) -> usize {
+        let mono = |frame: usize| -> f32 {
+            self.input[frame * self.channels..(frame + 1) * self.channels].iter().sum()
+        };
+
+        let mut best = (search_start, f32::MIN);
+        for start in (search_start..=search_end).step_by(SEARCH_STRIDE) {
+            let (mut correlation, mut energy) = (0.0, 0.0);
+            for i in (0..self.hop).step_by(SEARCH_STRIDE) {
+                let sample = mono(start + i);
+                correlation += sample * mono(previous_end + i);
+                energy += sample * sample;
+            }
+
+            // Normalized so that loud segments are not picked only for being loud
+            let score = correlation / energy.sqrt().max(f32::EPSILON);
+            if score > best.1 {
+                best = (start, score);
+            }
+        }
+
+        best.0
+    }
+
+    /// Removes the frames that no segment can start at anymore
+    fn drop_used_input(&mut self) {
+        let Some(previous_end) = self.previous_end else {
+            return;
+        };
+        let used = previous_end.min((self.next_position as usize).saturating_sub(self.search_range));
+
+        self.input.drain(..used * self.channels);
+        self.previous_end = Some(previous_end - used);
+        self.next_position -= used as f64;
+        self.input_length = self.input_length.map(|l| l.saturating_sub(used));
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for TimeStretch<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        loop {
+            if let Some(sample) = self.output.pop_front() {
+                return Some(sample);
+            }
+            if self.finished {
+                return None;
+            }
+
+            if !self.add_segment() {
+                // The end of the last segment fades out
+                self.output.extend(self.overlap.take().unwrap_or_default());
+                self.finished = true;
+            }
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Source for TimeStretch<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// Samples in mono
+    struct TestSource {
+        samples: std::vec::IntoIter<f32>,
+    }
+
+    impl Iterator for TestSource {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            self.samples.next()
+        }
+    }
+
+    impl Source for TestSource {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            SAMPLE_RATE
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    fn stretch(samples: Vec<f32>, speed: f64) -> Vec<f32> {
+        let control = Arc::new(SpeedControl::default());
+        control.set_speed(speed);
+        TimeStretch::new(TestSource { samples: samples.into_iter() }, control).collect()
+    }
+
+    fn sine(frequency: f32, seconds: f32) -> Vec<f32> {
+        (0..(seconds * SAMPLE_RATE as f32) as usize)
+            .map(|i| 0.5 * (2.0 * PI * frequency * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    /// Frequency of a sine from the number of times it crosses zero
+    fn frequency(samples: &[f32]) -> f32 {
+        let crossings = samples.windows(2).filter(|w| (w[0] < 0.0) != (w[1] < 0.0)).count();
+        crossings as f32 / 2.0 / (samples.len() as f32 / SAMPLE_RATE as f32)
+    }
+
+    #[test]
+    fn normal_speed_keeps_the_audio() {
+        let samples = sine(440.0, 1.0);
+        let stretched = stretch(samples.clone(), 1.0);
+
+        assert!(stretched.len() >= samples.len());
+        for (a, b) in samples.iter().zip(&stretched) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn speed_changes_the_length_and_not_the_pitch() {
+        for speed in [MIN_SPEED, 0.5, 1.5, 2.0, MAX_SPEED] {
+            let stretched = stretch(sine(440.0, 4.0), speed);
+
+            let expected_length = 4.0 * SAMPLE_RATE as f64 / speed;
+            let length_error = (stretched.len() as f64 - expected_length).abs() / expected_length;
+            assert!(length_error < 0.03, "{} samples at speed {speed}", stretched.len());
+
+            let frequency = frequency(&stretched[..stretched.len() * 9 / 10]);
+            assert!((frequency - 440.0).abs() < 440.0 * 0.02, "frequency {frequency} at speed {speed}");
+        }
+    }
+
+    #[test]
+    fn speed_is_kept_in_range() {
+        let control = SpeedControl::default();
+        control.set_speed(10.0);
+        assert_eq!(MAX_SPEED, control.speed());
+        control.set_speed(0.0);
+        assert_eq!(MIN_SPEED, control.speed());
+    }
+}
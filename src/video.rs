@@ -3,15 +3,22 @@
 use std::error::Error;
 use std::fmt::{Display, Debug};
 use std::io;
-use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use rustube::Video as YtVideo;
 use rustube::url::Url;
 
+use crate::ansi_dump::{self, AnsiDump};
+use crate::audio_decoder::{self, AudioDecodeError, AudioInput, AudioTrack};
 use crate::audio_manager::AudioManager;
 use crate::config::Config;
 use crate::image::ImageAsString;
 use crate::frames::FramesManager;
+use crate::quality::QualityController;
+use crate::text_video::{self, TextVideo, TextVideoError};
+use crate::time_stretch::{MAX_SPEED, MIN_SPEED};
+use crate::visualizer;
 
 #[derive(Debug)]
 pub enum VideoError {
@@ -20,7 +27,9 @@ pub enum VideoError {
     UrlParseError(rustube::url::ParseError),
     IoError(io::Error),
     RodioError(RodioError),
+    AudioDecodeError(AudioDecodeError),
     FfmpegError(io::Error),
+    TextVideoError(TextVideoError),
     NoStream,
 }
 
@@ -33,7 +42,9 @@ impl Display for VideoError {
             VideoError::UrlParseError(e) => write!(f, "{}", e),
             VideoError::IoError(e) => write!(f, "{}", e),
             VideoError::RodioError(e) => write!(f, "{}", e),
+            VideoError::AudioDecodeError(e) => write!(f, "{}", e),
             VideoError::FfmpegError(e) => write!(f, "{}", e),
+            VideoError::TextVideoError(e) => write!(f, "{}", e),
             VideoError::NoStream => write!(
                 f,
                 "No stream of the video could be found",
@@ -46,7 +57,6 @@ impl Error for VideoError {}
 
 #[derive(Debug)]
 pub enum RodioError {
-    DecoderError(rodio::decoder::DecoderError),
     PlayError(rodio::PlayError),
     StreamError(rodio::StreamError),
 }
@@ -54,7 +64,6 @@ pub enum RodioError {
 impl Display for RodioError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RodioError::DecoderError(e) => write!(f, "{}", e),
             RodioError::PlayError(e) => write!(f, "{}", e),
             RodioError::StreamError(e) => write!(f, "{}", e),
         }
@@ -66,104 +75,390 @@ impl Error for RodioError {}
 /// Contains the data of the video and is responsible for downloading
 pub struct Video {
     frames: FramesManager,
-    audio_source_path: String,
+    audio_source: Option<AudioInput>,
+    audio_source_start: Duration, // Timestamp in the video where the audio source starts
     audio_player: AudioManager,
+    audio_started: bool,
+    clock: Option<(Instant, Duration)>, // When the playback position was last known and what it was
+    speed: f64,
+    paused: bool,
     _current_frame: usize,
 }
 
 impl Video {
-    fn new(frames: FramesManager, audio_source_path: String, audio_player: AudioManager) -> Video {
+    fn new(frames: FramesManager, audio_source: Option<AudioInput>, audio_source_start: Duration, audio_player: AudioManager) -> Video {
         Video {
             frames,
-            audio_source_path,
+            audio_source,
+            audio_source_start,
             audio_player,
+            audio_started: false,
+            clock: None,
+            speed: 1.0,
+            paused: false,
             _current_frame: 0,
         }
     }
 
     /// Downloaded the video to ./downloaded-videos/ and collects all the frames
     pub async fn build_from_url(url: &str, config: &Config) -> Result<Video, VideoError> {
-        let url = match Url::parse(url) {
-            Ok(u) => u,
-            Err(e) => return Err(VideoError::UrlParseError(e)),
-        };
-        let yt_video = match YtVideo::from_url(&url).await {
+        let video_path = download_video(url).await?;
+
+        let video = Video::build_from_path(&video_path, config)?;
+        Ok(video)
+    }
+
+    /// Collects all the frames from the video specified at the path
+    pub fn build_from_path(path: &str, config: &Config) -> Result<Video, VideoError> {
+        if text_video::is_text_video(path) {
+            return Video::build_from_text_video(path, config);
+        }
+        if ansi_dump::is_ansi_dump(path) {
+            return Video::build_from_ansi_dump(path, config);
+        }
+        if visualizer::is_audio_file(path) {
+            return Video::build_from_audio(path, config);
+        }
+
+        let frames = FramesManager::build(path, config)?;
+        let mut audio_player = AudioManager::build(config.audio_output())?;
+
+        // The audio track is decoded from the video while playing, it has the same timestamps.
+        // Videos without audio that can be decoded are played without sound.
+        let audio_source = AudioInput::Path(path.to_string());
+        check_audio_track(&audio_source, config.audio_track())?;
+        audio_player.set_track(config.audio_track());
+        let video = Video::new(frames, Some(audio_source), Duration::ZERO, audio_player);
+        Ok(video)
+    }
+
+    /// Reads the frames and audio of a text video, nothing needs to be decoded or rendered
+    fn build_from_text_video(path: &str, config: &Config) -> Result<Video, VideoError> {
+        let text_video = match TextVideo::open(path) {
             Ok(v) => v,
-            Err(e) => return Err(VideoError::RustubeError(e)),
+            Err(e) => return Err(VideoError::TextVideoError(e)),
         };
+        let header = text_video.header();
+        let (native_fps, fps, start) = (header.native_fps(), header.fps(), header.start());
 
-        let stream = match yt_video.best_quality() {
-            Some(s) => s,
-            None => return Err(VideoError::NoStream),
+        // The audio is kept as it was stored, a WAV file starting at the start of the range.
+        // It only has the track that was picked when exporting.
+        let audio_source = if header.audio().is_empty() {
+            None
+        } else {
+            Some(AudioInput::Bytes(Arc::from(header.audio())))
         };
 
-        let video_path = match stream.download_to_dir("./downloaded-videos").await {
-            Ok(p) => p.to_string_lossy().to_string(),
-            Err(e) => return Err(VideoError::RustubeError(e)),
-        };
+        let frames = FramesManager::build_from_frame_file(text_video.into_frames(), native_fps, fps as f64, config);
+        let audio_player = AudioManager::build(config.audio_output())?;
 
-        let video = Video::build_from_path(&video_path, config)?;
+        let video = Video::new(frames, audio_source, start, audio_player);
         Ok(video)
     }
 
-    /// Collects all the frames from the video specified at the path
-    pub fn build_from_path(path: &str, config: &Config) -> Result<Video, VideoError> {
-        const TEMP_AUDIO_PATH: &str = "./temp_audio.wav";
-        
-        let frames = FramesManager::build(path, config)?;
+    /// Plays an audio file with a visualizer instead of the frames of a video
+    fn build_from_audio(path: &str, config: &Config) -> Result<Video, VideoError> {
+        let audio_source = AudioInput::Path(path.to_string());
+        let frames = FramesManager::build_from_audio(&audio_source, config)?;
+        let mut audio_player = AudioManager::build(config.audio_output())?;
+        check_audio_track(&audio_source, config.audio_track())?;
+        audio_player.set_track(config.audio_track());
 
-        // Seperates audio from video
-        match std::fs::remove_file(TEMP_AUDIO_PATH) {
-            Ok(()) => (),
-            // It makes an error when there is no file to delete which is normal
-            Err(_) => (),
-        }
+        let video = Video::new(frames, Some(audio_source), Duration::ZERO, audio_player);
+        Ok(video)
+    }
 
-        let command_result = Command::new("ffmpeg")
-            .args([
-                "-i",
-                path,
-                "-vn",
-                TEMP_AUDIO_PATH,
-                ]).output();
-                
-        match command_result {
-            Ok(_) => (),
-            // We don't really care if the audio is invalid, it will get caught later and there will
-            // just be no audio
-            Err(_) => eprintln!("Error while using FFMPEG, is it installed?"),
-            //Err(e) => return Err(VideoError::FfmpegError(e)),
-        }
+    /// Plays the frames of an ANSI dump at the frame limit if one was given, otherwise at the
+    /// frame rate of its timing headers, dumps have no audio
+    fn build_from_ansi_dump(path: &str, config: &Config) -> Result<Video, VideoError> {
+        let dump = match AnsiDump::open(path) {
+            Ok(d) => d,
+            Err(e) => return Err(VideoError::IoError(e)),
+        };
+        let fps = dump.fps(config.given_frame_limit());
 
-        let audio_player = AudioManager::build()?;
+        let frames = FramesManager::build_from_text_frames(dump.into_images(), fps);
+        let audio_player = AudioManager::build(config.audio_output())?;
 
-        let video = Video::new(frames, TEMP_AUDIO_PATH.to_string(), audio_player);
+        // There is no audio so the audio never starts
+        let video = Video::new(frames, None, Duration::ZERO, audio_player);
         Ok(video)
     }
 }
 
+/// Checks that the input has the audio track asked for on the command line. The first track is
+/// not checked, without it the video is played without sound.
+fn check_audio_track(input: &AudioInput, track: usize) -> Result<(), VideoError> {
+    if track == 0 {
+        return Ok(());
+    }
+
+    let count = match audio_decoder::audio_tracks(input) {
+        Ok(t) => t.len(),
+        Err(e) => return Err(VideoError::AudioDecodeError(e)),
+    };
+    if track >= count {
+        return Err(VideoError::AudioDecodeError(AudioDecodeError::TrackDoesNotExist(track, count)));
+    }
+
+    Ok(())
+}
+
+/// Downloads the video to ./downloaded-videos/, gives the path of the downloaded file
+pub async fn download_video(url: &str) -> Result<String, VideoError> {
+    let url = match Url::parse(url) {
+        Ok(u) => u,
+        Err(e) => return Err(VideoError::UrlParseError(e)),
+    };
+    let yt_video = match YtVideo::from_url(&url).await {
+        Ok(v) => v,
+        Err(e) => return Err(VideoError::RustubeError(e)),
+    };
+
+    let stream = match yt_video.best_quality() {
+        Some(s) => s,
+        None => return Err(VideoError::NoStream),
+    };
+
+    match stream.download_to_dir("./downloaded-videos").await {
+        Ok(p) => Ok(p.to_string_lossy().to_string()),
+        Err(e) => Err(VideoError::RustubeError(e)),
+    }
+}
+
 impl Video {
-    pub fn next_frame(&mut self) -> Option<Box<dyn ImageAsString>> {
+    /// Gives the next frame with its presentation timestamp in the video
+    pub fn next_frame(&mut self) -> Option<(Duration, Box<dyn ImageAsString>)> {
         self.frames.next_frame()
     }
 
-    pub fn next_frame_string(&mut self, config: &Config) -> Option<String> {
-        let next_frame = self.next_frame()?;
-        Some(next_frame.as_string(config))
+    pub fn next_frame_string(&mut self, config: &Config) -> Option<(Duration, String)> {
+        let (timestamp, next_frame) = self.next_frame()?;
+        Some((timestamp, next_frame.as_string(config)))
     }
 
-    /// Gives the fps of the video
-    pub fn fps(&self) -> u32 {
+    /// Gives the fps the frames are shown at, it is only the average for variable frame rates
+    pub fn fps(&self) -> f64 {
         self.frames.fps()
     }
 
-    pub fn start_audio(&self) -> Result<(), VideoError> {
-        self.audio_player.play_from_path(&self.audio_source_path)
+    /// Position in the video of the next frame
+    pub fn position(&self) -> Duration {
+        self.frames.position()
+    }
+
+    /// Moves the frames and, if it was started, the audio to the position
+    pub fn seek(&mut self, position: Duration) -> Result<(), VideoError> {
+        self.frames.seek(position)?;
+
+        if self.clock.is_some() {
+            self.clock = Some((Instant::now(), position));
+        }
+        if let (true, Some(audio_source)) = (self.audio_started, &self.audio_source) {
+            let audio_position = self.audio_position();
+            self.audio_player.play_at(audio_source, audio_position)?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts the audio at the current position of the frames, the playback clock starts
+    /// even if the audio can't be played
+    pub fn start_audio(&mut self) -> Result<(), VideoError> {
+        self.clock = Some((Instant::now(), self.frames.position()));
+
+        let audio_source = match &self.audio_source {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        let audio_position = self.audio_position();
+        self.audio_player.play_at(audio_source, audio_position)?;
+        self.audio_started = true;
+
+        Ok(())
+    }
+
+    /// Position in the video that is being played. The audio is the master clock, without audio
+    /// the time is counted from the last known position.
+    pub fn playback_position(&mut self) -> Duration {
+        // The clock is stopped while paused
+        if let (true, Some((_, position))) = (self.paused, self.clock) {
+            return position;
+        }
+
+        let audio_position = if self.audio_started {
+            self.audio_player.position()
+        } else {
+            None
+        };
+
+        match (audio_position, self.clock) {
+            (Some(p), _) => {
+                let position = p + self.audio_source_start;
+                self.clock = Some((Instant::now(), position));
+                position
+            },
+            (None, Some((instant, position))) => position + instant.elapsed().mul_f64(self.speed),
+            // The playback did not start
+            (None, None) => self.frames.position(),
+        }
+    }
+
+    /// Position in the audio source matching the position of the frames
+    fn audio_position(&self) -> Duration {
+        self.frames.position().saturating_sub(self.audio_source_start)
+    }
+
+    /// The audio tracks that can be switched to, they are empty when there is no audio
+    pub fn audio_tracks(&self) -> Vec<AudioTrack> {
+        match &self.audio_source {
+            Some(a) => audio_decoder::audio_tracks(a).unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Index of the audio track that is played
+    pub fn audio_track(&self) -> usize {
+        self.audio_player.track()
+    }
+
+    /// Plays another audio track from the current position, without stopping the frames
+    pub fn switch_audio_track(&mut self, track: usize) -> Result<(), VideoError> {
+        match &self.audio_source {
+            Some(audio_source) => self.audio_player.switch_track(audio_source, track),
+            None => Ok(()),
+        }
+    }
+
+    /// The controller of the rendering quality, if the quality can be lowered
+    pub fn quality_mut(&mut self) -> Option<&mut QualityController> {
+        self.frames.quality_mut()
+    }
+
+    /// How fast the video is played, 1.0 is the normal speed
+    pub fn speed(&self) -> f64 {
+        self.speed
     }
 
+    /// Plays the frames and audio faster or slower, the speed is kept between `MIN_SPEED`
+    /// and `MAX_SPEED`. The audio keeps its pitch.
+    pub fn set_speed(&mut self, speed: f64) {
+        // The time counted at the previous speed is kept
+        let position = self.playback_position();
+        if self.clock.is_some() {
+            self.clock = Some((Instant::now(), position));
+        }
+
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        self.frames.set_speed(self.speed);
+        self.audio_player.set_speed(self.speed);
+    }
+
+    /// Sets the volume, the audio goes to it smoothly
     pub fn set_volume(&self, volume: f32) {
         self.audio_player.set_volume(volume)
     }
+
+    /// Brings the audio to the same loudness as other videos, once it is measured
+    pub fn normalize_loudness(&mut self) {
+        if let Some(audio_source) = &self.audio_source {
+            self.audio_player.normalize_loudness(audio_source);
+        }
+    }
+
+    /// Stops the playback position, the audio fades out
+    pub fn pause(&mut self) {
+        if self.paused {
+            return;
+        }
+
+        let position = self.playback_position();
+        if self.clock.is_some() {
+            self.clock = Some((Instant::now(), position));
+        }
+        self.audio_player.pause();
+        self.paused = true;
+    }
+
+    /// Plays again from where it was paused, the audio fades in
+    pub fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+
+        self.clock = self.clock.map(|(_, position)| (Instant::now(), position));
+        self.audio_player.resume();
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Moves the playback position of the paused video to a frame that is shown,
+    /// the audio then resumes from that frame
+    pub fn step_to(&mut self, timestamp: Duration) -> Result<(), VideoError> {
+        if !self.paused {
+            return Ok(());
+        }
+
+        if self.clock.is_some() {
+            self.clock = Some((Instant::now(), timestamp));
+        }
+        if let (true, Some(audio_source)) = (self.audio_started, &self.audio_source) {
+            let audio_position = timestamp.saturating_sub(self.audio_source_start);
+            self.audio_player.play_at(audio_source, audio_position)?;
+        }
+
+        Ok(())
+    }
+
+    /// Renders the frames from the position with the config, it gives false when the frames
+    /// were rendered before playing and can't change
+    pub fn set_render_config(&mut self, config: &Config, position: Duration) -> Result<bool, VideoError> {
+        self.frames.set_config(config, position)
+    }
+
+    /// Fades out and stops the audio
+    pub fn stop_audio(&mut self) {
+        self.audio_player.stop();
+        self.audio_started = false;
+    }
 }
 
-// TODO: Add tests
\ No newline at end of file
+// TODO: Add tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_config() -> Config {
+        Config::build("video.mp4".to_string(), "ascii".to_string(), 50, 15, 1.0, true, false)
+            .unwrap()
+    }
+
+    #[test]
+    fn videos_without_audio_are_played_without_sound() {
+        let mut video = Video::build_from_path("./test-assets/video-no-audio.mp4", &default_config()).unwrap();
+
+        // The player ignores this error, the video is played silently
+        assert!(matches!(video.start_audio(), Err(VideoError::AudioDecodeError(AudioDecodeError::NoAudioTrack))));
+        assert!(video.next_frame().is_some());
+
+        // Without audio the playback position is counted from when the video started
+        let start = video.playback_position();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(video.playback_position() > start);
+    }
+
+    #[test]
+    fn missing_audio_tracks_are_reported() {
+        let mut config = default_config();
+        config.set_audio_track(1);
+
+        assert!(matches!(
+            Video::build_from_path("./test-assets/video.mp4", &config),
+            Err(VideoError::AudioDecodeError(AudioDecodeError::TrackDoesNotExist(1, 1))),
+        ));
+    }
+}
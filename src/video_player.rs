@@ -1,61 +1,373 @@
 use std::error::Error;
-use std::fmt::Display;
+use std::io::{self, IsTerminal};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
-use crate::video::Video;
+use crate::audio_decoder::AudioDecodeError;
+use crate::character_pallet::{self, CharacterPallet};
+use crate::config::{Config, PALLET_FILE_PATH};
+use crate::controls::{Controls, PlayerCommand};
+use crate::quality::QUALITY_LEVELS;
+use crate::terminal_session::TerminalSession;
+use crate::video::{Video, VideoError};
 
-#[derive(Debug)]
-pub enum VideoPlayerError {
-    TooMuchLag,
+/// Longest wait before checking the playback position again, in case the audio moves
+const MAX_WAIT: Duration = Duration::from_millis(50);
+/// How long the message of a control stays under the frames
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+/// Loudest volume the controls go up to
+const MAX_VOLUME: f32 = 2.0;
+
+/// Shows each frame when the playback position reaches its timestamp, the audio is the clock.
+/// Frames that are late are dropped and frames that are early leave the previous one on screen
+/// longer, so the frames always catch up with the audio, even when it is played faster or slower.
+/// With adaptive quality the quality level is shown under the frames while it is lowered.
+/// The keys pressed meanwhile control the playback, when they can be read from the terminal.
+/// In a terminal the frames are drawn in place on the alternate screen, which is left at the end.
+pub async fn play_video(video: Video, config: &Config) -> Result<(), Box<dyn Error>> {
+    // The frames are printed one after the other when the output is a file or a pipe
+    let session = if io::stdout().is_terminal() {
+        Some(TerminalSession::enter()?)
+    } else {
+        None
+    };
+
+    let mut player = Player {
+        video,
+        config: config.clone(),
+        volume: config.volume(),
+        muted: false,
+        message: None,
+        last_frame: None,
+        raw_mode: false,
+        show_next: false,
+        session,
+    };
+    player.run();
+
+    Ok(())
+}
+
+/// What the player does after a command
+enum Flow {
+    /// Keeps waiting for the frame to be due
+    Wait,
+    /// Shows the frame now
+    Show,
+    /// Drops the frame, the next frames replace it
+    Skip,
+    Quit,
+}
+
+struct Player {
+    video: Video,
+    config: Config, // The frames are rendered with it, the controls change its color and pallet
+    volume: f32,
+    muted: bool,
+    message: Option<(String, Instant)>, // Says what the last control did
+    last_frame: Option<String>, // Shown again when something changes while paused
+    raw_mode: bool, // The lines also need a carriage return
+    show_next: bool, // Shows the next frame even if the video is paused
+    session: Option<TerminalSession>, // The frames are drawn over each other
 }
 
-impl Display for VideoPlayerError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            VideoPlayerError::TooMuchLag => write!(f, "Frames take too much time to render"),
+impl Player {
+    fn run(&mut self) {
+        // Starts the audio
+        // The audio will stop when the program stops or when it has no more audio
+        self.video.set_speed(self.config.speed());
+        self.video.set_volume(self.volume);
+        if self.config.normalize_loudness() {
+            self.video.normalize_loudness();
         }
+        // The video is played without controls when no terminal can be read, like in a pipeline
+        let controls = Controls::start().ok();
+        self.raw_mode = controls.is_some();
+        match self.video.start_audio() {
+            // Videos without audio are played silently
+            Ok(()) | Err(VideoError::AudioDecodeError(AudioDecodeError::NoAudioTrack)) => (),
+            Err(e) => self.notify(format!("The audio can't be played: {e}")),
+        }
+
+        'frames: while let Some((timestamp, frame)) = self.video.next_frame_string(&self.config) {
+            // Time in the video between two frames shown, it is longer when played faster
+            let frame_duration = Duration::from_secs_f64(self.video.speed() / self.video.fps());
+            let mut show_now = std::mem::take(&mut self.show_next);
+
+            // The next frame is already due
+            if !show_now && !self.video.is_paused() && self.frame_position() > timestamp + frame_duration {
+                continue;
+            }
+
+            loop {
+                let mut redraw = false;
+                while let Some(command) = controls.as_ref().and_then(Controls::next_command) {
+                    match self.handle(command, timestamp) {
+                        Flow::Wait => redraw = true,
+                        Flow::Show => show_now = true,
+                        Flow::Skip => continue 'frames,
+                        Flow::Quit => break 'frames,
+                    }
+                }
+
+                if show_now {
+                    break;
+                }
+                if self.video.is_paused() {
+                    // The frame on screen shows what changed
+                    if redraw {
+                        self.redraw();
+                    }
+                    thread::sleep(MAX_WAIT);
+                    continue;
+                }
+
+                let position = self.frame_position();
+                if position >= timestamp {
+                    break;
+                }
+                thread::sleep((timestamp - position).div_f64(self.video.speed()).min(MAX_WAIT));
+            }
+
+            // The audio resumes from the frames shown while paused
+            if let Err(e) = self.video.step_to(timestamp) {
+                self.notify(format!("The audio could not follow the frame: {e}"));
+            }
+            self.show(frame);
+        }
+
+        // The audio is faded out when the frames end first, like with --end
+        self.video.stop_audio();
     }
-}
 
-impl Error for VideoPlayerError {}
+    /// Applies the command, `timestamp` is the one of the frame waiting to be shown
+    fn handle(&mut self, command: PlayerCommand, timestamp: Duration) -> Flow {
+        match command {
+            PlayerCommand::TogglePause if self.video.is_paused() => self.video.resume(),
+            PlayerCommand::TogglePause => self.video.pause(),
+
+            PlayerCommand::Seek(seconds) => {
+                let position = self.video.playback_position();
+                let offset = Duration::from_secs(seconds.unsigned_abs());
+                let target = if seconds >= 0 {
+                    position + offset
+                } else {
+                    position.saturating_sub(offset).max(self.config.start())
+                };
 
-pub async fn play_video(mut video: Video, config: &Config) -> Result<(), Box<dyn Error>> {
-    let fps = video.fps();
+                if let Err(e) = self.video.seek(target) {
+                    self.notify(format!("Could not seek: {e}"));
+                    return Flow::Wait;
+                }
+                self.notify(format!("Position {}", format_position(target)));
+                // The frame at the new position is shown even when paused
+                self.show_next = true;
+                return Flow::Skip;
+            },
+
+            PlayerCommand::StepFrame => {
+                self.video.pause();
+                return Flow::Show;
+            },
+
+            PlayerCommand::ChangeVolume(step) => {
+                // A volume set louder on the command line is only lowered
+                if step < 0.0 || self.volume < MAX_VOLUME {
+                    self.volume = (self.volume + step).clamp(0.0, MAX_VOLUME.max(self.volume));
+                }
+                self.muted = false;
+                self.video.set_volume(self.volume);
+                self.notify(format!("Volume {:.0}%", self.volume * 100.0));
+            },
 
-    // Starts the audio
-    // The audio will stop when the program stops or when it has no more audio
-    video.set_volume(config.volume());
-    let _ = video.start_audio();
+            PlayerCommand::ToggleMute => {
+                self.muted = !self.muted;
+                if self.muted {
+                    self.video.set_volume(0.0);
+                    self.notify("Muted".to_string());
+                } else {
+                    self.video.set_volume(self.volume);
+                    self.notify(format!("Volume {:.0}%", self.volume * 100.0));
+                }
+            },
 
-    let mut lag_count: u32 = 0;
-    loop  {
-        let start = SystemTime::now();
+            PlayerCommand::ChangeSpeed(step) => {
+                self.video.set_speed(self.video.speed() + step);
+                self.notify(format!("Speed {:.2}x", self.video.speed()));
+            },
 
-        let frame = match video.next_frame_string(config) {
-            Some(f) => f,
-            None => break,
-        };
+            PlayerCommand::ToggleColor => {
+                let mut config = self.config.clone();
+                config.set_color(!config.color());
+                let message = if config.color() { "Color on" } else { "Color off" };
+                return self.render_with(config, timestamp, message.to_string());
+            },
+
+            PlayerCommand::NextPallet => {
+                let pallet = match next_pallet(&self.config.pallet().name) {
+                    Some(p) => p,
+                    None => {
+                        self.notify("There is no other pallet".to_string());
+                        return Flow::Wait;
+                    },
+                };
+                let message = format!("Pallet {}", pallet.name);
+                let mut config = self.config.clone();
+                config.set_pallet(pallet);
+                return self.render_with(config, timestamp, message);
+            },
 
-        
-        println!("{}", frame);
+            PlayerCommand::NextAudioTrack => {
+                let tracks = self.video.audio_tracks();
+                if tracks.len() < 2 {
+                    self.notify("There is no other audio track".to_string());
+                    return Flow::Wait;
+                }
 
-        let render_time = start.elapsed()?;
-        let delta_time = match (Duration::from_secs(1) / fps).checked_sub(render_time) {
-            Some(d) => d,
-            None => {
-                lag_count += 4;
-                Duration::from_secs(0)
+                let track = &tracks[(self.video.audio_track() + 1) % tracks.len()];
+                match self.video.switch_audio_track(track.index()) {
+                    Ok(()) => self.notify(format!("Audio track {track}")),
+                    Err(e) => self.notify(format!("Could not switch the audio track: {e}")),
+                }
             },
-        };
 
-        if lag_count >= 25 {
-            return Err(Box::new(VideoPlayerError::TooMuchLag));
+            PlayerCommand::Quit => return Flow::Quit,
+        }
+
+        Flow::Wait
+    }
+
+    /// Renders the frames from the one waiting to be shown with the config, if they can still be rendered
+    fn render_with(&mut self, config: Config, timestamp: Duration, message: String) -> Flow {
+        match self.video.set_render_config(&config, timestamp) {
+            Ok(true) => (),
+            Ok(false) => {
+                self.notify("The frames were rendered before playing, they can't be changed".to_string());
+                return Flow::Wait;
+            },
+            Err(e) => {
+                self.notify(format!("Could not render the frames again: {e}"));
+                return Flow::Wait;
+            },
+        }
+
+        self.config = config;
+        self.notify(message);
+        // The frame is rendered again even when paused
+        self.show_next = true;
+        Flow::Skip
+    }
+
+    /// Position in the video of the frame to show, the audio is heard `av_offset` milliseconds
+    /// after it is played, which is more of the video when it is played faster
+    fn frame_position(&mut self) -> Duration {
+        let av_offset = self.config.av_offset();
+        let position = self.video.playback_position();
+        let offset = Duration::from_millis(av_offset.unsigned_abs()).mul_f64(self.video.speed());
+
+        if av_offset >= 0 {
+            position.saturating_sub(offset)
         } else {
-            lag_count = lag_count.checked_sub(1).unwrap_or(0);
+            position + offset
         }
-        thread::sleep(delta_time);
     }
-    Ok(())
-}
\ No newline at end of file
+
+    /// Shows the message under the frames for a while
+    fn notify(&mut self, message: String) {
+        self.message = Some((message, Instant::now()));
+    }
+
+    fn show(&mut self, frame: String) {
+        let output_start = Instant::now();
+        self.draw(&frame);
+        if let Some(quality) = self.video.quality_mut() {
+            quality.record_frame(output_start.elapsed());
+        }
+
+        self.last_frame = Some(frame);
+    }
+
+    /// Shows the last frame again with the current status
+    fn redraw(&mut self) {
+        if let Some(frame) = self.last_frame.take() {
+            self.draw(&frame);
+            self.last_frame = Some(frame);
+        }
+    }
+
+    /// Prints the frame with the status under it, over the previous frame when the terminal was taken over
+    fn draw(&mut self, frame: &str) {
+        let mut text = frame.trim_end_matches('\n').to_string();
+        for line in self.status() {
+            text.push('\n');
+            text.push_str(&line);
+        }
+        // In raw mode a new line does not bring the cursor back to the start of the line
+        if self.raw_mode {
+            text = text.replace('\n', "\r\n");
+        }
+
+        match &self.session {
+            Some(session) => {
+                let _ = session.draw(&text);
+            },
+            None if self.raw_mode => print!("{text}\r\n"),
+            None => println!("{text}"),
+        }
+    }
+
+    /// The lines under the frame: the lowered quality level, the pause and the message of the last control
+    fn status(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        // Nothing is shown at the full quality
+        if let Some(quality) = self.video.quality_mut().filter(|q| q.level() > 0) {
+            lines.push(format!(
+                "Quality: lowered {}/{} to keep up ({})",
+                quality.level(),
+                QUALITY_LEVELS.len() - 1,
+                quality.current_level().describe(),
+            ));
+        }
+
+        if self.video.is_paused() {
+            lines.push("Paused".to_string());
+        }
+
+        if self.message.as_ref().is_some_and(|(_, shown)| shown.elapsed() >= MESSAGE_DURATION) {
+            self.message = None;
+        }
+        if let Some((message, _)) = &self.message {
+            lines.push(message.clone());
+        }
+
+        lines
+    }
+}
+
+/// The pallet following the one named in the pallet file, in alphabetical order
+fn next_pallet(current: &str) -> Option<CharacterPallet> {
+    // The invalid pallets are skipped
+    let (mut pallets, _) = match character_pallet::parse_pallets_from_file(PALLET_FILE_PATH) {
+        Ok(p) => p,
+        Err(_) => return None,
+    };
+
+    let mut names: Vec<String> = pallets.keys().cloned().collect();
+    names.sort();
+    let next = match names.iter().position(|n| n == current) {
+        Some(i) => &names[(i + 1) % names.len()],
+        None => names.first()?,
+    };
+
+    if next == current {
+        return None;
+    }
+    pallets.remove(next)
+}
+
+/// The position as minutes and seconds, like 1:05
+fn format_position(position: Duration) -> String {
+    let seconds = position.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
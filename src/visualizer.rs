@@ -0,0 +1,436 @@
+//! Shows audio files as a spectrum, an oscilloscope or a waveform rendered with the pallet,
+//! under the cover art when the file has one
+//!
+//! The frames are made from the audio around their timestamp, so they follow the audio like
+//! the frames of a video.
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::path::Path;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use rodio::Source;
+
+use crate::audio_decoder::AudioDecoder;
+use crate::cells::{Cell, CellGrid};
+use crate::config::Config;
+
+/// Extensions of the files played as audio only
+pub const AUDIO_EXTENSIONS: [&str; 6] = ["mp3", "flac", "wav", "ogg", "m4a", "aac"];
+/// Frame rate of the visualizer when the frame limit is the native frame rate
+pub const DEFAULT_VISUALIZER_FPS: f64 = 30.0;
+
+/// Number of samples in the spectrum analysis
+const FFT_SIZE: usize = 2048;
+/// Frequencies of the first and last bars of the spectrum
+const SPECTRUM_FREQUENCIES: (f32, f32) = (40.0, 16000.0);
+/// Loudness range of the spectrum, quieter frequencies are not shown
+const SPECTRUM_DECIBELS: f32 = 60.0;
+/// How much of the height the bars of the spectrum go down each frame, so that they don't flicker
+const SPECTRUM_FALL: f32 = 0.08;
+/// Number of samples drawn by the oscilloscope
+const OSCILLOSCOPE_SAMPLES: usize = 1024;
+/// Length of the audio shown by the waveform
+const WAVEFORM_SECONDS: f64 = 4.0;
+
+const OSCILLOSCOPE_COLOR: (u8, u8, u8) = (80, 255, 120);
+const WAVEFORM_COLOR: (u8, u8, u8) = (100, 180, 255);
+const BACKGROUND_COLOR: (u8, u8, u8) = (0, 0, 0);
+
+/// How the audio is shown
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VisualizerKind {
+    /// The loudness of each frequency
+    #[default]
+    Spectrum,
+    /// The shape of the sound wave being played
+    Oscilloscope,
+    /// The loudness over the last seconds
+    Waveform,
+}
+
+/// Whether the file is played as audio only, going by its extension
+pub fn is_audio_file(path: &str) -> bool {
+    Path::new(path).extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+}
+
+/// Keeps the last samples of the audio, mixed to mono, and draws them
+pub struct AudioVisualizer {
+    kind: VisualizerKind,
+    sample_rate: u32,
+    samples: VecDeque<f32>,
+    bar_levels: Vec<f32>, // Height of each bar of the spectrum in the last frame, from 0 to 1
+}
+
+impl AudioVisualizer {
+    pub fn new(kind: VisualizerKind, sample_rate: u32) -> AudioVisualizer {
+        AudioVisualizer {
+            kind,
+            sample_rate,
+            samples: VecDeque::new(),
+            bar_levels: Vec::new(),
+        }
+    }
+}
+
+impl AudioVisualizer {
+    /// Adds the interleaved samples after the others
+    pub fn push(&mut self, samples: &[f32], channels: u16) {
+        let channels = channels.max(1) as usize;
+        for frame in samples.chunks(channels) {
+            self.samples.push_back(frame.iter().sum::<f32>() / channels as f32);
+        }
+
+        let kept = FFT_SIZE.max((WAVEFORM_SECONDS * self.sample_rate as f64) as usize);
+        let extra = self.samples.len().saturating_sub(kept);
+        self.samples.drain(..extra);
+    }
+
+    /// Forgets the samples, after a seek
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.bar_levels.clear();
+    }
+
+    /// Draws the last samples in a grid of `columns` by `rows` glyphs
+    pub fn render(&mut self, columns: usize, rows: usize, config: &Config) -> CellGrid {
+        let pixels = match self.kind {
+            VisualizerKind::Spectrum => self.draw_spectrum(columns, rows),
+            VisualizerKind::Oscilloscope => self.draw_oscilloscope(columns, rows),
+            VisualizerKind::Waveform => self.draw_waveform(columns, rows),
+        };
+
+        let cells = pixels.into_iter()
+            .map(|(r, g, b)| Cell::for_pixel(r, g, b, config))
+            .collect();
+        CellGrid::new(columns, rows, cells)
+    }
+
+    /// Bars of the loudness of frequencies going up logarithmically, colored from red to purple
+    fn draw_spectrum(&mut self, columns: usize, rows: usize) -> Vec<(u8, u8, u8)> {
+        let magnitudes = spectrum(&self.last_samples(FFT_SIZE));
+        let bin_frequency = self.sample_rate as f32 / FFT_SIZE as f32;
+        let (lowest, highest) = (SPECTRUM_FREQUENCIES.0, SPECTRUM_FREQUENCIES.1.min(self.sample_rate as f32 / 2.0));
+        // A full scale sine wave, the window halves its magnitude
+        let full_scale = FFT_SIZE as f32 / 4.0;
+
+        self.bar_levels.resize(columns, 0.0);
+        for (column, level) in self.bar_levels.iter_mut().enumerate() {
+            let frequency = |c: usize| lowest * (highest / lowest).powf(c as f32 / columns as f32);
+            let first_bin = (frequency(column) / bin_frequency) as usize;
+            let last_bin = ((frequency(column + 1) / bin_frequency) as usize).max(first_bin + 1);
+
+            let magnitude = magnitudes.get(first_bin..last_bin.min(magnitudes.len()))
+                .map_or(0.0, |m| m.iter().cloned().fold(0.0, f32::max));
+            let decibels = 20.0 * (magnitude / full_scale).max(1e-6).log10();
+            let new_level = ((decibels + SPECTRUM_DECIBELS) / SPECTRUM_DECIBELS).clamp(0.0, 1.0);
+
+            *level = new_level.max(*level - SPECTRUM_FALL);
+        }
+
+        let mut pixels = vec![BACKGROUND_COLOR; columns * rows];
+        for (column, level) in self.bar_levels.iter().enumerate() {
+            let color = hue_color(column as f32 / columns as f32 * 300.0);
+            let height = level * rows as f32;
+
+            for row_from_bottom in 0..rows {
+                // The top of the bar is dimmer when it only covers part of the glyph
+                let coverage = (height - row_from_bottom as f32).clamp(0.0, 1.0);
+                if coverage > 0.0 {
+                    pixels[(rows - 1 - row_from_bottom) * columns + column] = dim(color, coverage);
+                }
+            }
+        }
+
+        pixels
+    }
+
+    /// The last samples drawn as a line across the width
+    fn draw_oscilloscope(&self, columns: usize, rows: usize) -> Vec<(u8, u8, u8)> {
+        let samples = self.last_samples(OSCILLOSCOPE_SAMPLES);
+
+        let mut pixels = vec![BACKGROUND_COLOR; columns * rows];
+        let mut previous_row = None;
+        for column in 0..columns {
+            let sample = samples[column * samples.len() / columns];
+            let row = sample_row(sample, rows);
+
+            // The rows between the points are filled so that the line has no holes
+            let (top, bottom) = match previous_row {
+                Some(p) => (row.min(p), row.max(p)),
+                None => (row, row),
+            };
+            for r in top..=bottom {
+                pixels[r * columns + column] = OSCILLOSCOPE_COLOR;
+            }
+            previous_row = Some(row);
+        }
+
+        pixels
+    }
+
+    /// The lowest and highest samples of each part of the last seconds, the newest on the right
+    fn draw_waveform(&self, columns: usize, rows: usize) -> Vec<(u8, u8, u8)> {
+        let shown_samples = (WAVEFORM_SECONDS * self.sample_rate as f64) as usize;
+        let samples_per_column = (shown_samples / columns).max(1);
+        // Until there are enough samples the left of the waveform is empty
+        let first_column = columns.saturating_sub(self.samples.len() / samples_per_column);
+        let first_sample = self.samples.len() - (columns - first_column) * samples_per_column;
+
+        let mut pixels = vec![BACKGROUND_COLOR; columns * rows];
+        for column in first_column..columns {
+            let start = first_sample + (column - first_column) * samples_per_column;
+            let (lowest, highest) = self.samples.range(start..start + samples_per_column)
+                .fold((0.0f32, 0.0f32), |(l, h), s| (l.min(*s), h.max(*s)));
+
+            for r in sample_row(highest, rows)..=sample_row(lowest, rows) {
+                pixels[r * columns + column] = WAVEFORM_COLOR;
+            }
+        }
+
+        pixels
+    }
+
+    /// The last `count` samples, with silence before the audio if there are not enough
+    fn last_samples(&self, count: usize) -> Vec<f32> {
+        let missing = count.saturating_sub(self.samples.len());
+
+        let mut samples = vec![0.0; missing];
+        samples.extend(self.samples.range(self.samples.len() + missing - count..));
+        samples
+    }
+}
+
+/// The row showing a sample, from 1.0 at the top to -1.0 at the bottom
+fn sample_row(sample: f32, rows: usize) -> usize {
+    let position = (1.0 - sample.clamp(-1.0, 1.0)) / 2.0;
+    ((position * rows as f32) as usize).min(rows.saturating_sub(1))
+}
+
+/// Magnitude of the frequencies of the samples, the number of samples needs to be a power of 2.
+/// The samples are smoothed at the ends first with a Hann window.
+pub fn spectrum(samples: &[f32]) -> Vec<f32> {
+    let size = samples.len();
+    let mut real: Vec<f32> = samples.iter().enumerate()
+        .map(|(i, s)| s * 0.5 * (1.0 - (2.0 * PI * i as f32 / size as f32).cos()))
+        .collect();
+    let mut imaginary = vec![0.0; size];
+
+    fft(&mut real, &mut imaginary);
+
+    real.iter().zip(&imaginary)
+        .take(size / 2)
+        .map(|(r, i)| (r * r + i * i).sqrt())
+        .collect()
+}
+
+/// Iterative radix-2 fast Fourier transform, in place
+fn fft(real: &mut [f32], imaginary: &mut [f32]) {
+    let size = real.len();
+    assert!(size.is_power_of_two(), "The FFT needs a power of 2 of samples");
+
+    // Puts the samples in bit reversed order
+    let bits = size.trailing_zeros();
+    for i in 0..size {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if bits > 0 && i < j {
+            real.swap(i, j);
+            imaginary.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= size {
+        let angle = -2.0 * PI / length as f32;
+        for start in (0..size).step_by(length) {
+            for k in 0..length / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (even, odd) = (start + k, start + k + length / 2);
+
+                let odd_real = real[odd] * cos - imaginary[odd] * sin;
+                let odd_imaginary = real[odd] * sin + imaginary[odd] * cos;
+                real[odd] = real[even] - odd_real;
+                imaginary[odd] = imaginary[even] - odd_imaginary;
+                real[even] += odd_real;
+                imaginary[even] += odd_imaginary;
+            }
+        }
+        length *= 2;
+    }
+}
+
+/// The brightest color of the hue, in degrees
+fn hue_color(hue: f32) -> (u8, u8, u8) {
+    let channel = |offset: f32| {
+        let k = (offset + hue / 60.0) % 6.0;
+        let value = 1.0 - (k.min(4.0 - k).clamp(0.0, 1.0));
+        (value * 255.0) as u8
+    };
+
+    (channel(5.0), channel(3.0), channel(1.0))
+}
+
+fn dim((r, g, b): (u8, u8, u8), brightness: f32) -> (u8, u8, u8) {
+    let dim = |c: u8| (c as f32 * brightness) as u8;
+    (dim(r), dim(g), dim(b))
+}
+
+/// Frames of the visualizer made from an audio file, at a constant frame rate
+pub struct AudioFrames {
+    decoder: AudioDecoder,
+    visualizer: AudioVisualizer,
+    cover: Option<CellGrid>, // Shown above the visualizer
+    config: Config,
+    fps: f64,
+    index: usize, // Index of the next frame
+    decoded_samples: u64, // Number of samples of each channel before the decoder's position
+    end: Option<Duration>,
+}
+
+impl AudioFrames {
+    /// The frames start at the start of the config and end at its end, or at the end of the audio
+    pub fn new(decoder: AudioDecoder, cover: Option<CellGrid>, config: &Config, fps: f64) -> AudioFrames {
+        let visualizer = AudioVisualizer::new(config.visualizer(), decoder.sample_rate());
+
+        let mut frames = AudioFrames {
+            decoder,
+            visualizer,
+            cover,
+            config: config.clone(),
+            fps,
+            index: 0,
+            decoded_samples: 0,
+            end: config.end(),
+        };
+        frames.seek(config.start());
+
+        frames
+    }
+}
+
+impl AudioFrames {
+    /// Index of the next frame
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Draws the frame for the next frame duration of the audio, with its timestamp
+    pub fn next_frame(&mut self) -> Option<(Duration, CellGrid)> {
+        let timestamp = Duration::from_secs_f64(self.index as f64 / self.fps);
+        if self.end.is_some_and(|e| timestamp >= e) {
+            return None;
+        }
+
+        // Decodes the audio until the end of the frame
+        let channels = self.decoder.channels();
+        let frame_end = ((self.index + 1) as f64 / self.fps * self.decoder.sample_rate() as f64) as u64;
+        let missing = frame_end.saturating_sub(self.decoded_samples) as usize * channels as usize;
+        let samples: Vec<f32> = self.decoder.by_ref().take(missing).collect();
+        if samples.is_empty() && missing > 0 {
+            return None;
+        }
+        self.decoded_samples += (samples.len() / channels.max(1) as usize) as u64;
+        self.visualizer.push(&samples, channels);
+        self.index += 1;
+
+        let columns = (self.config.width() as usize / self.config.glyph_width()).max(1);
+        // About 16:9 with glyphs twice as high as they are wide
+        let rows = (columns * 9 / 32).max(4);
+        let visualizer = self.visualizer.render(columns, rows, &self.config);
+
+        let grid = match &self.cover {
+            Some(cover) => stack(cover, &visualizer),
+            None => visualizer,
+        };
+        Some((timestamp, grid))
+    }
+
+    /// Draws the next frames with the config, the cover art stays as it was rendered
+    pub fn set_config(&mut self, config: &Config) {
+        self.config = config.clone();
+    }
+
+    /// Makes the next frame be the first one shown at or after the position
+    pub fn seek(&mut self, position: Duration) {
+        self.index = (position.as_secs_f64() * self.fps).ceil() as usize;
+        let frame_start = Duration::from_secs_f64(self.index as f64 / self.fps);
+
+        // The frames end when the audio can't be read
+        let _ = self.decoder.seek(frame_start);
+        self.decoded_samples = (frame_start.as_secs_f64() * self.decoder.sample_rate() as f64) as u64;
+        self.visualizer.clear();
+    }
+}
+
+/// Puts the top grid above the bottom one, the narrower one is filled with blank glyphs
+fn stack(top: &CellGrid, bottom: &CellGrid) -> CellGrid {
+    let width = top.width().max(bottom.width());
+
+    let rows = top.rows().chain(bottom.rows())
+        .map(|row| {
+            let mut row = row.to_vec();
+            row.resize(width, Cell::new(' ', None));
+            row
+        })
+        .collect();
+
+    CellGrid::from_rows(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::default_config_with_width;
+
+    #[test]
+    fn spectrum_peaks_at_the_frequency() {
+        // 1000 Hz sampled at 8192 Hz falls on the bin 250
+        let samples: Vec<f32> = (0..FFT_SIZE)
+            .map(|i| (2.0 * PI * 1000.0 * i as f32 / 8192.0).sin())
+            .collect();
+        let magnitudes = spectrum(&samples);
+
+        let peak = (0..magnitudes.len()).max_by(|a, b| magnitudes[*a].total_cmp(&magnitudes[*b])).unwrap();
+        assert_eq!(250, peak);
+        assert!((magnitudes[peak] - FFT_SIZE as f32 / 4.0).abs() < 1.0, "peak was {}", magnitudes[peak]);
+    }
+
+    #[test]
+    fn visualizers_are_drawn_with_the_pallet() {
+        let mut config = default_config_with_width(40);
+        config.set_color(false);
+        let samples: Vec<f32> = (0..44100).map(|i| (2.0 * PI * 440.0 * i as f32 / 44100.0).sin()).collect();
+
+        for kind in [VisualizerKind::Spectrum, VisualizerKind::Oscilloscope, VisualizerKind::Waveform] {
+            let mut visualizer = AudioVisualizer::new(kind, 44100);
+            let silent = visualizer.render(40, 10, &config);
+            visualizer.push(&samples, 1);
+            let grid = visualizer.render(40, 10, &config);
+
+            assert_eq!((40, 10), (grid.width(), grid.height()));
+            assert_ne!(silent, grid, "{kind:?} did not draw the audio");
+            let background = config.pallet().character_for_luminosity(0).unwrap();
+            assert!(grid.cells().iter().any(|c| c.character != background));
+        }
+    }
+
+    #[test]
+    fn audio_files_are_recognized() {
+        assert!(is_audio_file("./music/song.MP3"));
+        assert!(is_audio_file("song.flac"));
+        assert!(!is_audio_file("video.mp4"));
+        assert!(!is_audio_file("https://www.youtube.com/watch?v=dQw4w9WgXcQ"));
+    }
+
+    #[test]
+    fn cover_is_put_above() {
+        let cover = CellGrid::new(1, 1, vec![Cell::new('#', None)]);
+        let visualizer = CellGrid::new(2, 1, vec![Cell::new('a', None), Cell::new('b', None)]);
+
+        let grid = stack(&cover, &visualizer);
+        assert_eq!("# \nab\n", grid.to_text());
+    }
+}
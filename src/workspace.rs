@@ -0,0 +1,164 @@
+//! The temporary files of the session are put in a directory of their own, so that instances
+//! running at the same time don't use the same files. It is removed when the program exits,
+//! panics or is stopped with Ctrl-C or SIGTERM, the terminal is then restored too. The temporary
+//! files that need to be somewhere else are removed with it.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::terminal_session;
+
+/// The directory of the session, it is only created when a temporary file is needed
+static WORKSPACE: Mutex<Option<PathBuf>> = Mutex::new(None);
+/// Temporary files of the session outside of its directory
+static OUTSIDE_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Exit code of programs stopped by Ctrl-C
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+/// Exit code of programs stopped by SIGTERM
+#[cfg(unix)]
+const TERMINATED_EXIT_CODE: i32 = 143;
+
+/// Path of the temporary file `name` in the directory of the session
+pub fn temp_path(name: &str) -> io::Result<PathBuf> {
+    let mut workspace = WORKSPACE.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let dir = match &*workspace {
+        Some(d) => d.clone(),
+        None => workspace.insert(create_dir()?).clone(),
+    };
+
+    Ok(dir.join(name))
+}
+
+fn create_dir() -> io::Result<PathBuf> {
+    // The time keeps apart the sessions of programs that got the id of an old one
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+    let dir = env::temp_dir().join(format!("cli-player-{}-{nanos}", process::id()));
+
+    // Fails if the directory exists, it could belong to someone else
+    fs::create_dir(&dir)?;
+    Ok(dir)
+}
+
+/// Removes the file with the directory of the session, for the temporary files that can't be in it,
+/// like the ones renamed to a directory that may be on another file system once they are written
+pub fn remove_on_cleanup(path: &Path) {
+    OUTSIDE_FILES.lock().unwrap_or_else(PoisonError::into_inner).push(path.to_path_buf());
+}
+
+/// Leaves the file added with `remove_on_cleanup` alone, once it was renamed or removed
+pub fn keep_on_cleanup(path: &Path) {
+    OUTSIDE_FILES.lock().unwrap_or_else(PoisonError::into_inner).retain(|p| p != path);
+}
+
+/// Removes the directory of the session with the files in it, and the files of the session outside of it
+pub fn cleanup() {
+    let mut workspace = WORKSPACE.lock().unwrap_or_else(PoisonError::into_inner);
+
+    if let Some(dir) = workspace.take() {
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    for path in OUTSIDE_FILES.lock().unwrap_or_else(PoisonError::into_inner).drain(..) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Removes the directory of the session and restores the terminal before exiting
+pub fn exit(code: i32) -> ! {
+    terminal_session::restore();
+    cleanup();
+    process::exit(code)
+}
+
+/// Makes the directory of the session be removed and the terminal be restored on panics,
+/// Ctrl-C and SIGTERM, it needs to be called in the tokio runtime
+pub fn install_cleanup_handlers() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        // The message would be lost with the alternate screen
+        terminal_session::restore();
+        cleanup();
+        default_hook(info);
+    }));
+
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            exit(INTERRUPTED_EXIT_CODE);
+        }
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            if terminate.recv().await.is_some() {
+                exit(TERMINATED_EXIT_CODE);
+            }
+        }
+    });
+}
+
+/// The path as an argument of another program, relative paths are made to start with `./`
+/// so that they are not read as an option (`-video.mp4`) or an url (`http:video.mp4`)
+pub fn command_path(path: &Path) -> PathBuf {
+    match path.components().next() {
+        Some(Component::Normal(_)) => Path::new(".").join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workspace_is_removed_by_cleanup() {
+        let path = temp_path("audio.wav").unwrap();
+        let dir = path.parent().unwrap().to_path_buf();
+        assert!(dir.starts_with(env::temp_dir()));
+        assert_eq!(dir, temp_path("video.mp4").unwrap().parent().unwrap());
+
+        fs::write(&path, b"audio").unwrap();
+        cleanup();
+        assert!(!dir.exists());
+
+        // A new directory is made for the next files
+        let new_dir = temp_path("audio.wav").unwrap().parent().unwrap().to_path_buf();
+        assert_ne!(dir, new_dir);
+        cleanup();
+    }
+
+    #[test]
+    fn files_outside_the_workspace_are_removed_by_cleanup() {
+        let removed = env::temp_dir().join("cli-player-test-removed.part");
+        let kept = env::temp_dir().join("cli-player-test-kept.part");
+        fs::write(&removed, b"frames").unwrap();
+        fs::write(&kept, b"frames").unwrap();
+
+        remove_on_cleanup(&removed);
+        remove_on_cleanup(&kept);
+        keep_on_cleanup(&kept);
+        cleanup();
+
+        assert!(!removed.exists());
+        assert!(kept.exists());
+        fs::remove_file(&kept).unwrap();
+    }
+
+    #[test]
+    fn paths_are_not_read_as_options() {
+        assert_eq!(Path::new("./-video.mp4"), command_path(Path::new("-video.mp4")));
+        assert_eq!(Path::new("./http:video.mp4"), command_path(Path::new("http:video.mp4")));
+        assert_eq!(Path::new("./videos/a.mp4"), command_path(Path::new("./videos/a.mp4")));
+        assert_eq!(Path::new("/videos/-a.mp4"), command_path(Path::new("/videos/-a.mp4")));
+    }
+}